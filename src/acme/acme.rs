@@ -0,0 +1,878 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::asn1::Asn1Time;
+use openssl::x509::X509;
+use tokio::sync::Mutex;
+
+use crate::acme::client::{
+    certificate_request, generate_key, write_private, AccountKey, AcmeClient, Authorization, Order,
+};
+use crate::common::errors::ErrorKind;
+use crate::config::{ChallengeKind, Config};
+use crate::models::DnsRecord;
+use crate::nginx::nginx::{NginxServer, Tls, ACME_CHALLENGE_PATH};
+use crate::propagation::propagation::{wait_for, Check, PropagationStatus};
+use crate::updater::updater;
+use crate::zones::zones::{Zone, Zones};
+
+/// Time between two looks at a pending authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_ATTEMPTS: u32 = 60;
+
+/// Time nginx gets to finish its reload after the challenge location was
+/// added.
+const RELOAD_GRACE: Duration = Duration::from_secs(2);
+
+/// Where the certificate of the site serving `hostname` is kept. `live`
+/// links to the directory of the latest issue.
+pub fn certificate_paths(cfg: &Config, hostname: &str) -> Tls {
+    let dir = Path::new(&cfg.acme_certs_dir)
+        .join(hostname.replace('*', "_"))
+        .join("live");
+
+    Tls {
+        certificate: dir.join("fullchain.pem").display().to_string(),
+        certificate_key: dir.join("privkey.pem").display().to_string(),
+    }
+}
+
+/// Writes a key and its chain to a new directory next to `live`, then
+/// swaps the link, so nginx never pairs a renewed key with the old chain.
+fn install_certificate(tls: &Tls, key: &[u8], chain: &str) -> Result<(), ErrorKind> {
+    let live = Path::new(&tls.certificate)
+        .parent()
+        .ok_or(ErrorKind::Error("Invalid certificate path".to_string()))?;
+    let host_dir = live
+        .parent()
+        .ok_or(ErrorKind::Error("Invalid certificate path".to_string()))?;
+    fs::create_dir_all(host_dir)?;
+
+    let mut stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    while host_dir.join(stamp.to_string()).exists() {
+        stamp += 1;
+    }
+    let version = stamp.to_string();
+    let dir = host_dir.join(&version);
+    fs::create_dir(&dir)?;
+    write_private(&dir.join("privkey.pem"), key)?;
+    fs::write(dir.join("fullchain.pem"), chain)?;
+
+    let tmp_link = host_dir.join("live.tmp");
+    let _ = fs::remove_file(&tmp_link);
+    symlink(&version, &tmp_link)?;
+    fs::rename(&tmp_link, live)?;
+
+    // Earlier issues are no longer linked; nginx keeps what it loaded.
+    for entry in fs::read_dir(host_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && entry.file_name() != version.as_str() {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Serves the business' site over HTTPS with `tls` and waits for nginx to
+/// pick it up.
+async fn enable_https(
+    user_id: &str,
+    business_id: &str,
+    tls: Tls,
+    cfg: &Config,
+) -> Result<(), ErrorKind> {
+    let (user_id, business_id, cfg) = (user_id.to_string(), business_id.to_string(), cfg.clone());
+    updater::blocking(move || updater::enable_https(&user_id, &business_id, &tls, &cfg)).await?;
+    Ok(())
+}
+
+/// The server names a certificate can be issued for, skipping regexes and
+/// catch-all names.
+pub fn certificate_names(server_names: &[String]) -> Vec<String> {
+    server_names
+        .iter()
+        .filter(|name| !name.starts_with('~') && name.contains('.'))
+        .map(|name| name.to_lowercase())
+        .collect()
+}
+
+/// True when the certificate at `path` is missing, unreadable, expires
+/// within `renew_days` or does not cover all of `names`.
+pub fn needs_certificate(path: &Path, names: &[String], renew_days: u32) -> bool {
+    let certificate = match fs::read(path).map(|pem| X509::from_pem(&pem)) {
+        Ok(Ok(certificate)) => certificate,
+        _ => return true,
+    };
+
+    let renew_at = match Asn1Time::days_from_now(renew_days) {
+        Ok(renew_at) => renew_at,
+        Err(_) => return true,
+    };
+    if certificate.not_after() <= renew_at {
+        return true;
+    }
+
+    let covered: Vec<String> = certificate
+        .subject_alt_names()
+        .map(|sans| {
+            sans.iter()
+                .filter_map(|san| san.dnsname().map(str::to_lowercase))
+                .collect()
+        })
+        .unwrap_or_default();
+    !names.iter().all(|name| covered.contains(name))
+}
+
+/// True when `hostname` is in the zone `cfg` is scoped to, so its records
+/// can be written through the zone's provider.
+fn in_zone(hostname: &str, cfg: &Config) -> bool {
+    let hostname = hostname.trim_start_matches("*.");
+    hostname == cfg.dns_suffix || hostname.ends_with(&format!(".{}", cfg.dns_suffix))
+}
+
+/// Picks the challenge a name is validated with. Wildcards are DNS-01 only,
+/// and names outside the zone, such as custom domains, can only use HTTP-01.
+fn challenge_kind(hostname: &str, wildcard: bool, cfg: &Config) -> ChallengeKind {
+    if wildcard || (cfg.acme_challenge == ChallengeKind::Dns01 && in_zone(hostname, cfg)) {
+        ChallengeKind::Dns01
+    } else {
+        ChallengeKind::Http01
+    }
+}
+
+/// A challenge response put in place, removed once the challenge is done.
+enum Answer {
+    File(PathBuf),
+    Record(String),
+}
+
+/// Gets certificates for the managed sites from the configured ACME server
+/// and keeps them renewed. The account is registered on first use.
+#[derive(Clone)]
+pub struct Certificates {
+    cfg: Config,
+    client: Arc<Mutex<Option<Arc<AcmeClient>>>>,
+}
+
+impl Certificates {
+    pub fn new(cfg: &Config) -> Self {
+        Certificates {
+            cfg: cfg.clone(),
+            client: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn client(&self) -> Result<Arc<AcmeClient>, ErrorKind> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = client.as_ref() {
+            return Ok(client.clone());
+        }
+
+        let cfg = &self.cfg;
+        let key = AccountKey::load_or_create(&Path::new(&cfg.acme_certs_dir).join("account.pem"))?;
+        let connected = Arc::new(
+            AcmeClient::connect(
+                &cfg.acme_directory_url,
+                key,
+                cfg.acme_email.as_deref(),
+                cfg.acme_ca_bundle.as_deref().map(Path::new),
+            )
+            .await?,
+        );
+
+        *client = Some(connected.clone());
+        Ok(connected)
+    }
+
+    /// Issues a certificate covering `names` and writes it to the paths
+    /// [`certificate_paths`] gives for the first name.
+    pub async fn issue(&self, zone: &Zone, names: &[String]) -> Result<Tls, ErrorKind> {
+        let client = self.client().await?;
+
+        let order = client.new_order(names).await?;
+        for url in &order.authorizations {
+            self.authorize(&client, zone, url).await?;
+        }
+
+        let key = generate_key()?;
+        let order = client
+            .finalize(&order, &certificate_request(names, &key)?)
+            .await?;
+        let order = poll_order(&client, order).await?;
+        let url = order.certificate.ok_or(ErrorKind::Error(
+            "ACME order is valid but has no certificate".to_string(),
+        ))?;
+        let chain = client.certificate(&url).await?;
+
+        let tls = certificate_paths(&zone.config, &names[0]);
+        install_certificate(&tls, &key.private_key_to_pem_pkcs8()?, &chain)?;
+
+        info!("Issued a certificate for {}", names.join(", "));
+        Ok(tls)
+    }
+
+    async fn authorize(
+        &self,
+        client: &AcmeClient,
+        zone: &Zone,
+        url: &str,
+    ) -> Result<(), ErrorKind> {
+        let authorization = client.authorization(url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let hostname = &authorization.identifier.value;
+        let kind = challenge_kind(hostname, authorization.wildcard, &zone.config);
+        let wanted = match kind {
+            ChallengeKind::Http01 => "http-01",
+            ChallengeKind::Dns01 => "dns-01",
+        };
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == wanted)
+            .ok_or(ErrorKind::Error(format!(
+                "ACME server offers no {} challenge for {}",
+                wanted, hostname
+            )))?;
+
+        let answer = self
+            .answer(client, zone, hostname, kind, &challenge.token)
+            .await?;
+        let result = match client.respond(challenge).await {
+            Ok(()) => poll_authorization(client, url).await,
+            Err(e) => Err(e),
+        };
+        self.clean_up(zone, answer).await;
+
+        let authorization = result?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let detail = authorization
+            .challenges
+            .iter()
+            .find_map(|challenge| challenge.error.as_ref())
+            .and_then(|error| error["detail"].as_str().map(str::to_string))
+            .unwrap_or(authorization.status);
+        Err(ErrorKind::Error(format!(
+            "{} challenge for {} failed: {}",
+            wanted, hostname, detail
+        )))
+    }
+
+    /// Puts the response to a challenge in place: a token file in the
+    /// webroot, or a TXT record that is waited for until the propagation
+    /// resolvers see it.
+    async fn answer(
+        &self,
+        client: &AcmeClient,
+        zone: &Zone,
+        hostname: &str,
+        kind: ChallengeKind,
+        token: &str,
+    ) -> Result<Answer, ErrorKind> {
+        let cfg = &zone.config;
+
+        match kind {
+            ChallengeKind::Http01 => {
+                let dir = Path::new(&cfg.acme_webroot).join(ACME_CHALLENGE_PATH.trim_matches('/'));
+                fs::create_dir_all(&dir)?;
+
+                let path = dir.join(token);
+                fs::write(&path, client.key().key_authorization(token)?)?;
+                Ok(Answer::File(path))
+            }
+            ChallengeKind::Dns01 => {
+                let name = format!("_acme-challenge.{}", hostname.trim_start_matches("*."));
+                let value = client.key().dns_value(token)?;
+                let record = DnsRecord::new(
+                    "TXT".to_string(),
+                    name.clone(),
+                    DnsRecord::automatic_ttl(),
+                    value.clone(),
+                    false,
+                );
+                let id = zone.provider.create_record(&record).await?;
+
+                let check = Check {
+                    record_type: "TXT".to_string(),
+                    expected: Some(value),
+                    ..Check::new(&name, &cfg.ip, false, cfg)
+                };
+                let propagation = wait_for(&check, |_| {}).await;
+                if propagation.status == PropagationStatus::TimedOut {
+                    warn!(
+                        "{} is not visible on {} yet, asking for validation anyway",
+                        name,
+                        propagation.pending.join(", ")
+                    );
+                }
+
+                Ok(Answer::Record(id))
+            }
+        }
+    }
+
+    async fn clean_up(&self, zone: &Zone, answer: Answer) {
+        let result = match &answer {
+            Answer::File(path) => fs::remove_file(path).map_err(ErrorKind::from),
+            Answer::Record(id) => zone.provider.delete_record(id).await,
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to remove an ACME challenge response: {}", e);
+        }
+    }
+
+    /// Makes sure the business' site has a certificate that covers all of
+    /// its names and is not about to expire, and serves it over HTTPS.
+    /// Returns whether a certificate was issued.
+    pub async fn secure_site(
+        &self,
+        zone: &Zone,
+        user_id: &str,
+        business_id: &str,
+    ) -> Result<bool, ErrorKind> {
+        let cfg = &zone.config;
+        let server = updater::load_site(user_id, business_id, cfg)?;
+
+        let names = certificate_names(&server.server_names);
+        if names.is_empty() {
+            return Ok(false);
+        }
+        let tls = certificate_paths(cfg, &names[0]);
+
+        if !needs_certificate(Path::new(&tls.certificate), &names, cfg.acme_renew_days) {
+            if server.tls.as_ref() != Some(&tls) {
+                enable_https(user_id, business_id, tls, cfg).await?;
+            }
+            return Ok(false);
+        }
+
+        let http01 = names
+            .iter()
+            .any(|name| challenge_kind(name, name.starts_with("*."), cfg) == ChallengeKind::Http01);
+        if http01 {
+            let (user, business, site_cfg) =
+                (user_id.to_string(), business_id.to_string(), cfg.clone());
+            let enabled = updater::blocking(move || {
+                updater::enable_acme_challenges(&user, &business, &site_cfg)
+            })
+            .await?;
+            if enabled {
+                tokio::time::sleep(RELOAD_GRACE).await;
+            }
+        }
+
+        let tls = self.issue(zone, &names).await?;
+        enable_https(user_id, business_id, tls, cfg).await?;
+        Ok(true)
+    }
+
+    /// Issues or renews the certificates of every site that needs one.
+    pub async fn renew_all(&self, zones: &Zones) {
+        // Every zone's sites share the same directories.
+        let sites = match updater::list_sites(&zones.default_zone().config) {
+            Ok(sites) => sites,
+            Err(e) => {
+                error!("Failed to list sites for certificate renewal: {}", e);
+                return;
+            }
+        };
+
+        for site in sites {
+            let zone = match site
+                .server_names
+                .first()
+                .and_then(|name| zones.for_hostname(name))
+            {
+                Some(zone) => zone,
+                None => continue,
+            };
+
+            if let Err(e) = self
+                .secure_site(zone, &site.user_id, &site.business_id)
+                .await
+            {
+                error!(
+                    "Failed to secure the site of {}/{}: {}",
+                    site.user_id, site.business_id, e
+                );
+            }
+        }
+    }
+
+    /// Secures the business' site in the background, once `check` says its
+    /// new name resolves if there is one.
+    pub fn start(&self, zone: Zone, user_id: &str, business_id: &str, check: Option<Check>) {
+        let certificates = self.clone();
+        let (user_id, business_id) = (user_id.to_string(), business_id.to_string());
+
+        tokio::spawn(async move {
+            if let Some(check) = &check {
+                let propagation = wait_for(check, |_| {}).await;
+                if propagation.status == PropagationStatus::TimedOut {
+                    warn!(
+                        "{} does not resolve everywhere yet, requesting a certificate anyway",
+                        check.name
+                    );
+                }
+            }
+
+            if let Err(e) = certificates
+                .secure_site(&zone, &user_id, &business_id)
+                .await
+            {
+                error!(
+                    "Failed to secure the site of {}/{}: {}",
+                    user_id, business_id, e
+                );
+            }
+        });
+    }
+}
+
+async fn poll_authorization(client: &AcmeClient, url: &str) -> Result<Authorization, ErrorKind> {
+    for _ in 0..POLL_ATTEMPTS {
+        let authorization = client.authorization(url).await?;
+        if authorization.status != "pending" {
+            return Ok(authorization);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    Err(ErrorKind::Error(format!(
+        "ACME authorization {} is still pending",
+        url
+    )))
+}
+
+async fn poll_order(client: &AcmeClient, mut order: Order) -> Result<Order, ErrorKind> {
+    for _ in 0..POLL_ATTEMPTS {
+        match order.status.as_str() {
+            "valid" => return Ok(order),
+            "pending" | "ready" | "processing" => {}
+            status => {
+                return Err(ErrorKind::Error(format!(
+                    "ACME order {} is {}: {}",
+                    order.url,
+                    status,
+                    order.error.unwrap_or_default()
+                )))
+            }
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+        order = client.order(&order.url).await?;
+    }
+
+    Err(ErrorKind::Error(format!(
+        "ACME order {} was not issued in time",
+        order.url
+    )))
+}
+
+/// Checks for certificates to issue or renew every `acme_renew_interval`
+/// seconds, starting right away. Does nothing unless ACME is configured.
+pub fn spawn_periodic(certificates: Certificates, zones: Zones) {
+    let cfg = &certificates.cfg;
+    if !cfg.acme_enabled() || cfg.acme_renew_interval == 0 {
+        return;
+    }
+
+    let period = Duration::from_secs(cfg.acme_renew_interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+            certificates.renew_all(&zones).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use rocket::serde::json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    use super::*;
+    use crate::localdns::localdns::{LocalDns, LocalZone};
+    use crate::updater::updater::create_domain;
+
+    /// A PEM certificate for `names`, valid for `days`.
+    fn self_signed(names: &[&str], days: u32) -> String {
+        let key = generate_key().unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject
+            .append_entry_by_nid(openssl::nid::Nid::COMMONNAME, names[0])
+            .unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+
+        let mut san = SubjectAlternativeName::new();
+        for name in names {
+            san.dns(name);
+        }
+        let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("floy-acme-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A built-in `floy.id` zone whose sites and certificates live in `dir`.
+    async fn zone(dir: &Path, directory_url: &str) -> Zone {
+        let config = Config {
+            dns_suffix: "floy.id".to_string(),
+            ip: "10.0.0.1".to_string(),
+            prefix: "/srv/sites".to_string(),
+            sites_available_dir: dir.join("available").display().to_string(),
+            sites_enabled_dir: dir.join("enabled").display().to_string(),
+            acme_directory_url: directory_url.to_string(),
+            acme_webroot: dir.join("webroot").display().to_string(),
+            acme_certs_dir: dir.join("certs").display().to_string(),
+            acme_renew_days: 30,
+            ..Default::default()
+        };
+        let local = Arc::new(
+            LocalZone::load("floy.id", config.nameservers(), vec![], None)
+                .await
+                .unwrap(),
+        );
+
+        Zone {
+            provider: Arc::new(LocalDns::from_zone(config.clone(), local)),
+            config,
+        }
+    }
+
+    #[test]
+    fn test_install_certificate() {
+        let dir = temp_dir("install");
+        let tls = Tls {
+            certificate: dir
+                .join("alice.floy.id/live/fullchain.pem")
+                .display()
+                .to_string(),
+            certificate_key: dir
+                .join("alice.floy.id/live/privkey.pem")
+                .display()
+                .to_string(),
+        };
+
+        install_certificate(&tls, b"key 1", "chain 1").unwrap();
+        let first = fs::read_link(dir.join("alice.floy.id/live")).unwrap();
+        install_certificate(&tls, b"key 2", "chain 2").unwrap();
+        let second = fs::read_link(dir.join("alice.floy.id/live")).unwrap();
+
+        assert_ne!(first, second);
+        assert!(!dir.join("alice.floy.id").join(&first).exists());
+        assert_eq!("key 2", fs::read_to_string(&tls.certificate_key).unwrap());
+        assert_eq!("chain 2", fs::read_to_string(&tls.certificate).unwrap());
+        assert!(!dir.join("alice.floy.id/live.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_needs_certificate() {
+        let dir = temp_dir("expiry");
+        let path = dir.join("fullchain.pem");
+        let names = vec!["alice.floy.id".to_string(), "*.alice.floy.id".to_string()];
+
+        assert!(needs_certificate(&path, &names, 30));
+
+        fs::write(
+            &path,
+            self_signed(&["alice.floy.id", "*.alice.floy.id"], 90),
+        )
+        .unwrap();
+        assert!(!needs_certificate(&path, &names, 30));
+        assert!(needs_certificate(&path, &names, 100));
+
+        // A verified custom domain is not covered yet.
+        let mut more = names.clone();
+        more.push("shop.example.com".to_string());
+        assert!(needs_certificate(&path, &more, 30));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_challenge_kind() {
+        let mut cfg = Config {
+            dns_suffix: "floy.id".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ChallengeKind::Http01,
+            challenge_kind("alice.floy.id", false, &cfg)
+        );
+        assert_eq!(
+            ChallengeKind::Dns01,
+            challenge_kind("alice.floy.id", true, &cfg)
+        );
+
+        cfg.acme_challenge = ChallengeKind::Dns01;
+        assert_eq!(
+            ChallengeKind::Dns01,
+            challenge_kind("alice.floy.id", false, &cfg)
+        );
+        assert_eq!(
+            ChallengeKind::Http01,
+            challenge_kind("shop.example.com", false, &cfg)
+        );
+
+        assert_eq!(
+            vec!["alice.floy.id", "*.alice.floy.id"],
+            certificate_names(&[
+                "Alice.floy.id".to_string(),
+                "*.alice.floy.id".to_string(),
+                "_".to_string(),
+                "~^www\\.".to_string(),
+            ])
+        );
+    }
+
+    fn acme_json(body: Value) -> ResponseTemplate {
+        ResponseTemplate::new(200)
+            .insert_header("Replay-Nonce", "nonce")
+            .set_body_json(body)
+    }
+
+    async fn mock(server: &MockServer, url: &str, response: ResponseTemplate) {
+        Mock::given(method("POST"))
+            .and(path(url))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    /// Answers like an ACME server whose authorizations turn valid once
+    /// their challenge was responded to.
+    async fn acme_server(webroot: PathBuf, local: Arc<LocalZone>) -> MockServer {
+        let server = MockServer::start().await;
+        let base = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/directory"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "newNonce": format!("{}/nonce", base),
+                "newAccount": format!("{}/account", base),
+                "newOrder": format!("{}/order", base),
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/nonce"))
+            .respond_with(ResponseTemplate::new(200).insert_header("Replay-Nonce", "first"))
+            .mount(&server)
+            .await;
+        mock(
+            &server,
+            "/account",
+            acme_json(json!({ "status": "valid" }))
+                .insert_header("Location", format!("{}/account/1", base).as_str()),
+        )
+        .await;
+
+        let order = json!({
+            "status": "pending",
+            "authorizations": [format!("{}/authz/1", base), format!("{}/authz/2", base)],
+            "finalize": format!("{}/order/1/finalize", base),
+        });
+        mock(
+            &server,
+            "/order",
+            acme_json(order.clone())
+                .insert_header("Location", format!("{}/order/1", base).as_str()),
+        )
+        .await;
+
+        for (id, kind, wildcard) in [("1", "http-01", false), ("2", "dns-01", true)] {
+            let authorization = |status: &str| {
+                json!({
+                    "status": status,
+                    "identifier": { "type": "dns", "value": "alice.floy.id" },
+                    "wildcard": wildcard,
+                    "challenges": [{
+                        "type": kind,
+                        "url": format!("{}/challenge/{}", base, id),
+                        "token": format!("token{}", id),
+                        "status": status,
+                    }],
+                })
+            };
+            Mock::given(method("POST"))
+                .and(path(format!("/authz/{}", id)))
+                .respond_with(acme_json(authorization("pending")))
+                .up_to_n_times(1)
+                .with_priority(1)
+                .mount(&server)
+                .await;
+            mock(
+                &server,
+                &format!("/authz/{}", id),
+                acme_json(authorization("valid")),
+            )
+            .await;
+        }
+
+        // The challenge responses have to be in place when the server is
+        // asked to validate them.
+        let token_file = webroot.join(".well-known/acme-challenge/token1");
+        Mock::given(method("POST"))
+            .and(path("/challenge/1"))
+            .respond_with(move |_: &Request| match fs::read_to_string(&token_file) {
+                Ok(content) if content.starts_with("token1.") => acme_json(json!({})),
+                _ => ResponseTemplate::new(400),
+            })
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/challenge/2"))
+            .respond_with(move |_: &Request| {
+                let published = local.records().iter().any(|record| {
+                    record.record_type == "TXT" && record.name == "_acme-challenge.alice.floy.id"
+                });
+                if published {
+                    acme_json(json!({}))
+                } else {
+                    ResponseTemplate::new(400)
+                }
+            })
+            .mount(&server)
+            .await;
+
+        let mut processing = order.clone();
+        processing["status"] = json!("processing");
+        mock(&server, "/order/1/finalize", acme_json(processing)).await;
+        let mut valid = order;
+        valid["status"] = json!("valid");
+        valid["certificate"] = json!(format!("{}/certificate/1", base));
+        mock(&server, "/order/1", acme_json(valid)).await;
+        mock(
+            &server,
+            "/certificate/1",
+            ResponseTemplate::new(200)
+                .insert_header("Replay-Nonce", "nonce")
+                .set_body_string(self_signed(&["alice.floy.id", "*.alice.floy.id"], 90)),
+        )
+        .await;
+
+        server
+    }
+
+    #[tokio::test]
+    async fn test_secure_site() {
+        let dir = temp_dir("secure");
+        let webroot = dir.join("webroot");
+        let placeholder = zone(&dir, "").await;
+        let local = placeholder.provider.local_zone().unwrap();
+        let server = acme_server(webroot.clone(), local.clone()).await;
+
+        let zone = Zone {
+            config: Config {
+                acme_directory_url: format!("{}/directory", server.uri()),
+                ..placeholder.config.clone()
+            },
+            provider: placeholder.provider.clone(),
+        };
+        create_domain("u1", "b1", "alice", true, &zone.config).unwrap();
+
+        let certificates = Certificates::new(&zone.config);
+        assert!(certificates.secure_site(&zone, "u1", "b1").await.unwrap());
+
+        let tls = certificate_paths(&zone.config, "alice.floy.id");
+        let (available, _) = updater::get_domain_paths("u1", "b1", &zone.config);
+        let site = NginxServer::load(&available).unwrap();
+        assert_eq!(Some(&tls), site.tls.as_ref());
+        assert_eq!(
+            vec![crate::nginx::nginx::Directive::new(
+                "listen",
+                &["443", "ssl"]
+            )],
+            site.directives
+        );
+        assert!(fs::read_to_string(&available)
+            .unwrap()
+            .contains("return 301 https://$host$request_uri;"));
+
+        let mode = fs::metadata(&tls.certificate_key)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        // The challenge responses are gone and the certificate is kept.
+        assert!(!webroot.join(".well-known/acme-challenge/token1").exists());
+        assert!(local
+            .records()
+            .iter()
+            .all(|record| record.record_type != "TXT"));
+        assert!(!certificates.secure_site(&zone, "u1", "b1").await.unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Runs against a local Pebble when `PEBBLE_DIRECTORY` is set, e.g.
+    /// `https://localhost:14000/dir` with `PEBBLE_CA_BUNDLE` pointing at its
+    /// `pebble.minica.pem`. Start Pebble with `PEBBLE_VA_ALWAYS_VALID=1`, as
+    /// it cannot reach the challenge responses of this test.
+    #[tokio::test]
+    async fn test_issue_from_pebble() {
+        let directory_url = match std::env::var("PEBBLE_DIRECTORY") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+
+        let dir = temp_dir("pebble");
+        let mut zone = zone(&dir, &directory_url).await;
+        zone.config.acme_ca_bundle = std::env::var("PEBBLE_CA_BUNDLE").ok();
+
+        let names = vec!["alice.floy.id".to_string(), "*.alice.floy.id".to_string()];
+        let tls = Certificates::new(&zone.config)
+            .issue(&zone, &names)
+            .await
+            .unwrap();
+        assert!(!needs_certificate(Path::new(&tls.certificate), &names, 1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
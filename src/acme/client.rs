@@ -0,0 +1,491 @@
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::{Certificate, Client, Response};
+use rocket::serde::json::{json, Value};
+use rocket::serde::{Deserialize, DeserializeOwned};
+use tokio::sync::Mutex;
+
+use crate::common::errors::ErrorKind;
+
+/// Nonces a request may be retried with after the server rejected one.
+const BAD_NONCE_RETRIES: u32 = 3;
+
+fn b64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// A new P-256 key, used for accounts as well as certificates.
+pub fn generate_key() -> Result<PKey<Private>, ErrorKind> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+/// A DER encoded certificate signing request for `names`, the first one
+/// also being the subject.
+pub fn certificate_request(names: &[String], key: &PKey<Private>) -> Result<Vec<u8>, ErrorKind> {
+    let mut subject = X509NameBuilder::new()?;
+    if let Some(name) = names.first().filter(|name| name.len() <= 64) {
+        subject.append_entry_by_nid(Nid::COMMONNAME, name)?;
+    }
+
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_subject_name(&subject.build())?;
+    builder.set_pubkey(key)?;
+
+    let mut san = SubjectAlternativeName::new();
+    for name in names {
+        san.dns(name);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build().to_der()?)
+}
+
+/// The ES256 key an ACME account is identified by.
+pub struct AccountKey {
+    key: EcKey<Private>,
+}
+
+impl AccountKey {
+    pub fn generate() -> Result<Self, ErrorKind> {
+        Ok(AccountKey {
+            key: generate_key()?.ec_key()?,
+        })
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Result<Self, ErrorKind> {
+        Ok(AccountKey {
+            key: EcKey::private_key_from_pem(pem)?,
+        })
+    }
+
+    /// Reads the key at `path`, creating one there on first use.
+    pub fn load_or_create(path: &Path) -> Result<Self, ErrorKind> {
+        if path.exists() {
+            return Self::from_pem(&fs::read(path)?);
+        }
+
+        let key = Self::generate()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_private(path, &key.key.private_key_to_pem()?)?;
+        Ok(key)
+    }
+
+    /// The public key as a JWK, members in the lexical order RFC 7638
+    /// hashes them in.
+    fn jwk(&self) -> Result<Value, ErrorKind> {
+        let mut ctx = BigNumContext::new()?;
+        let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+        self.key
+            .public_key()
+            .affine_coordinates(self.key.group(), &mut x, &mut y, &mut ctx)?;
+
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&x.to_vec_padded(32)?),
+            "y": b64(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    /// RFC 7638 thumbprint of the public key.
+    pub fn thumbprint(&self) -> Result<String, ErrorKind> {
+        Ok(b64(&sha256(self.jwk()?.to_string().as_bytes())))
+    }
+
+    /// What an HTTP-01 token file has to contain.
+    pub fn key_authorization(&self, token: &str) -> Result<String, ErrorKind> {
+        Ok(format!("{}.{}", token, self.thumbprint()?))
+    }
+
+    /// What the `_acme-challenge` TXT record of a DNS-01 challenge has to
+    /// contain.
+    pub fn dns_value(&self, token: &str) -> Result<String, ErrorKind> {
+        Ok(b64(&sha256(self.key_authorization(token)?.as_bytes())))
+    }
+
+    /// ES256 signature of `data`: `r` and `s` as two 32 byte integers.
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ErrorKind> {
+        let signature = EcdsaSig::sign(&sha256(data), &self.key)?;
+
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend(signature.s().to_vec_padded(32)?);
+        Ok(raw)
+    }
+}
+
+/// Writes a private key readable by its owner only.
+pub fn write_private(path: &Path, pem: &[u8]) -> Result<(), ErrorKind> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(pem)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Order {
+    /// Not part of the resource, taken from the `Location` it was created
+    /// at.
+    #[serde(skip)]
+    pub url: String,
+    pub status: String,
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+    pub error: Option<Value>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Authorization {
+    pub status: String,
+    pub identifier: Identifier,
+    #[serde(default)]
+    pub wildcard: bool,
+    #[serde(default)]
+    pub challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    #[serde(default)]
+    pub token: String,
+    pub status: String,
+    pub error: Option<Value>,
+}
+
+/// An RFC 8555 client acting for one account.
+pub struct AcmeClient {
+    http: Client,
+    directory: Directory,
+    key: AccountKey,
+    /// The account's URL, sent as `kid` once the account is registered.
+    account: Option<String>,
+    nonce: Mutex<Option<String>>,
+}
+
+impl AcmeClient {
+    /// Fetches the directory at `directory_url` and registers `key` with
+    /// it, or looks up the account it already has. `ca_bundle` holds extra
+    /// roots the server's TLS certificate may chain to.
+    pub async fn connect(
+        directory_url: &str,
+        key: AccountKey,
+        contact: Option<&str>,
+        ca_bundle: Option<&Path>,
+    ) -> Result<Self, ErrorKind> {
+        let mut builder = Client::builder();
+        if let Some(path) = ca_bundle {
+            for root in Certificate::from_pem_bundle(&fs::read(path)?)? {
+                builder = builder.add_root_certificate(root);
+            }
+        }
+        let http = builder.build()?;
+
+        let res = http.get(directory_url).send().await?;
+        if !res.status().is_success() {
+            return Err(ErrorKind::Error(format!(
+                "Failed to fetch ACME directory {}: {}",
+                directory_url,
+                res.status()
+            )));
+        }
+        let directory = res.json::<Directory>().await?;
+
+        let mut client = AcmeClient {
+            http,
+            directory,
+            key,
+            account: None,
+            nonce: Mutex::new(None),
+        };
+
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = contact {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = client.directory.new_account.clone();
+        let res = client.post(&url, Some(&account)).await?;
+        client.account = Some(location(&res)?);
+
+        Ok(client)
+    }
+
+    pub fn key(&self) -> &AccountKey {
+        &self.key
+    }
+
+    async fn nonce(&self) -> Result<String, ErrorKind> {
+        if let Some(nonce) = self.nonce.lock().await.take() {
+            return Ok(nonce);
+        }
+
+        let res = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&res).ok_or(ErrorKind::Error(
+            "ACME server did not hand out a nonce".to_string(),
+        ))
+    }
+
+    /// The flattened JWS of `payload` for `url`. An empty payload makes a
+    /// POST-as-GET.
+    async fn sign(&self, url: &str, payload: Option<&Value>) -> Result<Value, ErrorKind> {
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": self.nonce().await?,
+            "url": url,
+        });
+        match &self.account {
+            Some(account) => protected["kid"] = json!(account),
+            None => protected["jwk"] = self.key.jwk()?,
+        }
+
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = payload
+            .map(|payload| b64(payload.to_string().as_bytes()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(format!("{}.{}", protected, payload).as_bytes())?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(&signature),
+        }))
+    }
+
+    /// Sends a signed request, retrying with a fresh nonce when the server
+    /// rejected the one used. Error responses are turned into their problem
+    /// document's detail.
+    async fn post(&self, url: &str, payload: Option<&Value>) -> Result<Response, ErrorKind> {
+        let mut attempt = 0;
+
+        loop {
+            let body = self.sign(url, payload).await?;
+            let res = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+
+            if let Some(nonce) = replay_nonce(&res) {
+                *self.nonce.lock().await = Some(nonce);
+            }
+            if res.status().is_success() {
+                return Ok(res);
+            }
+
+            let status = res.status();
+            let problem = res.json::<Value>().await.unwrap_or_default();
+            let kind = problem["type"].as_str().unwrap_or_default();
+
+            if kind.ends_with(":badNonce") && attempt < BAD_NONCE_RETRIES {
+                attempt += 1;
+                continue;
+            }
+            if kind.ends_with(":rateLimited") {
+                return Err(ErrorKind::RateLimited(None));
+            }
+            return Err(ErrorKind::Error(format!(
+                "ACME request to {} failed with {}: {}",
+                url,
+                status,
+                problem["detail"].as_str().unwrap_or(kind)
+            )));
+        }
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, url: &str) -> Result<T, ErrorKind> {
+        Ok(self.post(url, None).await?.json::<T>().await?)
+    }
+
+    /// Orders a certificate for `names`.
+    pub async fn new_order(&self, names: &[String]) -> Result<Order, ErrorKind> {
+        let identifiers: Vec<Value> = names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect();
+
+        let url = &self.directory.new_order;
+        let res = self
+            .post(url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let url = location(&res)?;
+
+        Ok(Order {
+            url,
+            ..res.json::<Order>().await?
+        })
+    }
+
+    pub async fn order(&self, url: &str) -> Result<Order, ErrorKind> {
+        Ok(Order {
+            url: url.to_string(),
+            ..self.fetch::<Order>(url).await?
+        })
+    }
+
+    pub async fn authorization(&self, url: &str) -> Result<Authorization, ErrorKind> {
+        self.fetch(url).await
+    }
+
+    /// Tells the server the challenge is ready to be validated.
+    pub async fn respond(&self, challenge: &Challenge) -> Result<(), ErrorKind> {
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        Ok(())
+    }
+
+    /// Submits the DER encoded `csr` of a ready order.
+    pub async fn finalize(&self, order: &Order, csr: &[u8]) -> Result<Order, ErrorKind> {
+        let res = self
+            .post(&order.finalize, Some(&json!({ "csr": b64(csr) })))
+            .await?;
+
+        Ok(Order {
+            url: order.url.clone(),
+            ..res.json::<Order>().await?
+        })
+    }
+
+    /// Downloads an issued certificate chain as PEM.
+    pub async fn certificate(&self, url: &str) -> Result<String, ErrorKind> {
+        Ok(self.post(url, None).await?.text().await?)
+    }
+}
+
+fn replay_nonce(res: &Response) -> Option<String> {
+    res.headers()
+        .get("Replay-Nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(str::to_string)
+}
+
+fn location(res: &Response) -> Result<String, ErrorKind> {
+    res.headers()
+        .get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .map(str::to_string)
+        .ok_or(ErrorKind::Error(
+            "ACME server response is missing a Location".to_string(),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::bn::BigNum;
+    use openssl::ecdsa::EcdsaSig;
+
+    use super::*;
+
+    #[test]
+    fn test_signature_verifies() {
+        let key = AccountKey::generate().unwrap();
+        let signature = key.sign(b"protected.payload").unwrap();
+        assert_eq!(64, signature.len());
+
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32]).unwrap(),
+            BigNum::from_slice(&signature[32..]).unwrap(),
+        )
+        .unwrap();
+        assert!(signature
+            .verify(&sha256(b"protected.payload"), &key.key)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_key_round_trip_and_challenge_values() {
+        let dir = std::env::temp_dir().join(format!("floy-acme-key-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("account.pem");
+
+        let key = AccountKey::load_or_create(&path).unwrap();
+        let loaded = AccountKey::load_or_create(&path).unwrap();
+        assert_eq!(key.thumbprint().unwrap(), loaded.thumbprint().unwrap());
+
+        // A SHA-256 digest, base64url without padding.
+        let thumbprint = key.thumbprint().unwrap();
+        assert_eq!(43, thumbprint.len());
+        assert_eq!(
+            format!("token.{}", thumbprint),
+            key.key_authorization("token").unwrap()
+        );
+        assert_eq!(
+            b64(&sha256(format!("token.{}", thumbprint).as_bytes())),
+            key.dns_value("token").unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_certificate_request() {
+        let key = generate_key().unwrap();
+        let names = vec!["alice.floy.id".to_string(), "*.alice.floy.id".to_string()];
+        let csr =
+            openssl::x509::X509Req::from_der(&certificate_request(&names, &key).unwrap()).unwrap();
+
+        assert!(csr.verify(&key).unwrap());
+        assert_eq!(
+            "alice.floy.id",
+            csr.subject_name()
+                .entries()
+                .next()
+                .unwrap()
+                .data()
+                .as_utf8()
+                .unwrap()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,2 @@
+pub mod acme;
+pub mod client;
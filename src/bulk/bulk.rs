@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::common::errors::ErrorKind;
+use crate::common::records::validate_hostname;
+use crate::models::{BulkRow, BulkSlug, SubdomainRequest};
+
+/// A CSV line, `slugs` holding `slug:site_id` pairs separated by `;`.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CsvRow {
+    user_id: String,
+    business_id: String,
+    subdomain: String,
+    #[serde(default)]
+    slugs: String,
+    wildcard: Option<bool>,
+    ttl: Option<u32>,
+    proxied: Option<bool>,
+}
+
+fn parse_slugs(s: &str, line: usize) -> Result<Vec<BulkSlug>, ErrorKind> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((slug, site_id)) => Ok(BulkSlug {
+                slug: slug.trim().to_string(),
+                site_id: site_id.trim().to_string(),
+            }),
+            None => Err(ErrorKind::ValidationError(format!(
+                "line {}: slug {} must be written as slug:site_id",
+                line, pair
+            ))),
+        })
+        .collect()
+}
+
+fn parse_csv(body: &str) -> Result<Vec<BulkRow>, ErrorKind> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body.as_bytes());
+
+    let mut rows = vec![];
+    for (i, row) in reader.deserialize::<CsvRow>().enumerate() {
+        // Line 1 is the header.
+        let line = i + 2;
+        let row = row.map_err(|e| ErrorKind::ValidationError(format!("line {}: {}", line, e)))?;
+
+        rows.push(BulkRow {
+            slugs: parse_slugs(&row.slugs, line)?,
+            user_id: row.user_id,
+            business_id: row.business_id,
+            subdomain: row.subdomain,
+            wildcard: row.wildcard.unwrap_or(false),
+            ttl: row.ttl,
+            proxied: row.proxied,
+        });
+    }
+
+    Ok(rows)
+}
+
+/// Reads a batch sent either as a JSON array of rows or as CSV with a
+/// `user_id,business_id,subdomain,slugs` header (plus optional `wildcard`,
+/// `ttl` and `proxied` columns).
+pub fn parse_rows(body: &str, json: bool) -> Result<Vec<BulkRow>, ErrorKind> {
+    let rows = if json {
+        rocket::serde::json::from_str(body)
+            .map_err(|e| ErrorKind::ValidationError(format!("Invalid batch: {}", e)))?
+    } else {
+        parse_csv(body)?
+    };
+
+    if rows.is_empty() {
+        return Err(ErrorKind::ValidationError("The batch is empty".to_string()));
+    }
+
+    Ok(rows)
+}
+
+/// Ids end up as nginx paths and slugs in `location` blocks, so both are
+/// kept to letters, digits, `-` and `_`.
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_row(row: &BulkRow) -> Result<(), String> {
+    if !is_identifier(&row.user_id) || !is_identifier(&row.business_id) {
+        return Err("user_id and business_id must be non-empty identifiers".to_string());
+    }
+
+    if !validate_hostname(&row.subdomain) || row.subdomain.starts_with("*.") {
+        return Err(format!("{} is not a valid hostname", row.subdomain));
+    }
+
+    let mut slugs = HashSet::new();
+    for slug in &row.slugs {
+        if !is_identifier(&slug.slug) || !is_identifier(&slug.site_id) {
+            return Err(format!("Invalid slug {}:{}", slug.slug, slug.site_id));
+        }
+        if !slugs.insert(&slug.slug) {
+            return Err(format!("Slug {} is listed twice", slug.slug));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks every row on its own and against the rest of the batch: each
+/// subdomain and each business may only appear once since a business has one
+/// site per zone. Returns the problem with each row, if any.
+pub fn validate_rows(rows: &[BulkRow]) -> Vec<Option<String>> {
+    let mut subdomains = HashSet::new();
+    let mut businesses = HashSet::new();
+
+    rows.iter()
+        .map(|row| {
+            validate_row(row).err().or_else(|| {
+                if !subdomains.insert(row.subdomain.to_lowercase()) {
+                    Some(format!("Subdomain {} is listed twice", row.subdomain))
+                } else if !businesses.insert((&row.user_id, &row.business_id)) {
+                    Some(format!(
+                        "Business {}/{} is listed twice",
+                        row.user_id, row.business_id
+                    ))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// True when a nested row's parent is created by the same user earlier in
+/// the batch, so it can't be checked against DNS before provisioning.
+pub fn parent_in_batch(rows: &[BulkRow], index: usize) -> bool {
+    let row = &rows[index];
+
+    match row.subdomain.split_once('.') {
+        Some((_, parent)) => rows[..index]
+            .iter()
+            .any(|r| r.subdomain.eq_ignore_ascii_case(parent) && r.user_id == row.user_id),
+        None => false,
+    }
+}
+
+pub fn domain_request(row: &BulkRow, zone: Option<&str>) -> SubdomainRequest {
+    SubdomainRequest {
+        user_id: row.user_id.clone(),
+        business_id: row.business_id.clone(),
+        subdomain: row.subdomain.clone(),
+        wildcard: row.wildcard,
+        ttl: row.ttl,
+        proxied: row.proxied,
+        zone: zone.map(str::to_string),
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum RowStatus {
+    Created,
+    /// Rejected by validation, nothing was done.
+    Invalid,
+    /// Provisioning started but did not finish.
+    Failed,
+    /// Not attempted because an earlier row stopped the batch.
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RowResult {
+    /// 1-based position in the batch.
+    pub row: usize,
+    pub subdomain: String,
+    pub status: RowStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Slug pages added before any failure.
+    pub slugs: Vec<String>,
+}
+
+impl RowResult {
+    pub fn new(index: usize, row: &BulkRow, status: RowStatus, error: Option<String>) -> Self {
+        RowResult {
+            row: index + 1,
+            subdomain: row.subdomain.clone(),
+            status,
+            error,
+            slugs: vec![],
+        }
+    }
+}
+
+pub fn count(results: &[RowResult], status: RowStatus) -> usize {
+    results.iter().filter(|r| r.status == status).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "user_id,business_id,subdomain,slugs,proxied\n\
+                       u1,b1,alice,menu:s1;about:s2,\n\
+                       u1,b2,blog.alice,,false\n";
+
+    #[test]
+    fn test_parse_rows() {
+        let rows = parse_rows(CSV, false).unwrap();
+
+        assert_eq!(2, rows.len());
+        assert_eq!(
+            vec![
+                BulkSlug {
+                    slug: "menu".to_string(),
+                    site_id: "s1".to_string()
+                },
+                BulkSlug {
+                    slug: "about".to_string(),
+                    site_id: "s2".to_string()
+                },
+            ],
+            rows[0].slugs
+        );
+        assert_eq!(None, rows[0].proxied);
+        assert_eq!(Some(false), rows[1].proxied);
+        assert!(rows[1].slugs.is_empty());
+
+        let json = r#"[{"user_id": "u1", "business_id": "b1", "subdomain": "alice",
+                        "slugs": [{"slug": "menu", "site_id": "s1"}]}]"#;
+        let from_json = parse_rows(json, true).unwrap();
+        assert_eq!("alice", from_json[0].subdomain);
+        assert_eq!(rows[0].slugs[..1], from_json[0].slugs[..]);
+        assert!(!from_json[0].wildcard);
+
+        assert!(parse_rows(
+            "user_id,business_id,subdomain,slugs\nu1,b1,alice,menu\n",
+            false
+        )
+        .is_err());
+        assert!(parse_rows("[]", true).is_err());
+    }
+
+    #[test]
+    fn test_validate_rows() {
+        let mut rows = parse_rows(CSV, false).unwrap();
+        rows.push(rows[0].clone());
+        rows.push(BulkRow {
+            business_id: "../etc".to_string(),
+            subdomain: "carol".to_string(),
+            ..rows[0].clone()
+        });
+
+        let errors = validate_rows(&rows);
+
+        assert_eq!(None, errors[0]);
+        assert_eq!(None, errors[1]);
+        assert_eq!(
+            Some("Subdomain alice is listed twice".to_string()),
+            errors[2]
+        );
+        assert!(errors[3].is_some());
+        assert!(parent_in_batch(&rows, 1));
+        assert!(!parent_in_batch(&rows, 0));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::provider::provider::CacheStats;
+
+/// `(content, id)` of a record, or `None` when the lookup found nothing.
+pub type CachedRecord = Option<(String, String)>;
+
+struct Entry {
+    record: CachedRecord,
+    expires: Instant,
+}
+
+/// Short-lived cache of `(name, type)` lookups. Negative answers are cached
+/// too, which is what saves the second lookup when creating a subdomain.
+/// A zero TTL disables it.
+pub struct RecordCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RecordCache {
+    pub fn new(ttl: Duration) -> Self {
+        RecordCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    fn lookup(
+        entries: &mut HashMap<(String, String), Entry>,
+        name: &str,
+        record_type: &str,
+    ) -> Option<CachedRecord> {
+        let key = (name.to_string(), record_type.to_string());
+
+        match entries.get(&key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.record.clone()),
+            expired => {
+                if expired.is_some() {
+                    entries.remove(&key);
+                }
+                None
+            }
+        }
+    }
+
+    fn count<T>(&self, found: Option<T>) -> Option<T> {
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Returns the cached answer for `name`/`record_type`, counting the hit
+    /// or miss. The outer `None` means the lookup has to go to the API.
+    pub fn get(&self, name: &str, record_type: &str) -> Option<CachedRecord> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        self.count(Self::lookup(&mut entries, name, record_type))
+    }
+
+    /// Like [`RecordCache::get`] for several types of `name` answered by one
+    /// API call. Counts a single hit when every type is cached and a single
+    /// miss otherwise.
+    pub fn get_all(&self, name: &str, record_types: &[&str]) -> Option<Vec<CachedRecord>> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let found = record_types
+            .iter()
+            .map(|record_type| Self::lookup(&mut entries, name, record_type))
+            .collect();
+        self.count(found)
+    }
+
+    pub fn insert(&self, name: &str, record_type: &str, record: CachedRecord) {
+        if !self.enabled() {
+            return;
+        }
+
+        self.entries.lock().unwrap().insert(
+            (name.to_string(), record_type.to_string()),
+            Entry {
+                record,
+                expires: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    /// Drops every record type cached for `name`.
+    pub fn invalidate_name(&self, name: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(cached, _), _| cached != name);
+    }
+
+    /// Drops the entry of the record with `id`, for writes that only know
+    /// the id.
+    pub fn invalidate_id(&self, id: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| !matches!(&entry.record, Some((_, cached)) if cached == id));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some(content: &str, id: &str) -> CachedRecord {
+        Some((content.to_string(), id.to_string()))
+    }
+
+    #[test]
+    fn test_get_insert_invalidate() {
+        let cache = RecordCache::new(Duration::from_secs(60));
+
+        assert_eq!(None, cache.get("alice.floy.id", "A"));
+        cache.insert("alice.floy.id", "A", some("10.0.0.1", "r1"));
+        cache.insert("alice.floy.id", "AAAA", None);
+        cache.insert("bob.floy.id", "A", some("10.0.0.2", "r2"));
+
+        assert_eq!(
+            Some(some("10.0.0.1", "r1")),
+            cache.get("alice.floy.id", "A")
+        );
+        assert_eq!(Some(None), cache.get("alice.floy.id", "AAAA"));
+
+        cache.invalidate_id("r2");
+        assert_eq!(None, cache.get("bob.floy.id", "A"));
+
+        cache.invalidate_name("alice.floy.id");
+        assert_eq!(None, cache.get("alice.floy.id", "A"));
+
+        let stats = cache.stats();
+        assert_eq!(2, stats.hits);
+        assert_eq!(3, stats.misses);
+        assert_eq!(0.4, stats.hit_rate);
+        assert_eq!(0, stats.entries);
+    }
+
+    #[test]
+    fn test_expiry_and_disabled() {
+        let cache = RecordCache::new(Duration::from_millis(1));
+        cache.insert("alice.floy.id", "A", some("10.0.0.1", "r1"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(None, cache.get("alice.floy.id", "A"));
+
+        let disabled = RecordCache::new(Duration::ZERO);
+        disabled.insert("alice.floy.id", "A", some("10.0.0.1", "r1"));
+        assert_eq!(None, disabled.get("alice.floy.id", "A"));
+        assert_eq!(0, disabled.stats().misses);
+    }
+
+    #[test]
+    fn test_get_all_counts_once() {
+        let cache = RecordCache::new(Duration::from_secs(60));
+        let types = ["A", "AAAA"];
+
+        cache.insert("alice.floy.id", "A", some("10.0.0.1", "r1"));
+        assert_eq!(None, cache.get_all("alice.floy.id", &types));
+        cache.insert("alice.floy.id", "AAAA", None);
+        assert_eq!(
+            Some(vec![some("10.0.0.1", "r1"), None]),
+            cache.get_all("alice.floy.id", &types)
+        );
+
+        let stats = cache.stats();
+        assert_eq!(1, stats.hits);
+        assert_eq!(1, stats.misses);
+    }
+}
@@ -1,13 +1,37 @@
-use reqwest::Client;
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
 use rocket::request::FromRequest;
+use rocket::serde::json::{json, Value};
+use tokio::sync::Semaphore;
 
+use crate::cloudflare::cache::RecordCache;
 use crate::common::errors::ErrorKind;
+use crate::common::record_ids::RecordIdStore;
 use crate::config::Config;
-use crate::models::{DnsRecord, Records};
+use crate::models::{CfEnvelope, DeletedRecord, DnsRecord, DnsRecordResponse, DnsRecords, Records};
+use crate::provider::provider::{
+    record_type_for, CacheStats, DnsProvider, RecordOptions, ADDRESS_RECORD_TYPES,
+};
+
+const RECORDS_PER_PAGE: u32 = 100;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Error codes from the v4 API's `errors[]` envelope.
+const AUTH_ERROR_CODES: [u32; 8] = [6003, 6111, 9103, 9106, 9107, 9109, 10000, 10001];
+const RECORD_EXISTS_CODES: [u32; 3] = [81053, 81057, 81058];
+const ZONE_NOT_FOUND_CODES: [u32; 3] = [1001, 7000, 7003];
+const RECORD_NOT_FOUND_CODES: [u32; 1] = [81044];
+const RATE_LIMIT_CODES: [u32; 1] = [971];
+const VALIDATION_ERROR_CODES: [u32; 1] = [1004];
 
 pub struct Cloudflare {
     client: Client,
     config: Config,
+    limiter: Semaphore,
+    cache: RecordCache,
+    ids: RecordIdStore,
 }
 
 impl Cloudflare {
@@ -24,230 +48,575 @@ impl Cloudflare {
 
         let client = Client::builder().default_headers(headers).build().unwrap();
 
-        Cloudflare { client, config }
+        let limiter = Semaphore::new(config.cf_max_concurrent_requests.max(1));
+        let cache = RecordCache::new(Duration::from_secs(config.cf_cache_ttl));
+        let ids = RecordIdStore::new(&config.record_ids_path);
+
+        Cloudflare {
+            client,
+            config,
+            limiter,
+            cache,
+            ids,
+        }
+    }
+
+    /// Exponential backoff with equal jitter: a random delay between half and
+    /// all of `base * 2^attempt`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = Duration::from_millis(self.config.cf_retry_base_delay_ms)
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY);
+
+        rand::thread_rng().gen_range(ceiling / 2..=ceiling)
+    }
+
+    fn retry_after(res: &Response) -> Option<Duration> {
+        res.headers()
+            .get(reqwest::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .map(|secs| Duration::from_secs(secs).min(MAX_RETRY_DELAY))
+    }
+
+    /// Sends a request while holding one of the `cf_max_concurrent_requests`
+    /// permits, retrying up to `cf_max_retries` times.
+    ///
+    /// 429s and connection failures are retried for every method since
+    /// Cloudflare never processed the request. 5xx responses and timeouts are
+    /// only retried for idempotent methods, so a `POST` is never replayed
+    /// after it may have created a record.
+    async fn send(&self, builder: RequestBuilder) -> Result<Response, ErrorKind> {
+        let request = builder.build()?;
+        let idempotent = request.method() != Method::POST;
+        let mut attempt = 0;
+
+        loop {
+            let retry = request
+                .try_clone()
+                .ok_or(ErrorKind::Error("Request cannot be retried".to_string()))?;
+
+            let res = {
+                let _permit = self
+                    .limiter
+                    .acquire()
+                    .await
+                    .map_err(|e| ErrorKind::Error(e.to_string()))?;
+                self.client.execute(retry).await
+            };
+
+            let delay = match &res {
+                Ok(r) if r.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    Some(Self::retry_after(r).unwrap_or_else(|| self.backoff(attempt)))
+                }
+                Ok(r) if r.status().is_server_error() && idempotent => Some(self.backoff(attempt)),
+                Err(e) if e.is_connect() || (e.is_timeout() && idempotent) => {
+                    Some(self.backoff(attempt))
+                }
+                _ => None,
+            };
+
+            match delay {
+                Some(delay) if attempt < self.config.cf_max_retries => {
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return Ok(res?),
+            }
+        }
+    }
+
+    /// Maps a failed response onto an [`ErrorKind`] using the codes in
+    /// Cloudflare's `errors[]` envelope, falling back to the HTTP status when
+    /// the body can't be decoded.
+    async fn decode_error(res: Response, fallback: &str) -> ErrorKind {
+        let status = res.status();
+        let retry_after = Self::retry_after(&res).map(|d| d.as_secs());
+        let envelope = res.json::<CfEnvelope>().await.unwrap_or_default();
+
+        let codes: Vec<u32> = envelope.errors.iter().map(|e| e.code).collect();
+        let has = |group: &[u32]| codes.iter().any(|c| group.contains(c));
+
+        let message = envelope
+            .errors
+            .iter()
+            .chain(envelope.messages.iter())
+            .map(|m| m.message.as_str())
+            .filter(|m| !m.is_empty())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let message = if message.is_empty() {
+            fallback.to_string()
+        } else {
+            message
+        };
+
+        if status == StatusCode::TOO_MANY_REQUESTS || has(&RATE_LIMIT_CODES) {
+            ErrorKind::RateLimited(retry_after)
+        } else if has(&AUTH_ERROR_CODES)
+            || status == StatusCode::UNAUTHORIZED
+            || status == StatusCode::FORBIDDEN
+        {
+            ErrorKind::ProviderAuthFailed(message)
+        } else if has(&RECORD_EXISTS_CODES) {
+            ErrorKind::RecordAlreadyExists(message)
+        } else if has(&ZONE_NOT_FOUND_CODES) {
+            ErrorKind::ZoneNotFound(message)
+        } else if has(&RECORD_NOT_FOUND_CODES) || status == StatusCode::NOT_FOUND {
+            ErrorKind::NotFound
+        } else if has(&VALIDATION_ERROR_CODES)
+            || codes.iter().any(|c| (9000..10000).contains(c))
+            || status == StatusCode::BAD_REQUEST
+            || status == StatusCode::UNPROCESSABLE_ENTITY
+        {
+            ErrorKind::ValidationError(message)
+        } else {
+            ErrorKind::Error(format!("{}: {}", fallback, message))
+        }
     }
 
-    pub async fn add_subdomain_dns_record(
+    /// Applies a partial update, leaving every field not in `body` untouched.
+    async fn patch_record(&self, id: &str, body: &Value) -> Result<DnsRecord, ErrorKind> {
+        let url = format!("{}/{}", self.records_url(), id);
+
+        let res = self
+            .send(
+                self.client
+                    .patch(&url)
+                    .bearer_auth(&self.config.cf_api_key)
+                    .body(body.to_string()),
+            )
+            .await;
+        self.cache.invalidate_id(id);
+        let res = res?;
+
+        if res.status().is_success() {
+            let record = res.json::<DnsRecordResponse>().await?.result;
+            self.cache.invalidate_name(&record.name);
+            Ok(record)
+        } else {
+            Err(Self::decode_error(res, "Failed to update DNS record").await)
+        }
+    }
+
+    fn record_name(&self, subdomain: &str, wildcard: bool) -> String {
+        if wildcard {
+            format!("*.{}.{}", subdomain, &self.config.dns_suffix)
+        } else {
+            format!("{}.{}", subdomain, &self.config.dns_suffix)
+        }
+    }
+
+    /// Patches a subdomain's address record through its stored id, looking
+    /// it up by name only when no id is stored or the stored one is gone.
+    async fn patch_address_record(
         &self,
-        subdomain: &String,
-        ip: &String,
+        subdomain: &str,
+        record_type: &str,
+        wildcard: bool,
+        body: &Value,
     ) -> Result<(), ErrorKind> {
-        match &self.get_subdomain_dns_record(subdomain, false).await {
+        let name = self.record_name(subdomain, wildcard);
+
+        if let Some(id) = self.ids.get(&name, record_type).await? {
+            match self.patch_record(&id, body).await {
+                Ok(_) => return Ok(()),
+                Err(ErrorKind::NotFound) => self.ids.remove(&name, record_type).await?,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (_, id) = self
+            .get_subdomain_dns_record(subdomain, record_type, wildcard)
+            .await?;
+        self.patch_record(&id, body).await?;
+        self.ids.set(&name, record_type, &id).await?;
+
+        Ok(())
+    }
+
+    /// Deletes a subdomain's address record like [`Self::patch_address_record`]
+    /// patches it. Returns whether there was a record to delete.
+    async fn delete_address_record(
+        &self,
+        subdomain: &str,
+        record_type: &str,
+        wildcard: bool,
+    ) -> Result<bool, ErrorKind> {
+        let name = self.record_name(subdomain, wildcard);
+
+        if let Some(id) = self.ids.get(&name, record_type).await? {
+            match self.delete_record(&id).await {
+                Ok(_) => return Ok(true),
+                Err(ErrorKind::NotFound) => self.ids.remove(&name, record_type).await?,
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self
+            .get_subdomain_dns_record(subdomain, record_type, wildcard)
+            .await
+        {
+            Ok((_, id)) => self.delete_record(&id).await.map(|_| true),
+            Err(ErrorKind::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn records_url(&self) -> String {
+        format!(
+            "{}/zones/{}/dns_records",
+            self.config.cf_api_url.trim_end_matches('/'),
+            &self.config.cf_zone_id
+        )
+    }
+}
+
+#[rocket::async_trait]
+impl DnsProvider for Cloudflare {
+    async fn add_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        ip: &str,
+        options: &RecordOptions,
+    ) -> Result<(), ErrorKind> {
+        let record_type = record_type_for(ip);
+
+        match self
+            .get_subdomain_dns_record(subdomain, record_type, false)
+            .await
+        {
             Ok(_) => {
-                return Err(ErrorKind::Error("Subdomain already exists".to_string()));
+                return Err(ErrorKind::RecordAlreadyExists(format!(
+                    "{}.{}",
+                    subdomain, &self.config.dns_suffix
+                )))
             }
-            Err(_) => {}
-        };
+            Err(ErrorKind::NotFound) => {}
+            Err(e) => return Err(e),
+        }
 
         let name = format!("{}.{}", subdomain, &self.config.dns_suffix);
 
-        let client = &self.client;
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-            &self.config.cf_zone_id
+        let body = DnsRecord::new(
+            record_type.to_owned(),
+            name.clone(),
+            options.ttl,
+            ip.to_owned(),
+            options.proxied,
         );
+        let id = self.create_record(&body).await?;
+
+        if options.wildcard {
+            let wildcard_body = DnsRecord {
+                name: format!("*.{}", &name),
+                ..body
+            };
+            if let Err(e) = self.create_record(&wildcard_body).await {
+                // Either both records exist or neither does.
+                let _ = self.delete_record(&id).await;
+                return Err(e);
+            }
+        }
 
-        let body = DnsRecord::new("A".to_owned(), name.clone(), 1, ip.to_owned(), true);
-
-        let res = client
-            .post(&url)
-            .bearer_auth(&self.config.cf_api_key)
-            .body(body.to_string())
-            .send()
-            .await?;
+        Ok(())
+    }
 
-        if res.status().is_success() {
-            Ok(())
-        } else {
-            Err(ErrorKind::Error("Failed to add DNS record".to_string()))
-        }
-
-        // let wildcard_body = DnsRecord::new(
-        //     "A".to_owned(),
-        //     format!("*.{}", &name),
-        //     1,
-        //     ip.to_owned(),
-        //     true,
-        // );
-        //
-        // let res = client
-        //     .post(&url)
-        //     .bearer_auth(&self.config.cf_api_key)
-        //     .body(wildcard_body.to_string())
-        //     .send()
-        //     .await?;
-        //
-        // if res.status().is_success() {
-        //     Ok(())
-        // } else {
-        //     Err(ErrorKind::Error("Failed to add DNS record".to_string()))
-        // }
-    }
-
-    pub async fn get_subdomain_dns_record(
+    async fn get_subdomain_dns_record(
         &self,
-        subdomain: &String,
+        subdomain: &str,
+        record_type: &str,
         wildcard: bool,
     ) -> Result<(String, String), ErrorKind> {
-        let name = if wildcard {
-            format!("*.{}.{}", subdomain, &self.config.dns_suffix)
-        } else {
-            format!("{}.{}", subdomain, &self.config.dns_suffix)
-        };
+        let name = self.record_name(subdomain, wildcard);
+
+        if let Some(cached) = self.cache.get(&name, record_type) {
+            return cached.ok_or(ErrorKind::NotFound);
+        }
 
         let client = &self.client;
 
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records?type=A&name={}",
-            &self.config.cf_zone_id, name
-        );
+        let url = format!("{}?type={}&name={}", self.records_url(), record_type, name);
 
-        let res = client
-            .get(&url)
-            .bearer_auth(&self.config.cf_api_key)
-            .send()
+        let res = self
+            .send(client.get(&url).bearer_auth(&self.config.cf_api_key))
             .await?;
 
         if res.status().is_success() {
             let record = res.json::<Records>().await?;
 
-            if record.result.len() < 1 {
-                return Err(ErrorKind::Error("Failed to get DNS record".to_string()));
-            }
+            let found = record
+                .result
+                .first()
+                .map(|r| (r.content.clone(), r.id.clone()));
+            self.cache.insert(&name, record_type, found.clone());
 
-            Ok((
-                record.result[0].content.clone(),
-                record.result[0].id.clone(),
-            ))
+            found.ok_or(ErrorKind::NotFound)
         } else {
-            Err(ErrorKind::Error("Failed to get DNS record".to_string()))
+            Err(Self::decode_error(res, "Failed to get DNS record").await)
         }
     }
 
-    pub async fn update_subdomain_dns_record(
+    async fn update_subdomain_dns_record(
         &self,
-        subdomain: &String,
-        ip: &String,
+        subdomain: &str,
+        ip: &str,
     ) -> Result<(), ErrorKind> {
-        let record = match &self.get_subdomain_dns_record(subdomain, false).await {
-            Ok(r) => r.clone(),
-            Err(_) => {
-                return Err(ErrorKind::Error("Subdomain does not exist".to_string()));
+        let record_type = record_type_for(ip);
+        let content = json!({ "content": ip });
+
+        self.patch_address_record(subdomain, record_type, false, &content)
+            .await?;
+
+        // The wildcard is opt-in, so only follow the address when it exists.
+        match self
+            .patch_address_record(subdomain, record_type, true, &content)
+            .await
+        {
+            Err(ErrorKind::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn delete_subdomain_dns_record(&self, subdomain: &str) -> Result<(), ErrorKind> {
+        let mut stored = false;
+        for record_type in ADDRESS_RECORD_TYPES {
+            let name = self.record_name(subdomain, false);
+            stored |= self.ids.get(&name, record_type).await?.is_some();
+        }
+
+        // Without a stored id the record may only be found by name, so
+        // check that first to report a missing subdomain cheaply.
+        if !stored && !self.check_exists(subdomain).await? {
+            return Err(ErrorKind::NotFound);
+        }
+
+        let mut deleted = false;
+        for record_type in ADDRESS_RECORD_TYPES {
+            for wildcard in [false, true] {
+                deleted |= self
+                    .delete_address_record(subdomain, record_type, wildcard)
+                    .await?;
             }
-        };
+        }
+
+        if !deleted {
+            return Err(ErrorKind::NotFound);
+        }
+
+        Ok(())
+    }
 
+    async fn check_exists(&self, subdomain: &str) -> Result<bool, ErrorKind> {
         let name = format!("{}.{}", subdomain, &self.config.dns_suffix);
 
+        if let Some(cached) = self.cache.get_all(&name, &ADDRESS_RECORD_TYPES) {
+            return Ok(cached.iter().any(Option::is_some));
+        }
+
         let client = &self.client;
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-            &self.config.cf_zone_id
-        );
 
-        let body = DnsRecord::new("A".to_owned(), name.clone(), 1, ip.to_owned(), true);
+        let url = format!("{}?name={}", self.records_url(), name);
 
-        let res = client
-            .put(format!("{}/{}", &url, record.1))
-            .bearer_auth(&self.config.cf_api_key)
-            .body(body.to_string())
-            .send()
+        let res = self
+            .send(client.get(&url).bearer_auth(&self.config.cf_api_key))
             .await?;
 
         if res.status().is_success() {
+            let record = res.json::<Records>().await?;
+
+            // The answer covers every type, so remember both address types
+            // for the lookups that usually follow.
+            for record_type in ADDRESS_RECORD_TYPES {
+                let found = record
+                    .result
+                    .iter()
+                    .find(|r| r.record_type == record_type)
+                    .map(|r| (r.content.clone(), r.id.clone()));
+                self.cache.insert(&name, record_type, found);
+            }
+
+            Ok(record
+                .result
+                .iter()
+                .any(|r| ADDRESS_RECORD_TYPES.contains(&r.record_type.as_str())))
         } else {
-            return Err(ErrorKind::Error("Failed to update DNS record".to_string()));
+            Err(Self::decode_error(res, "Failed to get DNS record").await)
         }
+    }
 
-        let wildcard_record = &self.get_subdomain_dns_record(subdomain, true).await?;
+    async fn list_records(
+        &self,
+        record_type: Option<&str>,
+        name_suffix: Option<&str>,
+    ) -> Result<Vec<DnsRecord>, ErrorKind> {
+        let client = &self.client;
 
-        let wildcard_body = DnsRecord::new(
-            "A".to_owned(),
-            format!("*.{}", &name),
-            1,
-            ip.to_owned(),
-            true,
-        );
+        let mut query = vec![("per_page", RECORDS_PER_PAGE.to_string())];
+        if let Some(record_type) = record_type {
+            query.push(("type", record_type.to_uppercase()));
+        }
+        if let Some(suffix) = name_suffix {
+            query.push(("name.endswith", suffix.to_string()));
+        }
 
-        let wildcard_res = client
-            .put(&format!("{}/{}", &url, wildcard_record.1))
-            .bearer_auth(&self.config.cf_api_key)
-            .body(wildcard_body.to_string())
-            .send()
-            .await?;
+        let mut records = vec![];
+        let mut page = 1;
+
+        loop {
+            let res = self
+                .send(
+                    client
+                        .get(self.records_url())
+                        .bearer_auth(&self.config.cf_api_key)
+                        .query(&query)
+                        .query(&[("page", page)]),
+                )
+                .await?;
+
+            if !res.status().is_success() {
+                return Err(Self::decode_error(res, "Failed to get DNS records").await);
+            }
 
-        if wildcard_res.status().is_success() {
-            Ok(())
-        } else {
-            Err(ErrorKind::Error("Failed to update DNS record".to_string()))
+            let body = res.json::<DnsRecords>().await?;
+            let fetched = body.result.len();
+            records.extend(body.result);
+
+            let total_pages = body.result_info.map(|info| info.total_pages).unwrap_or(1);
+            if fetched == 0 || page >= total_pages {
+                break;
+            }
+            page += 1;
+        }
+
+        if let Some(suffix) = name_suffix {
+            records.retain(|r| r.name.ends_with(suffix));
         }
+
+        Ok(records)
     }
 
-    pub async fn delete_subdomain_dns_record(&self, subdomain: &String) -> Result<(), ErrorKind> {
-        let record = match &self.get_subdomain_dns_record(subdomain, false).await {
-            Ok(r) => r.clone(),
-            Err(_) => {
-                return Err(ErrorKind::Error("Subdomain does not exist".to_string()));
-            }
-        };
+    async fn list_subdomain_records(&self, subdomain: &str) -> Result<Vec<DnsRecord>, ErrorKind> {
+        let name = format!("{}.{}", subdomain, &self.config.dns_suffix);
+        let nested = format!(".{}", name);
+
+        Ok(self
+            .list_records(None, Some(&name))
+            .await?
+            .into_iter()
+            .filter(|r| r.name == name || r.name.ends_with(&nested))
+            .collect())
+    }
 
+    async fn get_record(&self, id: &str) -> Result<DnsRecord, ErrorKind> {
         let client = &self.client;
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-            &self.config.cf_zone_id
-        );
+        let url = format!("{}/{}", self.records_url(), id);
 
-        let res = client
-            .delete(format!("{}/{}", &url, record.1))
-            .bearer_auth(&self.config.cf_api_key)
-            .send()
+        let res = self
+            .send(client.get(&url).bearer_auth(&self.config.cf_api_key))
             .await?;
 
         if res.status().is_success() {
+            Ok(res.json::<DnsRecordResponse>().await?.result)
         } else {
-            return Err(ErrorKind::Error("Failed to delete DNS record".to_string()));
+            Err(Self::decode_error(res, "Failed to get DNS record").await)
         }
+    }
 
-        let wildcard_record = &self.get_subdomain_dns_record(subdomain, true).await?;
+    async fn create_record(&self, record: &DnsRecord) -> Result<String, ErrorKind> {
+        let client = &self.client;
+        let url = self.records_url();
+
+        let res = self
+            .send(
+                client
+                    .post(&url)
+                    .bearer_auth(&self.config.cf_api_key)
+                    .body(record.to_string()),
+            )
+            .await;
+        self.cache.invalidate_name(&record.name);
+        let res = res?;
 
-        let wildcard_res = client
-            .delete(&format!("{}/{}", &url, wildcard_record.1))
-            .bearer_auth(&self.config.cf_api_key)
-            .send()
-            .await?;
+        if res.status().is_success() {
+            let created = res.json::<DnsRecordResponse>().await?;
 
-        if wildcard_res.status().is_success() {
-            Ok(())
+            let id = created
+                .result
+                .id
+                .ok_or(ErrorKind::Error("Failed to add DNS record".to_string()))?;
+
+            if ADDRESS_RECORD_TYPES.contains(&record.record_type.as_str()) {
+                self.ids.set(&record.name, &record.record_type, &id).await?;
+            }
+
+            Ok(id)
         } else {
-            Err(ErrorKind::Error("Failed to delete DNS record".to_string()))
+            Err(Self::decode_error(res, "Failed to add DNS record").await)
         }
     }
 
-    pub async fn check_exists(&self, subdomain: &String) -> Result<bool, ErrorKind> {
-        let name = format!("{}.{}", subdomain, &self.config.dns_suffix);
-
+    async fn update_record(&self, id: &str, record: &DnsRecord) -> Result<(), ErrorKind> {
         let client = &self.client;
+        let url = format!("{}/{}", self.records_url(), id);
+
+        let res = self
+            .send(
+                client
+                    .put(&url)
+                    .bearer_auth(&self.config.cf_api_key)
+                    .body(record.to_string()),
+            )
+            .await;
+        self.cache.invalidate_id(id);
+        self.cache.invalidate_name(&record.name);
+        let res = res?;
 
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records?type=A&name={}",
-            &self.config.cf_zone_id, name
-        );
+        if res.status().is_success() {
+            res.json::<DnsRecordResponse>().await?;
+            Ok(())
+        } else {
+            Err(Self::decode_error(res, "Failed to update DNS record").await)
+        }
+    }
 
-        let res = client
-            .get(&url)
-            .bearer_auth(&self.config.cf_api_key)
-            .send()
-            .await?;
+    async fn set_proxied(&self, id: &str, proxied: bool) -> Result<DnsRecord, ErrorKind> {
+        let body = if proxied {
+            json!({ "proxied": true, "ttl": DnsRecord::automatic_ttl() })
+        } else {
+            json!({ "proxied": false })
+        };
 
-        if res.status().is_success() {
-            let record = res.json::<Records>().await?;
+        self.patch_record(id, &body).await
+    }
 
-            if record.result.len() < 1 {
-                return Ok(false);
-            }
+    async fn delete_record(&self, id: &str) -> Result<(), ErrorKind> {
+        let client = &self.client;
+        let url = format!("{}/{}", self.records_url(), id);
+
+        let res = self
+            .send(client.delete(&url).bearer_auth(&self.config.cf_api_key))
+            .await;
+        self.cache.invalidate_id(id);
+        let res = res?;
 
-            Ok(true)
+        if res.status().is_success() {
+            res.json::<DeletedRecord>().await?;
+            self.ids.remove_id(id).await?;
+            Ok(())
         } else {
-            Err(ErrorKind::Error("Failed to get DNS record".to_string()))
+            Err(Self::decode_error(res, "Failed to delete DNS record").await)
         }
     }
+
+    fn manages_records(&self) -> bool {
+        true
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache.stats())
+    }
 }
@@ -1 +1,5 @@
+pub mod cache;
 pub mod cloudflare;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1613 @@
+use rocket::serde::json::{json, Value};
+use wiremock::matchers::{
+    body_json, body_partial_json, header, method, path, query_param, query_param_is_missing,
+};
+use wiremock::{Mock, MockBuilder, MockServer, ResponseTemplate};
+
+use crate::cloudflare::cloudflare::Cloudflare;
+use crate::common::errors::ErrorKind;
+use crate::config::Config;
+use crate::models::DnsRecord;
+use crate::provider::provider::{DnsProvider, RecordOptions};
+
+const RECORDS_PATH: &str = "/zones/zone-id/dns_records";
+
+async fn cloudflare(server: &MockServer) -> Cloudflare {
+    cloudflare_with(server, |_| {}).await
+}
+
+/// Like [`cloudflare`], with `edit` applied to the config first.
+async fn cloudflare_with(server: &MockServer, edit: impl FnOnce(&mut Config)) -> Cloudflare {
+    let mut config = Config {
+        cf_api_url: format!("{}/", server.uri()),
+        cf_api_key: "token".to_string(),
+        cf_zone_id: "zone-id".to_string(),
+        dns_suffix: "floy.id".to_string(),
+        ..Default::default()
+    };
+    edit(&mut config);
+
+    Cloudflare::new(config).await
+}
+
+fn record(id: &str, record_type: &str, name: &str, content: &str) -> Value {
+    json!({
+        "id": id,
+        "type": record_type,
+        "name": name,
+        "content": content,
+        "ttl": 1,
+        "proxied": true
+    })
+}
+
+fn envelope(result: Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": result
+    }))
+}
+
+fn failure(status: u16) -> ResponseTemplate {
+    ResponseTemplate::new(status).set_body_json(json!({
+        "success": false,
+        "errors": [{ "code": 1000, "message": "Something went wrong" }],
+        "messages": [],
+        "result": null
+    }))
+}
+
+fn malformed() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_string("{\"result\": [")
+}
+
+fn lookup(record_type: &str, name: &str) -> MockBuilder {
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", record_type))
+        .and(query_param("name", name))
+}
+
+fn exists_lookup(name: &str) -> MockBuilder {
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", name))
+        .and(query_param_is_missing("type"))
+}
+
+async fn expect_no(server: &MockServer, verb: &str) {
+    Mock::given(method(verb))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(server)
+        .await;
+}
+
+fn is_reqwest_error(err: &ErrorKind) -> bool {
+    matches!(err, ErrorKind::ReqwestError(_))
+}
+
+#[tokio::test]
+async fn test_get_subdomain_dns_record_success() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .and(header("Authorization", "Bearer token"))
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert_eq!(
+        ("10.0.0.1".to_string(), "r1".to_string()),
+        cf.get_subdomain_dns_record("alice", "A", false)
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_get_subdomain_dns_record_wildcard() {
+    let server = MockServer::start().await;
+    lookup("AAAA", "*.alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "w1",
+            "AAAA",
+            "*.alice.floy.id",
+            "2001:db8::1"
+        )])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert_eq!(
+        ("2001:db8::1".to_string(), "w1".to_string()),
+        cf.get_subdomain_dns_record("alice", "AAAA", true)
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_get_subdomain_dns_record_empty() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf
+        .get_subdomain_dns_record("alice", "A", false)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err, ErrorKind::NotFound));
+}
+
+#[tokio::test]
+async fn test_get_subdomain_dns_record_non_2xx() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(failure(500))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf
+        .get_subdomain_dns_record("alice", "A", false)
+        .await
+        .unwrap_err();
+
+    assert!(!matches!(err, ErrorKind::NotFound));
+}
+
+#[tokio::test]
+async fn test_get_subdomain_dns_record_malformed() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf
+        .get_subdomain_dns_record("alice", "A", false)
+        .await
+        .unwrap_err();
+
+    assert!(is_reqwest_error(&err));
+}
+
+#[tokio::test]
+async fn test_check_exists_success() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([
+            record("t1", "TXT", "alice.floy.id", "hello"),
+            record("r2", "AAAA", "alice.floy.id", "2001:db8::1")
+        ])))
+        .mount(&server)
+        .await;
+    exists_lookup("bob.floy.id")
+        .respond_with(envelope(json!([record(
+            "t2",
+            "TXT",
+            "bob.floy.id",
+            "hello"
+        )])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.check_exists("alice").await.unwrap());
+    assert!(!cf.check_exists("bob").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_check_exists_empty() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(!cf.check_exists("alice").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_check_exists_non_2xx() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(failure(403))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.check_exists("alice").await.is_err());
+}
+
+#[tokio::test]
+async fn test_check_exists_malformed() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(is_reqwest_error(
+        &cf.check_exists("alice").await.unwrap_err()
+    ));
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_success() {
+    let server = MockServer::start().await;
+    lookup("AAAA", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_partial_json(json!({
+            "type": "AAAA",
+            "name": "alice.floy.id",
+            "content": "2001:db8::1",
+            "proxied": true
+        })))
+        .respond_with(envelope(record(
+            "r1",
+            "AAAA",
+            "alice.floy.id",
+            "2001:db8::1",
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.add_subdomain_dns_record("alice", "2001:db8::1", &RecordOptions::default())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_existing() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(&server)
+        .await;
+    expect_no(&server, "POST").await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_empty_result() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(envelope(Value::Null))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_non_2xx() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(failure(400))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_failed_lookup() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(failure(500))
+        .mount(&server)
+        .await;
+    expect_no(&server, "POST").await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_malformed() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+    expect_no(&server, "POST").await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf
+        .add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .unwrap_err();
+
+    assert!(is_reqwest_error(&err));
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_with_wildcard() {
+    let server = MockServer::start().await;
+    lookup("A", "blog.alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    for (id, name) in [("r1", "blog.alice.floy.id"), ("w1", "*.blog.alice.floy.id")] {
+        Mock::given(method("POST"))
+            .and(path(RECORDS_PATH))
+            .and(body_partial_json(json!({ "type": "A", "name": name })))
+            .respond_with(envelope(record(id, "A", name, "10.0.0.1")))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let cf = cloudflare(&server).await;
+
+    cf.add_subdomain_dns_record(
+        "blog.alice",
+        "10.0.0.1",
+        &RecordOptions {
+            wildcard: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_failed_wildcard_removes_record() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_partial_json(json!({ "name": "alice.floy.id" })))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.1")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_partial_json(json!({ "name": "*.alice.floy.id" })))
+        .respond_with(failure(400))
+        .expect(1)
+        .mount(&server)
+        .await;
+    by_id("DELETE", "r1")
+        .respond_with(envelope(json!({ "id": "r1" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .add_subdomain_dns_record(
+            "alice",
+            "10.0.0.1",
+            &RecordOptions {
+                wildcard: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .is_err());
+}
+
+async fn mount_update_lookups(server: &MockServer) {
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(server)
+        .await;
+    lookup("A", "*.alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "w1",
+            "A",
+            "*.alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn test_update_subdomain_dns_record_success() {
+    let server = MockServer::start().await;
+    mount_update_lookups(&server).await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{}/r1", RECORDS_PATH)))
+        .and(body_partial_json(json!({ "content": "10.0.0.2" })))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.2")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{}/w1", RECORDS_PATH)))
+        .and(body_partial_json(json!({ "content": "10.0.0.2" })))
+        .respond_with(envelope(record("w1", "A", "*.alice.floy.id", "10.0.0.2")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_update_subdomain_dns_record_empty() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    expect_no(&server, "PATCH").await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_update_subdomain_dns_record_non_2xx() {
+    let server = MockServer::start().await;
+    mount_update_lookups(&server).await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{}/r1", RECORDS_PATH)))
+        .respond_with(failure(400))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf
+        .update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_update_subdomain_dns_record_malformed() {
+    let server = MockServer::start().await;
+    mount_update_lookups(&server).await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{}/r1", RECORDS_PATH)))
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf
+        .update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .unwrap_err();
+
+    assert!(is_reqwest_error(&err));
+}
+
+#[tokio::test]
+async fn test_update_subdomain_dns_record_without_wildcard() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(&server)
+        .await;
+    lookup("A", "*.alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{}/r1", RECORDS_PATH)))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.2")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .unwrap();
+}
+
+async fn mount_delete_lookups(server: &MockServer) {
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(server)
+        .await;
+    mount_update_lookups(server).await;
+    lookup("AAAA", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn test_delete_subdomain_dns_record_success() {
+    let server = MockServer::start().await;
+    mount_delete_lookups(&server).await;
+    for id in ["r1", "w1"] {
+        Mock::given(method("DELETE"))
+            .and(path(format!("{}/{}", RECORDS_PATH, id)))
+            .respond_with(envelope(json!({ "id": id })))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let cf = cloudflare(&server).await;
+
+    cf.delete_subdomain_dns_record("alice").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_delete_subdomain_dns_record_empty() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    expect_no(&server, "DELETE").await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.delete_subdomain_dns_record("alice").await.is_err());
+}
+
+#[tokio::test]
+async fn test_delete_subdomain_dns_record_non_2xx() {
+    let server = MockServer::start().await;
+    mount_delete_lookups(&server).await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("{}/r1", RECORDS_PATH)))
+        .respond_with(failure(500))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.delete_subdomain_dns_record("alice").await.is_err());
+}
+
+#[tokio::test]
+async fn test_delete_subdomain_dns_record_malformed() {
+    let server = MockServer::start().await;
+    mount_delete_lookups(&server).await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("{}/r1", RECORDS_PATH)))
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf.delete_subdomain_dns_record("alice").await.unwrap_err();
+
+    assert!(is_reqwest_error(&err));
+}
+
+#[tokio::test]
+async fn test_delete_subdomain_dns_record_without_wildcard() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(&server)
+        .await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .mount(&server)
+        .await;
+    for (record_type, name) in [
+        ("A", "*.alice.floy.id"),
+        ("AAAA", "alice.floy.id"),
+        ("AAAA", "*.alice.floy.id"),
+    ] {
+        lookup(record_type, name)
+            .respond_with(envelope(json!([])))
+            .mount(&server)
+            .await;
+    }
+    by_id("DELETE", "r1")
+        .respond_with(envelope(json!({ "id": "r1" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.delete_subdomain_dns_record("alice").await.unwrap();
+}
+
+fn list_lookup() -> MockBuilder {
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name.endswith", "alice.floy.id"))
+}
+
+#[tokio::test]
+async fn test_list_subdomain_records_success() {
+    let server = MockServer::start().await;
+    list_lookup()
+        .respond_with(envelope(json!([
+            record("r1", "A", "alice.floy.id", "10.0.0.1"),
+            record("t1", "TXT", "_verify.alice.floy.id", "token"),
+            record("m1", "A", "malice.floy.id", "10.0.0.9")
+        ])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let records = cf.list_subdomain_records("alice").await.unwrap();
+    let ids: Vec<Option<String>> = records.into_iter().map(|r| r.id).collect();
+
+    assert_eq!(vec![Some("r1".to_string()), Some("t1".to_string())], ids);
+}
+
+#[tokio::test]
+async fn test_list_subdomain_records_empty() {
+    let server = MockServer::start().await;
+    list_lookup()
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.list_subdomain_records("alice").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_list_subdomain_records_non_2xx() {
+    let server = MockServer::start().await;
+    list_lookup()
+        .respond_with(failure(500))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.list_subdomain_records("alice").await.is_err());
+}
+
+#[tokio::test]
+async fn test_list_subdomain_records_malformed() {
+    let server = MockServer::start().await;
+    list_lookup().respond_with(malformed()).mount(&server).await;
+
+    let cf = cloudflare(&server).await;
+    let err = cf.list_subdomain_records("alice").await.unwrap_err();
+
+    assert!(is_reqwest_error(&err));
+}
+
+fn by_id(verb: &str, id: &str) -> MockBuilder {
+    Mock::given(method(verb)).and(path(format!("{}/{}", RECORDS_PATH, id)))
+}
+
+#[tokio::test]
+async fn test_get_record_success() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(envelope(record("t1", "TXT", "alice.floy.id", "hello")))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let record = cf.get_record("t1").await.unwrap();
+
+    assert_eq!("TXT", record.record_type);
+    assert_eq!("hello", record.content);
+}
+
+#[tokio::test]
+async fn test_get_record_empty() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(envelope(Value::Null))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.get_record("t1").await.is_err());
+}
+
+#[tokio::test]
+async fn test_get_record_non_2xx() {
+    let server = MockServer::start().await;
+    by_id("GET", "missing")
+        .respond_with(failure(404))
+        .mount(&server)
+        .await;
+    by_id("GET", "t1")
+        .respond_with(failure(500))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.get_record("missing").await.unwrap_err(),
+        ErrorKind::NotFound
+    ));
+    assert!(!matches!(
+        cf.get_record("t1").await.unwrap_err(),
+        ErrorKind::NotFound
+    ));
+}
+
+#[tokio::test]
+async fn test_get_record_malformed() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(is_reqwest_error(&cf.get_record("t1").await.unwrap_err()));
+}
+
+fn txt_record() -> DnsRecord {
+    DnsRecord::new(
+        "TXT".to_string(),
+        "alice.floy.id".to_string(),
+        1,
+        "hello".to_string(),
+        false,
+    )
+}
+
+#[tokio::test]
+async fn test_create_record_success() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_partial_json(
+            json!({ "type": "TXT", "content": "hello", "proxied": false }),
+        ))
+        .respond_with(envelope(record("t1", "TXT", "alice.floy.id", "hello")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert_eq!("t1", cf.create_record(&txt_record()).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_create_record_empty() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(envelope(Value::Null))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.create_record(&txt_record()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_create_record_non_2xx() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(failure(400))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.create_record(&txt_record()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_create_record_malformed() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(is_reqwest_error(
+        &cf.create_record(&txt_record()).await.unwrap_err()
+    ));
+}
+
+#[tokio::test]
+async fn test_update_record_success() {
+    let server = MockServer::start().await;
+    by_id("PUT", "t1")
+        .and(body_partial_json(
+            json!({ "type": "TXT", "content": "hello" }),
+        ))
+        .respond_with(envelope(record("t1", "TXT", "alice.floy.id", "hello")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.update_record("t1", &txt_record()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_update_record_empty() {
+    let server = MockServer::start().await;
+    by_id("PUT", "t1")
+        .respond_with(envelope(Value::Null))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.update_record("t1", &txt_record()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_update_record_non_2xx() {
+    let server = MockServer::start().await;
+    by_id("PUT", "t1")
+        .respond_with(failure(400))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.update_record("t1", &txt_record()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_update_record_malformed() {
+    let server = MockServer::start().await;
+    by_id("PUT", "t1")
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(is_reqwest_error(
+        &cf.update_record("t1", &txt_record()).await.unwrap_err()
+    ));
+}
+
+#[tokio::test]
+async fn test_delete_record_success() {
+    let server = MockServer::start().await;
+    by_id("DELETE", "t1")
+        .respond_with(envelope(json!({ "id": "t1" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.delete_record("t1").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_delete_record_empty() {
+    let server = MockServer::start().await;
+    by_id("DELETE", "t1")
+        .respond_with(envelope(Value::Null))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.delete_record("t1").await.is_err());
+}
+
+#[tokio::test]
+async fn test_delete_record_non_2xx() {
+    let server = MockServer::start().await;
+    by_id("DELETE", "t1")
+        .respond_with(failure(404))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.delete_record("t1").await.is_err());
+}
+
+#[tokio::test]
+async fn test_delete_record_malformed() {
+    let server = MockServer::start().await;
+    by_id("DELETE", "t1")
+        .respond_with(malformed())
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(is_reqwest_error(&cf.delete_record("t1").await.unwrap_err()));
+}
+
+fn page(records: Value, page: u32, total_pages: u32) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": records,
+        "result_info": {
+            "page": page,
+            "per_page": 2,
+            "count": 2,
+            "total_count": 3,
+            "total_pages": total_pages
+        }
+    }))
+}
+
+#[tokio::test]
+async fn test_list_records_walks_every_page() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("page", "1"))
+        .respond_with(page(
+            json!([
+                record("r1", "A", "alice.floy.id", "10.0.0.1"),
+                record("r2", "A", "bob.floy.id", "10.0.0.1")
+            ]),
+            1,
+            2,
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("page", "2"))
+        .respond_with(page(
+            json!([{
+                "id": "t1",
+                "type": "TXT",
+                "name": "alice.floy.id",
+                "content": "hello",
+                "ttl": 300,
+                "proxied": false,
+                "comment": "verification"
+            }]),
+            2,
+            2,
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let records = cf.list_records(None, None).await.unwrap();
+
+    assert_eq!(3, records.len());
+    assert_eq!(Some("verification".to_string()), records[2].comment);
+    assert_eq!(300, records[2].ttl);
+}
+
+#[tokio::test]
+async fn test_list_records_filters() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "TXT"))
+        .and(query_param("name.endswith", "alice.floy.id"))
+        .respond_with(page(
+            json!([record("t1", "TXT", "alice.floy.id", "hello")]),
+            1,
+            1,
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+    let records = cf
+        .list_records(Some("txt"), Some("alice.floy.id"))
+        .await
+        .unwrap();
+
+    assert_eq!(1, records.len());
+}
+
+#[tokio::test]
+async fn test_list_records_non_2xx_on_later_page() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("page", "1"))
+        .respond_with(page(
+            json!([record("r1", "A", "alice.floy.id", "10.0.0.1")]),
+            1,
+            2,
+        ))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("page", "2"))
+        .respond_with(failure(500))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.list_records(None, None).await.is_err());
+}
+
+async fn retrying_cloudflare(server: &MockServer, retries: u32, concurrency: usize) -> Cloudflare {
+    Cloudflare::new(Config {
+        cf_api_url: server.uri(),
+        cf_api_key: "token".to_string(),
+        cf_zone_id: "zone-id".to_string(),
+        dns_suffix: "floy.id".to_string(),
+        cf_max_retries: retries,
+        cf_retry_base_delay_ms: 1,
+        cf_max_concurrent_requests: concurrency,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_retries_idempotent_request_on_5xx() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(failure(502))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+    by_id("GET", "t1")
+        .respond_with(envelope(record("t1", "TXT", "alice.floy.id", "hello")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = retrying_cloudflare(&server, 3, 4).await;
+
+    assert_eq!("hello", cf.get_record("t1").await.unwrap().content);
+}
+
+#[tokio::test]
+async fn test_gives_up_after_max_retries() {
+    let server = MockServer::start().await;
+    by_id("DELETE", "t1")
+        .respond_with(failure(503))
+        .expect(3)
+        .mount(&server)
+        .await;
+
+    let cf = retrying_cloudflare(&server, 2, 4).await;
+
+    assert!(cf.delete_record("t1").await.is_err());
+}
+
+#[tokio::test]
+async fn test_does_not_retry_post_on_5xx() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(failure(500))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = retrying_cloudflare(&server, 3, 4).await;
+
+    assert!(cf.create_record(&txt_record()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_retries_post_on_429_honouring_retry_after() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(failure(429).insert_header("Retry-After", "1"))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(envelope(record("t1", "TXT", "alice.floy.id", "hello")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = retrying_cloudflare(&server, 3, 4).await;
+    let started = std::time::Instant::now();
+
+    assert_eq!("t1", cf.create_record(&txt_record()).await.unwrap());
+    assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn test_caps_concurrent_requests() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(
+            envelope(record("t1", "TXT", "alice.floy.id", "hello"))
+                .set_delay(std::time::Duration::from_millis(200)),
+        )
+        .mount(&server)
+        .await;
+
+    let cf = retrying_cloudflare(&server, 0, 1).await;
+    let started = std::time::Instant::now();
+
+    let (a, b, c) = tokio::join!(
+        cf.get_record("t1"),
+        cf.get_record("t1"),
+        cf.get_record("t1")
+    );
+
+    assert!(a.is_ok() && b.is_ok() && c.is_ok());
+    assert!(started.elapsed() >= std::time::Duration::from_millis(600));
+}
+
+fn cf_error(status: u16, code: u32, message: &str) -> ResponseTemplate {
+    ResponseTemplate::new(status).set_body_json(json!({
+        "success": false,
+        "errors": [{ "code": code, "message": message }],
+        "messages": [],
+        "result": null
+    }))
+}
+
+#[tokio::test]
+async fn test_decodes_auth_failure() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(cf_error(403, 10000, "Authentication error"))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.get_record("t1").await.unwrap_err(),
+        ErrorKind::ProviderAuthFailed(m) if m == "Authentication error"
+    ));
+}
+
+#[tokio::test]
+async fn test_decodes_record_already_exists() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(cf_error(400, 81057, "Record already exists."))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.create_record(&txt_record()).await.unwrap_err(),
+        ErrorKind::RecordAlreadyExists(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_decodes_zone_not_found() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(cf_error(400, 7003, "Could not route to /zones/zone-id"))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.list_records(None, None).await.unwrap_err(),
+        ErrorKind::ZoneNotFound(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_decodes_rate_limit() {
+    let server = MockServer::start().await;
+    by_id("DELETE", "t1")
+        .respond_with(cf_error(429, 971, "Please wait").insert_header("Retry-After", "7"))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.delete_record("t1").await.unwrap_err(),
+        ErrorKind::RateLimited(Some(7))
+    ));
+}
+
+#[tokio::test]
+async fn test_decodes_validation_error() {
+    let server = MockServer::start().await;
+    by_id("PUT", "t1")
+        .respond_with(cf_error(400, 9005, "Content for A record is invalid."))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.update_record("t1", &txt_record()).await.unwrap_err(),
+        ErrorKind::ValidationError(m) if m == "Content for A record is invalid."
+    ));
+}
+
+#[tokio::test]
+async fn test_unknown_error_code_keeps_message() {
+    let server = MockServer::start().await;
+    by_id("GET", "t1")
+        .respond_with(failure(500))
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(matches!(
+        cf.get_record("t1").await.unwrap_err(),
+        ErrorKind::Error(m) if m == "Failed to get DNS record: Something went wrong"
+    ));
+}
+
+#[tokio::test]
+async fn test_set_proxied_forces_automatic_ttl() {
+    let server = MockServer::start().await;
+    by_id("PATCH", "r1")
+        .and(body_partial_json(json!({ "proxied": true, "ttl": 1 })))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.1")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(cf.set_proxied("r1", true).await.unwrap().proxied);
+}
+
+#[tokio::test]
+async fn test_set_proxied_off_keeps_ttl() {
+    let server = MockServer::start().await;
+    by_id("PATCH", "r1")
+        .and(body_json(json!({ "proxied": false })))
+        .respond_with(envelope(json!({
+            "id": "r1",
+            "type": "A",
+            "name": "alice.floy.id",
+            "content": "10.0.0.1",
+            "ttl": 1,
+            "proxied": false
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    assert!(!cf.set_proxied("r1", false).await.unwrap().proxied);
+}
+
+#[tokio::test]
+async fn test_add_subdomain_dns_record_dns_only() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_partial_json(json!({ "ttl": 300, "proxied": false })))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.1")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare(&server).await;
+
+    cf.add_subdomain_dns_record(
+        "alice",
+        "10.0.0.1",
+        &RecordOptions {
+            ttl: 300,
+            proxied: false,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_cache_saves_lookups_on_create() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.1")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare_with(&server, |config| config.cf_cache_ttl = 60).await;
+
+    assert!(!cf.check_exists("alice").await.unwrap());
+    cf.add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .unwrap();
+
+    let stats = cf.cache_stats().unwrap();
+    assert_eq!(1, stats.hits);
+    assert_eq!(1, stats.misses);
+}
+
+#[tokio::test]
+async fn test_check_exists_counts_one_lookup() {
+    let server = MockServer::start().await;
+    exists_lookup("alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare_with(&server, |config| config.cf_cache_ttl = 60).await;
+
+    for _ in 0..3 {
+        assert!(cf.check_exists("alice").await.unwrap());
+    }
+
+    let stats = cf.cache_stats().unwrap();
+    assert_eq!(2, stats.hits);
+    assert_eq!(1, stats.misses);
+}
+
+#[tokio::test]
+async fn test_cache_invalidated_by_writes() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([record(
+            "r1",
+            "A",
+            "alice.floy.id",
+            "10.0.0.1"
+        )])))
+        .expect(2)
+        .mount(&server)
+        .await;
+    by_id("DELETE", "r1")
+        .respond_with(envelope(json!({ "id": "r1" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let cf = cloudflare_with(&server, |config| config.cf_cache_ttl = 60).await;
+
+    cf.get_subdomain_dns_record("alice", "A", false)
+        .await
+        .unwrap();
+    cf.get_subdomain_dns_record("alice", "A", false)
+        .await
+        .unwrap();
+    cf.delete_record("r1").await.unwrap();
+    cf.get_subdomain_dns_record("alice", "A", false)
+        .await
+        .unwrap();
+
+    assert_eq!(1, cf.cache_stats().unwrap().hits);
+}
+
+/// A fresh record id store for the test `name`.
+fn ids_path(name: &str) -> std::path::PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("floy-cf-ids-{}-{}.json", name, std::process::id()));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+#[tokio::test]
+async fn test_stored_ids_skip_lookups() {
+    let server = MockServer::start().await;
+    lookup("A", "alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(envelope(record("r1", "A", "alice.floy.id", "10.0.0.1")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // The record was renamed behind our back, only its id still finds it.
+    by_id("PATCH", "r1")
+        .and(body_json(json!({ "content": "10.0.0.2" })))
+        .respond_with(envelope(record("r1", "A", "renamed.floy.id", "10.0.0.2")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    for (record_type, name) in [("A", "*.alice.floy.id"), ("AAAA", "alice.floy.id")] {
+        lookup(record_type, name)
+            .respond_with(envelope(json!([])))
+            .mount(&server)
+            .await;
+    }
+    lookup("AAAA", "*.alice.floy.id")
+        .respond_with(envelope(json!([])))
+        .mount(&server)
+        .await;
+    by_id("DELETE", "r1")
+        .respond_with(envelope(json!({ "id": "r1" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let path = ids_path("skip");
+    let cf = cloudflare_with(&server, |config| {
+        config.record_ids_path = path.to_string_lossy().to_string()
+    })
+    .await;
+
+    cf.add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+        .await
+        .unwrap();
+    cf.update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .unwrap();
+    cf.delete_subdomain_dns_record("alice").await.unwrap();
+
+    assert_eq!("{}", std::fs::read_to_string(&path).unwrap().trim());
+    std::fs::remove_file(path).ok();
+}
+
+#[tokio::test]
+async fn test_stale_stored_id_falls_back_to_lookup() {
+    let server = MockServer::start().await;
+    by_id("PATCH", "gone")
+        .respond_with(failure(404))
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_update_lookups(&server).await;
+    for id in ["r1", "w1"] {
+        by_id("PATCH", id)
+            .respond_with(envelope(record(id, "A", "alice.floy.id", "10.0.0.2")))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let path = ids_path("stale");
+    let cf = cloudflare_with(&server, |config| {
+        config.record_ids_path = path.to_string_lossy().to_string()
+    })
+    .await;
+    std::fs::write(&path, r#"{"alice.floy.id/A": "gone"}"#).unwrap();
+
+    cf.update_subdomain_dns_record("alice", "10.0.0.2")
+        .await
+        .unwrap();
+
+    let stored = std::fs::read_to_string(&path).unwrap();
+    assert!(stored.contains(r#""alice.floy.id/A":"r1""#));
+    std::fs::remove_file(path).ok();
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::PathBuf;
+
+use bcrypt::verify;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::common::utils::hash_password;
+
+const SECRET_LENGTH: usize = 32;
+
+/// Dynamic DNS secrets, one per fully qualified hostname, stored
+/// bcrypt-hashed as `<hostname>:<hash>\r` entries in the same style as the
+/// user database.
+pub struct CredentialStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl CredentialStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CredentialStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn read(&self) -> Result<BTreeMap<String, String>, std::io::Error> {
+        let content = match fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == IoErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(content
+            .split('\r')
+            .filter_map(|line| line.trim().split_once(':'))
+            .filter(|(subdomain, _)| !subdomain.is_empty())
+            .map(|(subdomain, hash)| (subdomain.to_string(), hash.to_string()))
+            .collect())
+    }
+
+    async fn write(&self, entries: &BTreeMap<String, String>) -> Result<(), std::io::Error> {
+        let content: String = entries
+            .iter()
+            .map(|(subdomain, hash)| format!("{}:{}\r", subdomain, hash))
+            .collect();
+
+        fs::write(&self.path, content).await
+    }
+
+    /// Generates a new secret for `subdomain`, replacing any previous one,
+    /// and returns it. Only the hash is kept.
+    pub async fn issue(&self, subdomain: &str) -> Result<String, std::io::Error> {
+        let secret: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SECRET_LENGTH)
+            .map(char::from)
+            .collect();
+
+        let _guard = self.lock.lock().await;
+        let mut entries = self.read().await?;
+        entries.insert(subdomain.to_string(), hash_password(&secret));
+        self.write(&entries).await?;
+
+        Ok(secret)
+    }
+
+    /// Forgets the secret of `subdomain`. Returns whether there was one.
+    pub async fn revoke(&self, subdomain: &str) -> Result<bool, std::io::Error> {
+        let _guard = self.lock.lock().await;
+        let mut entries = self.read().await?;
+        let removed = entries.remove(subdomain).is_some();
+        if removed {
+            self.write(&entries).await?;
+        }
+
+        Ok(removed)
+    }
+
+    pub async fn verify(&self, subdomain: &str, secret: &str) -> Result<bool, std::io::Error> {
+        let hash = {
+            let _guard = self.lock.lock().await;
+            self.read().await?.remove(subdomain)
+        };
+
+        Ok(hash.is_some_and(|hash| verify(secret, &hash).unwrap_or(false)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_issue_verify_revoke() {
+        let path = std::env::temp_dir().join(format!("floy-ddns-{}.txt", std::process::id()));
+        let store = CredentialStore::new(&path);
+
+        assert!(!store.verify("alice", "anything").await.unwrap());
+
+        let secret = store.issue("alice").await.unwrap();
+        assert_eq!(SECRET_LENGTH, secret.len());
+        assert!(store.verify("alice", &secret).await.unwrap());
+        assert!(!store.verify("alice", "wrong").await.unwrap());
+        assert!(!store.verify("bob", &secret).await.unwrap());
+
+        let rotated = store.issue("alice").await.unwrap();
+        assert!(!store.verify("alice", &secret).await.unwrap());
+        assert!(store.verify("alice", &rotated).await.unwrap());
+
+        assert!(store.revoke("alice").await.unwrap());
+        assert!(!store.revoke("alice").await.unwrap());
+        assert!(!store.verify("alice", &rotated).await.unwrap());
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -2,7 +2,9 @@ use std::fmt;
 
 use lettre;
 use reqwest;
-use rocket::response::Responder;
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::Request;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 
@@ -30,6 +32,13 @@ pub enum ErrorKind {
     ReqwestError(reqwest::Error),
     LettreTransportError(lettre::transport::smtp::Error),
     LettreError(lettre::error::Error),
+    Forbidden,
+    ProviderAuthFailed(String),
+    RecordAlreadyExists(String),
+    ZoneNotFound(String),
+    RateLimited(Option<u64>),
+    ValidationError(String),
+    NotImplemented(String),
 }
 
 impl From<std::io::Error> for ErrorKind {
@@ -68,6 +77,12 @@ impl From<lettre::error::Error> for ErrorKind {
     }
 }
 
+impl From<openssl::error::ErrorStack> for ErrorKind {
+    fn from(error: openssl::error::ErrorStack) -> Self {
+        ErrorKind::Error(format!("Crypto error: {}", error))
+    }
+}
+
 impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let msg = match self {
@@ -84,12 +99,75 @@ impl fmt::Display for ErrorKind {
             ErrorKind::ReqwestError(err) => err.to_string(),
             ErrorKind::LettreTransportError(err) => err.to_string(),
             ErrorKind::LettreError(err) => err.to_string(),
+            ErrorKind::Forbidden => "Forbidden".to_string(),
+            ErrorKind::ProviderAuthFailed(err) => {
+                format!("DNS provider rejected the configured credentials: {}", err)
+            }
+            ErrorKind::RecordAlreadyExists(err) => format!("Record already exists: {}", err),
+            ErrorKind::ZoneNotFound(err) => format!("DNS zone not found: {}", err),
+            ErrorKind::RateLimited(Some(secs)) => {
+                format!("Rate limited by DNS provider, retry in {}s", secs)
+            }
+            ErrorKind::RateLimited(None) => "Rate limited by DNS provider".to_string(),
+            ErrorKind::ValidationError(err) => format!("Validation error: {}", err),
+            ErrorKind::NotImplemented(err) => err.to_string(),
         };
 
         write!(f, "{}", msg)
     }
 }
 
+impl ErrorKind {
+    pub fn status(&self) -> Status {
+        match self {
+            ErrorKind::JWTError(_) | ErrorKind::JWTCreationError(_) => Status::Unauthorized,
+            ErrorKind::InvalidValue | ErrorKind::ValidationError(_) => Status::BadRequest,
+            ErrorKind::Forbidden => Status::Forbidden,
+            ErrorKind::NotFound => Status::NotFound,
+            ErrorKind::EmailAlreadyExists
+            | ErrorKind::UsernameAlreadyExists
+            | ErrorKind::RecordAlreadyExists(_) => Status::Conflict,
+            ErrorKind::RateLimited(_) => Status::TooManyRequests,
+            ErrorKind::NotImplemented(_) => Status::NotImplemented,
+            ErrorKind::ReqwestError(_)
+            | ErrorKind::ProviderAuthFailed(_)
+            | ErrorKind::ZoneNotFound(_) => Status::BadGateway,
+            ErrorKind::IOError(_)
+            | ErrorKind::Error(_)
+            | ErrorKind::LettreTransportError(_)
+            | ErrorKind::LettreError(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorKind {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+
+        let message = if status == Status::InternalServerError {
+            error!("{}", self);
+            "Internal server error".to_string()
+        } else {
+            self.to_string()
+        };
+
+        let mut response = (
+            status,
+            Json(Catcher {
+                status: status.code,
+                message,
+            }),
+        )
+            .respond_to(request)?;
+
+        if let ErrorKind::RateLimited(Some(secs)) = self {
+            response.set_raw_header("Retry-After", secs.to_string());
+        }
+
+        Ok(response)
+    }
+}
+
 #[catch(400)]
 fn bad_request() -> Json<Catcher> {
     Catcher {
@@ -108,6 +186,15 @@ fn unauthorized() -> Json<Catcher> {
     .into()
 }
 
+#[catch(403)]
+fn forbidden() -> Json<Catcher> {
+    Catcher {
+        status: 403,
+        message: "Forbidden".to_string(),
+    }
+    .into()
+}
+
 #[catch(404)]
 fn not_found() -> Json<Catcher> {
     Catcher {
@@ -142,6 +229,7 @@ pub async fn build_catchers() -> rocket::fairing::AdHoc {
             catchers![
                 bad_request,
                 unauthorized,
+                forbidden,
                 not_found,
                 conflict,
                 internal_server_error
@@ -18,6 +18,10 @@ pub struct Claims {
 #[derive(Clone)]
 pub struct ApiKey(pub String);
 
+/// An [`ApiKey`] whose subject is listed in `Config::admin_emails`.
+#[derive(Clone)]
+pub struct AdminKey(pub String);
+
 pub fn generate_token(config: &Config, key: &String) -> Result<String, ErrorKind> {
     let dt = Local::now();
 
@@ -75,3 +79,24 @@ impl<'r> FromRequest<'r> for ApiKey {
         }
     }
 }
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminKey {
+    type Error = ErrorKind;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<AdminKey, ErrorKind> {
+        let key = match request.guard::<ApiKey>().await {
+            request::Outcome::Success(key) => key,
+            request::Outcome::Error(e) => return request::Outcome::Error(e),
+            request::Outcome::Forward(f) => return request::Outcome::Forward(f),
+        };
+
+        let config = request.guard::<&rocket::State<Config>>().await.unwrap();
+
+        if config.admin_emails.contains(&key.0) {
+            request::Outcome::Success(AdminKey(key.0))
+        } else {
+            request::Outcome::Error((Status::Forbidden, ErrorKind::InvalidValue))
+        }
+    }
+}
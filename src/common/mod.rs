@@ -1,4 +1,8 @@
+pub mod credentials;
 pub mod errors;
 pub mod jwt;
+pub mod record_ids;
+pub mod records;
+pub mod resolver;
 pub mod utils;
 pub mod writers;
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::PathBuf;
+
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Every zone's provider has its own store but they may share one file.
+static FILE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Provider ids of the records floy-dns created, keyed by record name and
+/// type and kept as a JSON object on disk. With no path nothing is stored
+/// and every lookup misses.
+pub struct RecordIdStore {
+    path: Option<PathBuf>,
+}
+
+fn key(name: &str, record_type: &str) -> String {
+    format!("{}/{}", name, record_type)
+}
+
+impl RecordIdStore {
+    pub fn new(path: &str) -> Self {
+        RecordIdStore {
+            path: Some(PathBuf::from(path)).filter(|_| !path.is_empty()),
+        }
+    }
+
+    async fn read(&self, path: &PathBuf) -> Result<BTreeMap<String, String>, std::io::Error> {
+        match fs::read_to_string(path).await {
+            Ok(content) => rocket::serde::json::from_str(&content)
+                .map_err(|e| std::io::Error::new(IoErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == IoErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Applies `edit` to the stored ids and writes them back if it changed
+    /// anything.
+    async fn update(
+        &self,
+        edit: impl FnOnce(&mut BTreeMap<String, String>) -> bool,
+    ) -> Result<(), std::io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let _guard = FILE_LOCK.lock().await;
+        let mut ids = self.read(path).await?;
+        if edit(&mut ids) {
+            let content = rocket::serde::json::to_string(&ids)
+                .map_err(|e| std::io::Error::new(IoErrorKind::InvalidData, e))?;
+            fs::write(path, content).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get(
+        &self,
+        name: &str,
+        record_type: &str,
+    ) -> Result<Option<String>, std::io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let _guard = FILE_LOCK.lock().await;
+        Ok(self.read(path).await?.remove(&key(name, record_type)))
+    }
+
+    pub async fn set(&self, name: &str, record_type: &str, id: &str) -> Result<(), std::io::Error> {
+        self.update(|ids| {
+            ids.insert(key(name, record_type), id.to_string())
+                .as_deref()
+                != Some(id)
+        })
+        .await
+    }
+
+    pub async fn remove(&self, name: &str, record_type: &str) -> Result<(), std::io::Error> {
+        self.update(|ids| ids.remove(&key(name, record_type)).is_some())
+            .await
+    }
+
+    /// Forgets the record with `id` under whatever name it was stored.
+    pub async fn remove_id(&self, id: &str) -> Result<(), std::io::Error> {
+        self.update(|ids| {
+            let before = ids.len();
+            ids.retain(|_, stored| stored != id);
+            ids.len() != before
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set_get_remove() {
+        let path = std::env::temp_dir().join(format!("floy-ids-{}.json", std::process::id()));
+        let store = RecordIdStore::new(path.to_str().unwrap());
+
+        assert_eq!(None, store.get("alice.floy.id", "A").await.unwrap());
+
+        store.set("alice.floy.id", "A", "r1").await.unwrap();
+        store.set("alice.floy.id", "AAAA", "r2").await.unwrap();
+        assert_eq!(
+            Some("r1".to_string()),
+            store.get("alice.floy.id", "A").await.unwrap()
+        );
+
+        store.remove_id("r1").await.unwrap();
+        assert_eq!(None, store.get("alice.floy.id", "A").await.unwrap());
+
+        store.remove("alice.floy.id", "AAAA").await.unwrap();
+        assert_eq!(None, store.get("alice.floy.id", "AAAA").await.unwrap());
+
+        let disabled = RecordIdStore::new("");
+        disabled.set("alice.floy.id", "A", "r1").await.unwrap();
+        assert_eq!(None, disabled.get("alice.floy.id", "A").await.unwrap());
+
+        std::fs::remove_file(path).ok();
+    }
+}
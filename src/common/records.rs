@@ -0,0 +1,248 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use rocket::serde::json::Value;
+
+use crate::common::errors::ErrorKind;
+use crate::models::DnsRecord;
+
+/// Record types users may manage under their own subdomain.
+pub const MANAGED_RECORD_TYPES: [&str; 7] = ["A", "AAAA", "CNAME", "TXT", "MX", "SRV", "CAA"];
+
+/// Record types Cloudflare can proxy.
+pub const PROXIABLE_RECORD_TYPES: [&str; 3] = ["A", "AAAA", "CNAME"];
+
+const MIN_TTL: u32 = 60;
+const MAX_TTL: u32 = 86400;
+const MAX_TXT_LENGTH: usize = 2048;
+const CAA_TAGS: [&str; 3] = ["issue", "issuewild", "iodef"];
+
+pub fn validate_hostname(s: &str) -> bool {
+    let s = s.strip_suffix('.').unwrap_or(s);
+
+    !s.is_empty()
+        && s.len() <= 253
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Resolves a user supplied record name against `<subdomain>.<suffix>`.
+///
+/// `@` and the empty string stand for the subdomain itself, names ending in
+/// the suffix are taken as absolute and anything else is relative to the
+/// subdomain. Absolute names outside the subdomain are rejected.
+pub fn qualify_record_name(name: &str, subdomain: &str, suffix: &str) -> Result<String, ErrorKind> {
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    let base = format!("{}.{}", subdomain, suffix);
+
+    let fqdn = if name.is_empty() || name == "@" {
+        base.clone()
+    } else if name == suffix || name.ends_with(&format!(".{}", suffix)) {
+        name
+    } else {
+        format!("{}.{}", name, base)
+    };
+
+    if fqdn != base && !fqdn.ends_with(&format!(".{}", base)) {
+        return Err(ErrorKind::ValidationError(format!(
+            "Record name {} is outside of {}",
+            fqdn, base
+        )));
+    }
+
+    if !validate_hostname(fqdn.trim_start_matches("*.")) {
+        return Err(ErrorKind::InvalidValue);
+    }
+
+    Ok(fqdn)
+}
+
+fn data_u64(data: &Value, key: &str, max: u64) -> Result<u64, ErrorKind> {
+    data.get(key)
+        .and_then(Value::as_u64)
+        .filter(|v| *v <= max)
+        .ok_or(ErrorKind::ValidationError(format!(
+            "Invalid or missing data.{}",
+            key
+        )))
+}
+
+fn data_str<'a>(data: &'a Value, key: &str) -> Result<&'a str, ErrorKind> {
+    data.get(key)
+        .and_then(Value::as_str)
+        .filter(|v| !v.is_empty())
+        .ok_or(ErrorKind::ValidationError(format!(
+            "Invalid or missing data.{}",
+            key
+        )))
+}
+
+/// Applies Cloudflare's rules for TTL and proxying: the TTL is either `1`
+/// (automatic) or between 60 and 86400 seconds, only address and CNAME
+/// records can be proxied, and proxied records always use automatic TTL.
+pub fn validate_ttl_and_proxied(
+    record_type: &str,
+    ttl: u32,
+    proxied: bool,
+) -> Result<(), ErrorKind> {
+    if ttl != DnsRecord::automatic_ttl() && !(MIN_TTL..=MAX_TTL).contains(&ttl) {
+        return Err(ErrorKind::ValidationError(format!(
+            "TTL must be 1 (automatic) or between {} and {} seconds",
+            MIN_TTL, MAX_TTL
+        )));
+    }
+
+    if proxied && !PROXIABLE_RECORD_TYPES.contains(&record_type) {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} records cannot be proxied",
+            record_type
+        )));
+    }
+
+    if proxied && ttl != DnsRecord::automatic_ttl() {
+        return Err(ErrorKind::ValidationError(
+            "Proxied records always use automatic TTL".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the record's content (or `data` for SRV/CAA) is well formed
+/// for its type.
+pub fn validate_record(record: &DnsRecord) -> Result<(), ErrorKind> {
+    let invalid = |msg: &str| Err(ErrorKind::ValidationError(msg.to_string()));
+    let content = record.content.as_str();
+
+    validate_ttl_and_proxied(&record.record_type, record.ttl, record.proxied)?;
+
+    match record.record_type.as_str() {
+        "A" if content.parse::<Ipv4Addr>().is_err() => invalid("A content must be an IPv4 address"),
+        "AAAA" if content.parse::<Ipv6Addr>().is_err() => {
+            invalid("AAAA content must be an IPv6 address")
+        }
+        "CNAME" if !validate_hostname(content) => invalid("CNAME content must be a hostname"),
+        "TXT" if content.is_empty() || content.len() > MAX_TXT_LENGTH => {
+            invalid("TXT content must be between 1 and 2048 characters")
+        }
+        "MX" if !validate_hostname(content) => invalid("MX content must be a hostname"),
+        "MX" if record.priority.is_none() => invalid("MX records require a priority"),
+        "SRV" => {
+            let labels: Vec<&str> = record.name.split('.').collect();
+            if labels.len() < 3 || !labels[0].starts_with('_') || !labels[1].starts_with('_') {
+                return invalid("SRV names must start with _service._proto");
+            }
+            let data = match &record.data {
+                Some(data) => data,
+                None => return invalid("SRV records require data"),
+            };
+            data_u64(data, "priority", u16::MAX as u64)?;
+            data_u64(data, "weight", u16::MAX as u64)?;
+            data_u64(data, "port", u16::MAX as u64)?;
+            if !validate_hostname(data_str(data, "target")?) {
+                return invalid("SRV target must be a hostname");
+            }
+            Ok(())
+        }
+        "CAA" => {
+            let data = match &record.data {
+                Some(data) => data,
+                None => return invalid("CAA records require data"),
+            };
+            data_u64(data, "flags", u8::MAX as u64)?;
+            if !CAA_TAGS.contains(&data_str(data, "tag")?) {
+                return invalid("CAA tag must be one of issue, issuewild or iodef");
+            }
+            data_str(data, "value")?;
+            Ok(())
+        }
+        t if !MANAGED_RECORD_TYPES.contains(&t) => Err(ErrorKind::ValidationError(format!(
+            "Record type {} is not supported",
+            t
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::json;
+
+    use super::*;
+
+    fn record(record_type: &str, name: &str, content: &str) -> DnsRecord {
+        DnsRecord::new(
+            record_type.to_string(),
+            name.to_string(),
+            1,
+            content.to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_qualify_record_name() {
+        assert_eq!(
+            "alice.floy.id",
+            qualify_record_name("@", "alice", "floy.id").unwrap()
+        );
+        assert_eq!(
+            "www.alice.floy.id",
+            qualify_record_name("www", "alice", "floy.id").unwrap()
+        );
+        assert_eq!(
+            "_dmarc.alice.floy.id",
+            qualify_record_name("_dmarc.alice.floy.id.", "alice", "floy.id").unwrap()
+        );
+        assert!(qualify_record_name("bob.floy.id", "alice", "floy.id").is_err());
+        assert!(qualify_record_name("floy.id", "alice", "floy.id").is_err());
+        assert!(qualify_record_name("bad name", "alice", "floy.id").is_err());
+    }
+
+    #[test]
+    fn test_validate_record() {
+        assert!(validate_record(&record("A", "alice.floy.id", "10.0.0.1")).is_ok());
+        assert!(validate_record(&record("A", "alice.floy.id", "2001:db8::1")).is_err());
+        assert!(validate_record(&record("AAAA", "alice.floy.id", "2001:db8::1")).is_ok());
+        assert!(validate_record(&record("CNAME", "www.alice.floy.id", "example.com")).is_ok());
+        assert!(validate_record(&record("TXT", "alice.floy.id", "")).is_err());
+        assert!(validate_record(&record("NS", "alice.floy.id", "ns.example.com")).is_err());
+
+        let mut mx = record("MX", "alice.floy.id", "mail.example.com");
+        assert!(validate_record(&mx).is_err());
+        mx.priority = Some(10);
+        assert!(validate_record(&mx).is_ok());
+
+        let mut srv = record("SRV", "_sip._tcp.alice.floy.id", "");
+        srv.data =
+            Some(json!({ "priority": 10, "weight": 5, "port": 5060, "target": "sip.example.com" }));
+        assert!(validate_record(&srv).is_ok());
+        srv.name = "sip.alice.floy.id".to_string();
+        assert!(validate_record(&srv).is_err());
+
+        let mut caa = record("CAA", "alice.floy.id", "");
+        caa.data = Some(json!({ "flags": 0, "tag": "issue", "value": "letsencrypt.org" }));
+        assert!(validate_record(&caa).is_ok());
+        caa.data = Some(json!({ "flags": 0, "tag": "bogus", "value": "letsencrypt.org" }));
+        assert!(validate_record(&caa).is_err());
+    }
+
+    #[test]
+    fn test_validate_ttl_and_proxied() {
+        assert!(validate_ttl_and_proxied("A", 1, true).is_ok());
+        assert!(validate_ttl_and_proxied("CNAME", 1, true).is_ok());
+        assert!(validate_ttl_and_proxied("A", 300, false).is_ok());
+        assert!(validate_ttl_and_proxied("TXT", 3600, false).is_ok());
+
+        assert!(validate_ttl_and_proxied("A", 300, true).is_err());
+        assert!(validate_ttl_and_proxied("MX", 1, true).is_err());
+        assert!(validate_ttl_and_proxied("A", 30, false).is_err());
+        assert!(validate_ttl_and_proxied("A", 90000, false).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::common::errors::ErrorKind;
+
+/// A resolver that only asks the server at `addr` (`host:port`).
+pub fn resolver_for(addr: &str) -> Result<TokioAsyncResolver, ErrorKind> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| ErrorKind::Error(format!("Invalid resolver address {}", addr)))?;
+
+    let servers = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+    let config = ResolverConfig::from_parts(None, vec![], servers);
+    let mut opts = ResolverOpts::default();
+    // A freshly published record must not be hidden by a cached NXDOMAIN.
+    opts.cache_size = 0;
+
+    Ok(TokioAsyncResolver::tokio(config, opts))
+}
+
+/// Looks up the `record_type` records of `name` through the resolver at
+/// `addr` and returns their content. A name without such records yields an
+/// empty list.
+pub async fn lookup(addr: &str, name: &str, record_type: &str) -> Result<Vec<String>, ErrorKind> {
+    let record_type = RecordType::from_str(record_type)
+        .map_err(|_| ErrorKind::Error(format!("Unknown record type {}", record_type)))?;
+
+    match resolver_for(addr)?
+        .lookup(format!("{}.", name.trim_end_matches('.')), record_type)
+        .await
+    {
+        Ok(lookup) => Ok(lookup
+            .record_iter()
+            .filter(|record| record.record_type() == record_type)
+            .filter_map(|record| record.data().map(|data| data.to_string()))
+            .collect()),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(vec![]),
+        Err(e) => Err(ErrorKind::Error(format!(
+            "{} lookup of {} failed: {}",
+            record_type, name, e
+        ))),
+    }
+}
@@ -15,6 +15,11 @@ pub fn validate_email(s: &String) -> bool {
     s.contains('@')
 }
 
+/// True when `subdomain` is the user's claimed subdomain or nested under it.
+pub fn owns_subdomain(subdomain_claim: &str, subdomain: &str) -> bool {
+    subdomain == subdomain_claim || subdomain.ends_with(&format!(".{}", subdomain_claim))
+}
+
 pub fn hash_password(s: &String) -> String {
     hash(s, DEFAULT_COST).unwrap()
 }
@@ -80,6 +85,14 @@ mod tests {
         assert_eq!(false, validate_username(&username_fail));
     }
 
+    #[test]
+    fn test_owns_subdomain() {
+        assert!(owns_subdomain("alice", "alice"));
+        assert!(owns_subdomain("alice", "blog.alice"));
+        assert!(!owns_subdomain("alice", "malice"));
+        assert!(!owns_subdomain("alice", "bob"));
+    }
+
     #[test]
     fn test_hash_password() {
         let password = "password".to_string();
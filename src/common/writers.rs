@@ -42,6 +42,31 @@ impl<T: AsRef<Path>> Writer<T> {
         Ok(())
     }
 
+    pub async fn all(&self) -> Result<Vec<User>, std::io::Error> {
+        let mut file = self.file.lock().await;
+
+        file.seek(SeekFrom::Start(0)).await?;
+
+        let mut content = String::new();
+
+        file.read_to_string(&mut content).await?;
+
+        Ok(content
+            .split('\r')
+            .filter_map(|i| {
+                let map: Vec<&str> = i.trim().split(':').collect();
+                if map.len() < 3 || map[0].is_empty() {
+                    return None;
+                }
+                Some(User {
+                    subdomain_claim: map[0].to_string(),
+                    email: map[1].to_string(),
+                    password: map[2].to_string(),
+                })
+            })
+            .collect())
+    }
+
     pub async fn find(&self, key: &str) -> Result<Option<User>, std::io::Error> {
         let mut file = self.file.lock().await;
 
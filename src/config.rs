@@ -1,6 +1,96 @@
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Clone)]
+use rocket::serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProviderKind {
+    #[default]
+    Cloudflare,
+    PowerDns,
+    /// Records are kept by floy-dns itself and served by its own DNS server.
+    Builtin,
+}
+
+impl FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cloudflare" => Ok(ProviderKind::Cloudflare),
+            "powerdns" | "pdns" => Ok(ProviderKind::PowerDns),
+            "builtin" | "local" => Ok(ProviderKind::Builtin),
+            other => Err(format!("Unknown DNS provider: {}", other)),
+        }
+    }
+}
+
+/// How site files are laid out under the sites directories.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SiteLayout {
+    /// `<user>/<business>/nginx.conf`
+    #[default]
+    Nested,
+    /// `<user>-<business>.conf`, for an `include sites-enabled/*;` that does
+    /// not recurse into subdirectories.
+    Flat,
+}
+
+impl FromStr for SiteLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nested" => Ok(SiteLayout::Nested),
+            "flat" => Ok(SiteLayout::Flat),
+            other => Err(format!("Unknown site layout: {}", other)),
+        }
+    }
+}
+
+/// How ACME challenges for hostnames in a managed zone are answered.
+/// Wildcards can only be validated over DNS and always use DNS-01.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ChallengeKind {
+    /// A token file served from `acme_webroot` on port 80.
+    #[default]
+    Http01,
+    /// A `_acme-challenge` TXT record created through the zone's provider.
+    Dns01,
+}
+
+impl FromStr for ChallengeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "http-01" | "http" => Ok(ChallengeKind::Http01),
+            "dns-01" | "dns" => Ok(ChallengeKind::Dns01),
+            other => Err(format!("Unknown ACME challenge: {}", other)),
+        }
+    }
+}
+
+/// One DNS zone served by this deployment, e.g. `floy.id` or `floy.site`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ZoneConfig {
+    pub dns_suffix: String,
+    #[serde(default)]
+    pub cf_zone_id: String,
+    #[serde(default)]
+    pub cf_api_key: String,
+    pub ip: String,
+    #[serde(default)]
+    pub ipv6: Option<String>,
+    /// PowerDNS zone name, defaults to `<dns_suffix>.`.
+    #[serde(default)]
+    pub pdns_zone: Option<String>,
+}
+
+#[derive(Clone, Default)]
 pub struct Config {
     pub jwt_secret: String,
     pub smtp_host: String,
@@ -10,17 +100,104 @@ pub struct Config {
     pub smtp_from_name: String,
     pub base_url: String,
     pub prefix: String,
+    pub admin_emails: Vec<String>,
+    pub dns_provider: ProviderKind,
+    pub cf_api_url: String,
     pub cf_email: String,
     pub cf_api_key: String,
     pub cf_zone_id: String,
+    pub cf_max_retries: u32,
+    pub cf_retry_base_delay_ms: u64,
+    pub cf_max_concurrent_requests: usize,
+    /// Seconds record lookups are cached for, `0` disables the cache.
+    pub cf_cache_ttl: u64,
+    pub pdns_api_url: String,
+    pub pdns_api_key: String,
+    pub pdns_server_id: String,
+    pub pdns_zone: String,
     pub dns_suffix: String,
+    /// `host:port` the built-in DNS server listens on, over UDP and TCP.
+    pub dns_listen: String,
+    /// Directory the built-in provider keeps each zone's records in.
+    pub local_zone_dir: String,
+    /// Nameservers announced in the NS and SOA records of built-in zones,
+    /// `ns1.<dns_suffix>` when empty.
+    pub dns_nameservers: Vec<String>,
     pub database_path: String,
+    pub ddns_credentials_path: String,
+    pub custom_domains_path: String,
+    /// JSON file keeping the ids of the records floy-dns created, empty to
+    /// always look records up by name.
+    pub record_ids_path: String,
+    /// `host:port` of the resolver custom domain TXT records are checked
+    /// against.
+    pub txt_resolver: String,
+    /// `host:port` of the resolvers new domains are checked against after
+    /// provisioning, none to skip the check.
+    pub propagation_resolvers: Vec<String>,
+    /// Seconds to wait for a new domain to resolve everywhere.
+    pub propagation_timeout: u64,
+    /// Seconds between two rounds of propagation lookups.
+    pub propagation_interval: u64,
+    /// Directory the site files are written to.
+    pub sites_available_dir: String,
+    /// Directory the site files are linked into, empty when nginx includes
+    /// `sites_available_dir` directly as with `conf.d`.
+    pub sites_enabled_dir: String,
+    pub site_layout: SiteLayout,
+    /// Shell command checking the nginx configuration, empty to skip.
+    pub nginx_test_command: String,
+    /// Shell command making nginx pick up changed sites, empty to skip.
+    pub nginx_reload_command: String,
+    /// Milliseconds without site changes before nginx is tested and
+    /// reloaded, so a burst of changes costs one reload. Requests changing
+    /// a site wait for it and fail when the test rolled them back.
+    pub nginx_reload_debounce_ms: u64,
+    /// Directory URL of the ACME server certificates are requested from,
+    /// empty to serve sites over plain HTTP only.
+    pub acme_directory_url: String,
+    /// Contact address registered with the ACME account.
+    pub acme_email: Option<String>,
+    pub acme_challenge: ChallengeKind,
+    /// Directory HTTP-01 challenge tokens are written to and served from.
+    pub acme_webroot: String,
+    /// Directory the account key and each site's certificate are kept in.
+    pub acme_certs_dir: String,
+    /// PEM file with extra roots the ACME server's TLS certificate is
+    /// checked against, such as the one of a local Pebble.
+    pub acme_ca_bundle: Option<String>,
+    /// Certificates expiring within this many days are renewed.
+    pub acme_renew_days: u32,
+    /// Seconds between two checks for certificates to issue or renew.
+    pub acme_renew_interval: u64,
     pub ip: String,
+    pub ipv6: Option<String>,
+    pub reconcile_interval: u64,
+    pub reconcile_repair: bool,
+    /// Every zone served, the first one being the default. The zone specific
+    /// fields above (`dns_suffix`, `cf_zone_id`, `ip`, ...) always describe
+    /// the zone this `Config` was scoped to with [`Config::for_zone`].
+    pub zones: Vec<ZoneConfig>,
 }
 
 impl Config {
     pub fn new() -> Self {
-        Self {
+        // ZONES_FILE holds a JSON array of zones; without it the single zone
+        // comes from the DNS_SUFFIX, CF_ZONE_ID, CF_API_KEY, IP and IPV6 vars.
+        let zones: Vec<ZoneConfig> = match env::var("ZONES_FILE") {
+            Ok(path) => rocket::serde::json::from_str(&fs::read_to_string(path).unwrap()).unwrap(),
+            Err(_) => vec![ZoneConfig {
+                dns_suffix: env::var("DNS_SUFFIX").unwrap(),
+                cf_zone_id: env::var("CF_ZONE_ID").unwrap_or_default(),
+                cf_api_key: env::var("CF_API_KEY").unwrap_or_default(),
+                ip: env::var("IP").unwrap(),
+                ipv6: env::var("IPV6").ok().filter(|ip| !ip.is_empty()),
+                pdns_zone: env::var("PDNS_ZONE").ok(),
+            }],
+        };
+        assert!(!zones.is_empty(), "At least one zone must be configured");
+
+        let config = Self {
             jwt_secret: env::var("JWT_SECRET").unwrap(),
             smtp_host: env::var("SMTP_HOST").unwrap(),
             smtp_username: env::var("SMTP_USERNAME").unwrap(),
@@ -28,13 +205,170 @@ impl Config {
             smtp_from_email: env::var("SMTP_FROM_EMAIL").unwrap(),
             smtp_from_name: env::var("SMTP_FROM_NAME").unwrap(),
             base_url: env::var("BASE_URL").unwrap(),
-            cf_email: env::var("CF_EMAIL").unwrap(),
-            cf_api_key: env::var("CF_API_KEY").unwrap(),
-            cf_zone_id: env::var("CF_ZONE_ID").unwrap(),
-            dns_suffix: env::var("DNS_SUFFIX").unwrap(),
+            admin_emails: env::var("ADMIN_EMAILS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|email| email.trim().to_string())
+                .filter(|email| !email.is_empty())
+                .collect(),
+            dns_provider: env::var("DNS_PROVIDER")
+                .map(|p| p.parse().unwrap())
+                .unwrap_or_default(),
+            cf_api_url: env::var("CF_API_URL")
+                .unwrap_or("https://api.cloudflare.com/client/v4".to_string()),
+            cf_email: env::var("CF_EMAIL").unwrap_or_default(),
+            cf_max_retries: env::var("CF_MAX_RETRIES")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(3),
+            cf_retry_base_delay_ms: env::var("CF_RETRY_BASE_DELAY_MS")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(500),
+            cf_max_concurrent_requests: env::var("CF_MAX_CONCURRENT_REQUESTS")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(4),
+            cf_cache_ttl: env::var("CF_CACHE_TTL")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(30),
+            pdns_api_url: env::var("PDNS_API_URL").unwrap_or_default(),
+            pdns_api_key: env::var("PDNS_API_KEY").unwrap_or_default(),
+            pdns_server_id: env::var("PDNS_SERVER_ID").unwrap_or("localhost".to_string()),
+            dns_listen: env::var("DNS_LISTEN").unwrap_or("0.0.0.0:5353".to_string()),
+            local_zone_dir: env::var("LOCAL_ZONE_DIR").unwrap_or("zones".to_string()),
+            dns_nameservers: env::var("DNS_NAMESERVERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|ns| ns.trim().trim_end_matches('.').to_lowercase())
+                .filter(|ns| !ns.is_empty())
+                .collect(),
             database_path: env::var("DATABASE_PATH").unwrap(),
+            ddns_credentials_path: env::var("DDNS_CREDENTIALS_PATH")
+                .unwrap_or("ddns_credentials.txt".to_string()),
+            record_ids_path: env::var("RECORD_IDS_PATH").unwrap_or("record_ids.json".to_string()),
+            custom_domains_path: env::var("CUSTOM_DOMAINS_PATH")
+                .unwrap_or("custom_domains.json".to_string()),
+            txt_resolver: env::var("TXT_RESOLVER").unwrap_or("1.1.1.1:53".to_string()),
+            propagation_resolvers: env::var("PROPAGATION_RESOLVERS")
+                .unwrap_or("1.1.1.1:53,8.8.8.8:53".to_string())
+                .split(',')
+                .map(|resolver| resolver.trim().to_string())
+                .filter(|resolver| !resolver.is_empty())
+                .collect(),
+            propagation_timeout: env::var("PROPAGATION_TIMEOUT")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(120),
+            propagation_interval: env::var("PROPAGATION_INTERVAL")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(5),
+            sites_available_dir: env::var("SITES_AVAILABLE_DIR")
+                .unwrap_or("/etc/nginx/sites-available".to_string()),
+            sites_enabled_dir: env::var("SITES_ENABLED_DIR")
+                .unwrap_or("/etc/nginx/sites-enabled".to_string()),
+            site_layout: env::var("SITE_LAYOUT")
+                .map(|l| l.parse().unwrap())
+                .unwrap_or_default(),
+            nginx_test_command: env::var("NGINX_TEST_COMMAND").unwrap_or("nginx -t".to_string()),
+            nginx_reload_command: env::var("NGINX_RELOAD_COMMAND")
+                .unwrap_or("nginx -s reload".to_string()),
+            nginx_reload_debounce_ms: env::var("NGINX_RELOAD_DEBOUNCE_MS")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(500),
+            acme_directory_url: env::var("ACME_DIRECTORY_URL").unwrap_or_default(),
+            acme_email: env::var("ACME_EMAIL")
+                .ok()
+                .filter(|email| !email.is_empty()),
+            acme_challenge: env::var("ACME_CHALLENGE")
+                .map(|c| c.parse().unwrap())
+                .unwrap_or_default(),
+            acme_webroot: env::var("ACME_WEBROOT").unwrap_or("/var/www/acme".to_string()),
+            acme_certs_dir: env::var("ACME_CERTS_DIR").unwrap_or("/etc/floy-dns/certs".to_string()),
+            acme_ca_bundle: env::var("ACME_CA_BUNDLE")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            acme_renew_days: env::var("ACME_RENEW_DAYS")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(30),
+            acme_renew_interval: env::var("ACME_RENEW_INTERVAL")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(43200),
             prefix: env::var("PREFIX").unwrap(),
-            ip: env::var("IP").unwrap(),
+            reconcile_interval: env::var("RECONCILE_INTERVAL")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(0),
+            reconcile_repair: env::var("RECONCILE_REPAIR")
+                .map(|s| s.parse().unwrap())
+                .unwrap_or(false),
+            zones,
+            ..Default::default()
+        };
+
+        config.for_zone(&config.zones[0])
+    }
+
+    /// Returns a copy of this configuration scoped to `zone`.
+    pub fn for_zone(&self, zone: &ZoneConfig) -> Config {
+        Config {
+            dns_suffix: zone.dns_suffix.clone(),
+            cf_zone_id: zone.cf_zone_id.clone(),
+            cf_api_key: zone.cf_api_key.clone(),
+            ip: zone.ip.clone(),
+            ipv6: zone.ipv6.clone(),
+            pdns_zone: zone
+                .pdns_zone
+                .clone()
+                .unwrap_or(format!("{}.", zone.dns_suffix)),
+            ..self.clone()
+        }
+    }
+
+    /// True when sites are to be served over HTTPS.
+    pub fn acme_enabled(&self) -> bool {
+        !self.acme_directory_url.is_empty()
+    }
+
+    pub fn is_default_zone(&self) -> bool {
+        self.zones
+            .first()
+            .is_none_or(|zone| zone.dns_suffix == self.dns_suffix)
+    }
+
+    /// Nameservers of this zone, see [`Config::dns_nameservers`].
+    pub fn nameservers(&self) -> Vec<String> {
+        if self.dns_nameservers.is_empty() {
+            vec![format!("ns1.{}", self.dns_suffix)]
+        } else {
+            self.dns_nameservers.clone()
+        }
+    }
+
+    /// Path of a business' nginx site file in this zone, relative to the
+    /// sites directories. The default zone keeps the historical `nginx.conf`
+    /// (or `<user>-<business>.conf`), other zones add their suffix.
+    pub fn site_file_name(&self, user_id: &str, business_id: &str) -> PathBuf {
+        let zone = if self.is_default_zone() {
+            String::new()
+        } else {
+            format!(".{}", self.dns_suffix)
+        };
+
+        match self.site_layout {
+            SiteLayout::Nested => Path::new(user_id)
+                .join(business_id)
+                .join(format!("nginx{}.conf", zone)),
+            SiteLayout::Flat => PathBuf::from(format!("{}-{}{}.conf", user_id, business_id, zone)),
+        }
+    }
+
+    /// Path of the site file of a nested hostname like `blog.alice.<suffix>`,
+    /// kept next to the business' own site so it has a server block of its
+    /// own. The full hostname keeps it apart from every other site file.
+    pub fn host_site_file_name(&self, user_id: &str, business_id: &str, hostname: &str) -> PathBuf {
+        match self.site_layout {
+            SiteLayout::Nested => Path::new(user_id)
+                .join(business_id)
+                .join(format!("nginx-{}.conf", hostname)),
+            SiteLayout::Flat => {
+                PathBuf::from(format!("{}-{}-{}.conf", user_id, business_id, hostname))
+            }
         }
     }
 }
@@ -0,0 +1,337 @@
+use std::io::ErrorKind as IoErrorKind;
+use std::path::PathBuf;
+
+use hickory_resolver::error::ResolveErrorKind;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::common::errors::ErrorKind;
+use crate::common::resolver::resolver_for;
+
+/// Label the verification TXT record is published under.
+pub const VERIFICATION_LABEL: &str = "_floy-verify";
+
+const TOKEN_LENGTH: usize = 32;
+
+/// A hostname outside our zones that a user wants served by their site.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomDomain {
+    pub hostname: String,
+    pub user_id: String,
+    pub business_id: String,
+    /// The subdomain whose site serves the hostname, e.g. `shop`.
+    pub subdomain: String,
+    /// Suffix of the zone the subdomain lives in.
+    pub zone: String,
+    pub token: String,
+    #[serde(default)]
+    pub verified: bool,
+}
+
+impl CustomDomain {
+    pub fn new(
+        hostname: &str,
+        user_id: &str,
+        business_id: &str,
+        subdomain: &str,
+        zone: &str,
+    ) -> Self {
+        CustomDomain {
+            hostname: hostname.to_lowercase(),
+            user_id: user_id.to_string(),
+            business_id: business_id.to_string(),
+            subdomain: subdomain.to_string(),
+            zone: zone.to_string(),
+            token: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(TOKEN_LENGTH)
+                .map(char::from)
+                .collect(),
+            verified: false,
+        }
+    }
+
+    /// Name of the TXT record the owner has to publish.
+    pub fn txt_name(&self) -> String {
+        format!("{}.{}", VERIFICATION_LABEL, self.hostname)
+    }
+
+    /// Content of the TXT record the owner has to publish.
+    pub fn txt_value(&self) -> String {
+        format!("floy-verify={}", self.token)
+    }
+}
+
+/// Custom domains, pending and verified, kept as a JSON array on disk.
+pub struct CustomDomainStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl CustomDomainStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CustomDomainStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn read(&self) -> Result<Vec<CustomDomain>, ErrorKind> {
+        match fs::read_to_string(&self.path).await {
+            Ok(content) => rocket::serde::json::from_str(&content)
+                .map_err(|e| ErrorKind::Error(format!("Corrupt custom domain store: {}", e))),
+            Err(e) if e.kind() == IoErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(&self, domains: &[CustomDomain]) -> Result<(), ErrorKind> {
+        let content = rocket::serde::json::to_string(&domains)
+            .map_err(|e| ErrorKind::Error(e.to_string()))?;
+
+        Ok(fs::write(&self.path, content).await?)
+    }
+
+    /// Stores a new pending domain. A hostname can only be claimed once.
+    pub async fn add(&self, domain: CustomDomain) -> Result<CustomDomain, ErrorKind> {
+        let _guard = self.lock.lock().await;
+        let mut domains = self.read().await?;
+
+        if domains.iter().any(|d| d.hostname == domain.hostname) {
+            return Err(ErrorKind::RecordAlreadyExists(domain.hostname));
+        }
+
+        domains.push(domain.clone());
+        self.write(&domains).await?;
+
+        Ok(domain)
+    }
+
+    pub async fn get(&self, hostname: &str) -> Result<Option<CustomDomain>, ErrorKind> {
+        let _guard = self.lock.lock().await;
+
+        Ok(self
+            .read()
+            .await?
+            .into_iter()
+            .find(|d| d.hostname.eq_ignore_ascii_case(hostname)))
+    }
+
+    /// Domains attached to `<subdomain>.<zone>`.
+    pub async fn list(&self, subdomain: &str, zone: &str) -> Result<Vec<CustomDomain>, ErrorKind> {
+        let _guard = self.lock.lock().await;
+
+        Ok(self
+            .read()
+            .await?
+            .into_iter()
+            .filter(|d| d.subdomain == subdomain && d.zone == zone)
+            .collect())
+    }
+
+    pub async fn mark_verified(&self, hostname: &str) -> Result<(), ErrorKind> {
+        let _guard = self.lock.lock().await;
+        let mut domains = self.read().await?;
+
+        match domains
+            .iter_mut()
+            .find(|d| d.hostname.eq_ignore_ascii_case(hostname))
+        {
+            Some(domain) => domain.verified = true,
+            None => return Err(ErrorKind::NotFound),
+        }
+
+        self.write(&domains).await
+    }
+
+    /// Forgets a domain and returns it.
+    pub async fn remove(&self, hostname: &str) -> Result<Option<CustomDomain>, ErrorKind> {
+        let _guard = self.lock.lock().await;
+        let mut domains = self.read().await?;
+
+        let index = domains
+            .iter()
+            .position(|d| d.hostname.eq_ignore_ascii_case(hostname));
+        let removed = index.map(|i| domains.remove(i));
+        if removed.is_some() {
+            self.write(&domains).await?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Forgets every domain attached to `<subdomain>.<zone>`, for when the
+    /// subdomain itself is deleted.
+    pub async fn remove_all(
+        &self,
+        subdomain: &str,
+        zone: &str,
+    ) -> Result<Vec<CustomDomain>, ErrorKind> {
+        let _guard = self.lock.lock().await;
+        let (removed, kept): (Vec<CustomDomain>, Vec<CustomDomain>) = self
+            .read()
+            .await?
+            .into_iter()
+            .partition(|d| d.subdomain == subdomain && d.zone == zone);
+        if !removed.is_empty() {
+            self.write(&kept).await?;
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Looks up the TXT records of `name` through the resolver at `resolver`
+/// (`host:port`). A name without TXT records yields an empty list.
+pub async fn lookup_txt(resolver: &str, name: &str) -> Result<Vec<String>, ErrorKind> {
+    match resolver_for(resolver)?
+        .txt_lookup(format!("{}.", name.trim_end_matches('.')))
+        .await
+    {
+        Ok(lookup) => Ok(lookup
+            .iter()
+            .map(|txt| {
+                txt.iter()
+                    .map(|part| String::from_utf8_lossy(part).to_string())
+                    .collect()
+            })
+            .collect()),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(vec![]),
+        Err(e) => Err(ErrorKind::Error(format!(
+            "TXT lookup of {} failed: {}",
+            name, e
+        ))),
+    }
+}
+
+/// True when the domain's verification TXT record is published.
+pub async fn check_verification(domain: &CustomDomain, resolver: &str) -> Result<bool, ErrorKind> {
+    let expected = domain.txt_value();
+
+    Ok(lookup_txt(resolver, &domain.txt_name())
+        .await?
+        .iter()
+        .any(|txt| txt.trim() == expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::TXT;
+    use hickory_resolver::proto::rr::{RData, Record, RecordType};
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Answers TXT queries for `records` over UDP, standing in for the
+    /// resolver custom domains are checked against.
+    async fn serve_txt(records: Vec<(String, String)>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let name = query.name().to_string();
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_query(query.clone());
+
+                let answers: Vec<Record> = records
+                    .iter()
+                    .filter(|(n, _)| *n == name && query.query_type() == RecordType::TXT)
+                    .map(|(_, value)| {
+                        Record::from_rdata(
+                            query.name().clone(),
+                            60,
+                            RData::TXT(TXT::new(vec![value.clone()])),
+                        )
+                    })
+                    .collect();
+                if answers.is_empty() {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+                response.add_answers(answers);
+
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_check_verification() {
+        let domain = CustomDomain::new("www.theirshop.com", "u1", "b1", "shop", "floy.id");
+        let resolver = serve_txt(vec![
+            (
+                "_floy-verify.www.theirshop.com.".to_string(),
+                domain.txt_value(),
+            ),
+            (
+                "_floy-verify.other.com.".to_string(),
+                "floy-verify=wrong".to_string(),
+            ),
+        ])
+        .await;
+
+        assert!(check_verification(&domain, &resolver).await.unwrap());
+
+        let other = CustomDomain::new("other.com", "u1", "b1", "shop", "floy.id");
+        assert!(!check_verification(&other, &resolver).await.unwrap());
+
+        let missing = CustomDomain::new("missing.com", "u1", "b1", "shop", "floy.id");
+        assert!(!check_verification(&missing, &resolver).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let path = std::env::temp_dir().join(format!("floy-custom-{}.json", std::process::id()));
+        let store = CustomDomainStore::new(&path);
+
+        let domain = CustomDomain::new("WWW.theirshop.com", "u1", "b1", "shop", "floy.id");
+        store.add(domain.clone()).await.unwrap();
+        assert!(matches!(
+            store.add(domain.clone()).await,
+            Err(ErrorKind::RecordAlreadyExists(_))
+        ));
+
+        store.mark_verified("www.theirshop.com").await.unwrap();
+        let listed = store.list("shop", "floy.id").await.unwrap();
+        assert_eq!(1, listed.len());
+        assert!(listed[0].verified);
+        assert!(store.list("shop", "floy.site").await.unwrap().is_empty());
+
+        assert!(store.remove("www.theirshop.com").await.unwrap().is_some());
+        assert_eq!(None, store.get("www.theirshop.com").await.unwrap());
+
+        for (hostname, zone) in [
+            ("a.com", "floy.id"),
+            ("b.com", "floy.id"),
+            ("c.com", "floy.site"),
+        ] {
+            let domain = CustomDomain::new(hostname, "u1", "b1", "shop", zone);
+            store.add(domain).await.unwrap();
+        }
+        assert_eq!(2, store.remove_all("shop", "floy.id").await.unwrap().len());
+        assert!(store.list("shop", "floy.id").await.unwrap().is_empty());
+        assert_eq!(1, store.list("shop", "floy.site").await.unwrap().len());
+
+        std::fs::remove_file(path).ok();
+    }
+}
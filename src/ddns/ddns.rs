@@ -0,0 +1,263 @@
+use std::net::IpAddr;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+
+use crate::common::credentials::CredentialStore;
+use crate::common::errors::ErrorKind;
+use crate::provider::provider::{record_type_for, DnsProvider, RecordOptions};
+use crate::zones::zones::Zones;
+
+/// Credentials sent by dyndns2 clients as HTTP basic auth. The password is
+/// the subdomain's update secret; the username is not checked.
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicAuth {
+    type Error = ErrorKind;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<BasicAuth, ErrorKind> {
+        let decoded = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|b| STANDARD.decode(b.trim()).ok())
+            .and_then(|b| String::from_utf8(b).ok());
+
+        match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            Some((username, password)) => request::Outcome::Success(BasicAuth {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            None => request::Outcome::Error((Status::Unauthorized, ErrorKind::InvalidValue)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UpdateOutcome {
+    Good(IpAddr),
+    NoChange(IpAddr),
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    NotFqdn,
+    BadAuth,
+    NoHost,
+    Failed(ErrorKind),
+}
+
+impl From<ErrorKind> for UpdateError {
+    fn from(e: ErrorKind) -> Self {
+        UpdateError::Failed(e)
+    }
+}
+
+impl From<std::io::Error> for UpdateError {
+    fn from(e: std::io::Error) -> Self {
+        UpdateError::Failed(ErrorKind::from(e))
+    }
+}
+
+/// Strips the zone suffix from a client supplied hostname. DuckDNS clients
+/// send bare names, so a name without the suffix is taken as-is.
+pub fn subdomain_of(hostname: &str, suffix: &str) -> Option<String> {
+    let hostname = hostname.trim().trim_end_matches('.').to_lowercase();
+
+    let subdomain = match hostname.strip_suffix(suffix) {
+        Some(rest) => rest.strip_suffix('.')?.to_string(),
+        None => hostname,
+    };
+
+    Some(subdomain).filter(|s| !s.is_empty() && !s.starts_with("*."))
+}
+
+/// Points the subdomain's record of `ip`'s family at `ip`, creating it if
+/// the subdomain only has a record of the other family so far.
+pub async fn update_host(
+    provider: &dyn DnsProvider,
+    subdomain: &str,
+    ip: IpAddr,
+) -> Result<UpdateOutcome, ErrorKind> {
+    let address = ip.to_string();
+    let record_type = record_type_for(&address);
+
+    match provider
+        .get_subdomain_dns_record(subdomain, record_type, false)
+        .await
+    {
+        Ok((content, _)) if content == address => Ok(UpdateOutcome::NoChange(ip)),
+        Ok(_) => {
+            provider
+                .update_subdomain_dns_record(subdomain, &address)
+                .await?;
+            Ok(UpdateOutcome::Good(ip))
+        }
+        Err(ErrorKind::NotFound) => {
+            // Home connections are rarely plain HTTP, so keep new records
+            // out of the proxy.
+            let options = RecordOptions {
+                proxied: false,
+                ..Default::default()
+            };
+            provider
+                .add_subdomain_dns_record(subdomain, &address, &options)
+                .await?;
+            Ok(UpdateOutcome::Good(ip))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks `secret` against the hostname's update secret and then runs
+/// [`update_host`] in the zone the hostname belongs to. Bare names go to the
+/// default zone. The secret is checked first so that callers without one
+/// can't probe which hostnames exist.
+pub async fn authenticated_update(
+    zones: &Zones,
+    credentials: &CredentialStore,
+    hostname: &str,
+    secret: &str,
+    ip: IpAddr,
+) -> Result<UpdateOutcome, UpdateError> {
+    let zone = zones.for_hostname(hostname).unwrap_or(zones.default_zone());
+    let suffix = &zone.config.dns_suffix;
+    let subdomain = subdomain_of(hostname, suffix).ok_or(UpdateError::NotFqdn)?;
+
+    if !credentials
+        .verify(&format!("{}.{}", subdomain, suffix), secret)
+        .await?
+    {
+        return Err(UpdateError::BadAuth);
+    }
+
+    let provider = zone.provider.as_ref();
+    if !provider.check_exists(&subdomain).await? {
+        return Err(UpdateError::NoHost);
+    }
+
+    Ok(update_host(provider, &subdomain, ip).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Keeps `(subdomain, type) -> content` in memory.
+    #[derive(Default)]
+    struct FakeProvider {
+        records: Mutex<HashMap<(String, String), String>>,
+    }
+
+    #[rocket::async_trait]
+    impl DnsProvider for FakeProvider {
+        async fn add_subdomain_dns_record(
+            &self,
+            subdomain: &str,
+            ip: &str,
+            _options: &RecordOptions,
+        ) -> Result<(), ErrorKind> {
+            self.records.lock().unwrap().insert(
+                (subdomain.to_string(), record_type_for(ip).to_string()),
+                ip.to_string(),
+            );
+            Ok(())
+        }
+
+        async fn get_subdomain_dns_record(
+            &self,
+            subdomain: &str,
+            record_type: &str,
+            _wildcard: bool,
+        ) -> Result<(String, String), ErrorKind> {
+            self.records
+                .lock()
+                .unwrap()
+                .get(&(subdomain.to_string(), record_type.to_string()))
+                .map(|content| (content.clone(), subdomain.to_string()))
+                .ok_or(ErrorKind::NotFound)
+        }
+
+        async fn update_subdomain_dns_record(
+            &self,
+            subdomain: &str,
+            ip: &str,
+        ) -> Result<(), ErrorKind> {
+            self.add_subdomain_dns_record(subdomain, ip, &RecordOptions::default())
+                .await
+        }
+
+        async fn delete_subdomain_dns_record(&self, subdomain: &str) -> Result<(), ErrorKind> {
+            self.records
+                .lock()
+                .unwrap()
+                .retain(|(name, _), _| name != subdomain);
+            Ok(())
+        }
+
+        async fn check_exists(&self, subdomain: &str) -> Result<bool, ErrorKind> {
+            Ok(self
+                .records
+                .lock()
+                .unwrap()
+                .keys()
+                .any(|(name, _)| name == subdomain))
+        }
+    }
+
+    #[test]
+    fn test_subdomain_of() {
+        assert_eq!(
+            Some("alice".to_string()),
+            subdomain_of("alice.floy.id", "floy.id")
+        );
+        assert_eq!(
+            Some("blog.alice".to_string()),
+            subdomain_of("Blog.Alice.floy.id.", "floy.id")
+        );
+        assert_eq!(Some("alice".to_string()), subdomain_of("alice", "floy.id"));
+        assert_eq!(None, subdomain_of("floy.id", "floy.id"));
+        assert_eq!(None, subdomain_of("*.alice.floy.id", "floy.id"));
+    }
+
+    #[tokio::test]
+    async fn test_update_host() {
+        let provider = FakeProvider::default();
+        let v4: IpAddr = "203.0.113.7".parse().unwrap();
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+
+        provider
+            .add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            UpdateOutcome::Good(v4),
+            update_host(&provider, "alice", v4).await.unwrap()
+        );
+        assert_eq!(
+            UpdateOutcome::NoChange(v4),
+            update_host(&provider, "alice", v4).await.unwrap()
+        );
+        assert_eq!(
+            UpdateOutcome::Good(v6),
+            update_host(&provider, "alice", v6).await.unwrap()
+        );
+        assert_eq!(
+            ("2001:db8::7".to_string(), "alice".to_string()),
+            provider
+                .get_subdomain_dns_record("alice", "AAAA", false)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use bcrypt::verify;
 use reqwest::{get, redirect};
-use rocket::http::Status;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Status};
 use rocket::log::private::{log, logger, Level, Log};
 use rocket::response::Redirect;
 use rocket::serde::json::serde_json::json;
@@ -10,19 +11,41 @@ use rocket_dyn_templates::handlebars::JsonValue;
 use std::fs;
 use std::os::unix::fs::symlink;
 use std::path::Path;
+use std::net::IpAddr;
 use std::process::Command;
+use std::sync::Arc;
 
-use crate::cloudflare::cloudflare::Cloudflare;
+use crate::acme::acme::Certificates;
+use crate::bulk::bulk::{self, RowResult, RowStatus};
+use crate::common::credentials::CredentialStore;
 use crate::common::errors::ErrorKind;
 use crate::common::errors::ErrorKind::Error;
-use crate::common::jwt::{generate_token, read_token, ApiKey};
+use crate::common::jwt::{generate_token, read_token, AdminKey, ApiKey};
+use crate::common::records::{
+    qualify_record_name, validate_hostname, validate_record, validate_ttl_and_proxied,
+};
 use crate::common::utils::{
-    find_subdomain_claim, hash_password, send_verification_email, validate_email, validate_username,
+    find_subdomain_claim, hash_password, owns_subdomain, send_verification_email, validate_email,
+    validate_username,
 };
 use crate::common::writers::Writer;
 use crate::config::Config;
-use crate::models::{SubdomainRequest, Login, SlugRequest, User, WhoAmI, DNS};
+use crate::custom_domains::custom_domains::{self, CustomDomain, CustomDomainStore};
+use crate::ddns::ddns::{authenticated_update, BasicAuth, UpdateError, UpdateOutcome};
+use crate::importer::importer;
+use crate::models::{
+    BulkRow, CustomDomainRequest, DnsRecord, DuckDnsQuery, Login, ProxiedRequest, RecordFilter,
+    SlugRequest, SubdomainRequest, User, WhoAmI, DNS,
+};
+use crate::propagation::propagation::{Check, PropagationTracker};
+use crate::provider::provider::{record_type_for, DnsProvider, RecordOptions, ADDRESS_RECORD_TYPES};
+use crate::reconciler::reconciler::{reconcile, PROVISIONING};
 use crate::updater::updater;
+use crate::zonefile::zonefile;
+use crate::zones::zones::{Zone, Zones};
+
+/// Largest zone file or bulk batch accepted, in MiB.
+const UPLOAD_LIMIT: u64 = 2;
 
 #[post("/register", data = "<data>")]
 async fn register(
@@ -170,60 +193,263 @@ async fn verify_account(
     Ok(Redirect::to("/login?verified=true"))
 }
 
+/// Creates the domain, then keeps checking in the background that the
+/// configured resolvers see it. The check's progress is reported by
+/// `GET /domain/<sub>/propagation`. With ACME configured a certificate is
+/// requested once the name resolves. The site is live when this returns:
+/// nginx has been tested and reloaded, and a failed test fails the request.
 #[post("/domain", data = "<req>")]
 pub async fn create_domain_endpoint(
     req: Json<SubdomainRequest>,
-    cfg: &State<Config>,
-    cloudflare: &State<Cloudflare>,
-) -> Result<Json<JsonValue>, Status> {
-    if (cloudflare
-        .check_exists(&req.subdomain)
-        .await
-        .map_err(|_| Status::InternalServerError)?)
-    {
-        return Err(Status::Conflict);
-    }
+    zones: &State<Zones>,
+    propagation: &State<PropagationTracker>,
+    certificates: &State<Certificates>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(req.zone.as_deref())?;
 
-    cloudflare
-        .add_subdomain_dns_record(&req.subdomain, &cfg.ip)
-        .await
-        .map_err(|_| Status::InternalServerError)?;
+    let options = check_domain(&req, zone).await?;
+    provision_domain(&req, &options, zone).await?;
+
+    let cfg = &zone.config;
+    let domain = format!("{}.{}", req.subdomain, cfg.dns_suffix);
+    let check = Check::new(&domain, &cfg.ip, options.proxied, cfg);
+    if cfg.acme_enabled() {
+        let (user_id, business_id) = (&req.user_id, &req.business_id);
+        certificates.start(zone.clone(), user_id, business_id, Some(check.clone()));
+    }
 
-    updater::create_domain(
-        &req.user_id,
-        &req.business_id,
-        &req.subdomain,
-        &cfg,
-    )
-    .map_err(|_| Status::InternalServerError)?;
     Ok(Json(json!({
         "status": 200,
         "message": "Domain created successfully",
-        "domain": format!("{}.{}", req.subdomain, cfg.dns_suffix)
+        "domain": domain,
+        "propagation": propagation.start(check)
     })))
 }
 
+#[get("/domain/<sub>/propagation?<zone>")]
+pub async fn propagation_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    zones: &State<Zones>,
+    propagation: &State<PropagationTracker>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let domain = format!("{}.{}", sub, zone.config.dns_suffix);
+
+    match propagation.get(&domain) {
+        Some(state) => Ok(Json(json!({
+            "status": 200,
+            "message": "Propagation status found",
+            "domain": domain,
+            "propagation": state
+        }))),
+        None => Err(ErrorKind::NotFound),
+    }
+}
+
+/// Everything `POST /domain` checks before touching DNS or nginx. Returns the
+/// options the address records will be created with.
+async fn check_domain(req: &SubdomainRequest, zone: &Zone) -> Result<RecordOptions, ErrorKind> {
+    let (cfg, provider) = (&zone.config, zone.provider.as_ref());
+
+    if !validate_hostname(&req.subdomain) || req.subdomain.starts_with("*.") {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} is not a valid hostname",
+            req.subdomain
+        )));
+    }
+
+    if let Some((_, parent)) = req.subdomain.split_once('.') {
+        authorize_nested_host(provider, &req.user_id, parent, cfg).await?;
+    }
+
+    let options = RecordOptions {
+        wildcard: req.wildcard,
+        ttl: req.ttl.unwrap_or(DnsRecord::automatic_ttl()),
+        proxied: req.proxied.unwrap_or(true),
+    };
+    validate_ttl_and_proxied(record_type_for(&cfg.ip), options.ttl, options.proxied)?;
+
+    if provider.check_exists(&req.subdomain).await? {
+        return Err(ErrorKind::RecordAlreadyExists(format!(
+            "{}.{}",
+            req.subdomain, cfg.dns_suffix
+        )));
+    }
+
+    Ok(options)
+}
+
+/// Creates the address records and the nginx site of a checked request.
+async fn provision_domain(
+    req: &SubdomainRequest,
+    options: &RecordOptions,
+    zone: &Zone,
+) -> Result<(), ErrorKind> {
+    let (cfg, provider) = (&zone.config, zone.provider.as_ref());
+    let _provisioning = PROVISIONING.read().await;
+
+    provider
+        .add_subdomain_dns_record(&req.subdomain, &cfg.ip, options)
+        .await?;
+
+    if let Some(ipv6) = &cfg.ipv6 {
+        let added = provider
+            .add_subdomain_dns_record(&req.subdomain, ipv6, options)
+            .await;
+        if let Err(e) = added {
+            // Otherwise the A record makes a retry fail as already existing.
+            let _ = provider.delete_subdomain_dns_record(&req.subdomain).await;
+            return Err(e);
+        }
+    }
+
+    let (user_id, business_id) = (req.user_id.clone(), req.business_id.clone());
+    let (subdomain, wildcard, site_cfg) = (req.subdomain.clone(), req.wildcard, cfg.clone());
+    let created = updater::blocking(move || {
+        updater::create_domain(&user_id, &business_id, &subdomain, wildcard, &site_cfg)
+    })
+    .await;
+
+    if let Err(e) = created {
+        // Same as above: no records without a site.
+        let _ = provider.delete_subdomain_dns_record(&req.subdomain).await;
+        return Err(match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                ErrorKind::RecordAlreadyExists(format!("{}.{}", req.subdomain, cfg.dns_suffix))
+            }
+            _ => e.into(),
+        });
+    }
+    Ok(())
+}
+
+/// Provisions a batch of domains and their slug pages, sent as a JSON array
+/// or as CSV. Every row is validated before anything is created. Rows are
+/// then provisioned in order; unless `stop_on_error=false` is passed the
+/// first invalid or failed row stops the batch.
+#[post("/domain/bulk?<zone>&<stop_on_error>", data = "<data>")]
+pub async fn bulk_provision_endpoint(
+    zone: Option<&str>,
+    stop_on_error: Option<bool>,
+    content_type: Option<&ContentType>,
+    data: Data<'_>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let selected = zones.select(zone)?;
+    let stop_on_error = stop_on_error.unwrap_or(true);
+
+    let json = content_type.is_some_and(|ct| ct.is_json());
+    let rows = bulk::parse_rows(&read_upload(data).await?, json)?;
+
+    let mut errors = bulk::validate_rows(&rows);
+    for (i, row) in rows.iter().enumerate() {
+        if errors[i].is_none() && !bulk::parent_in_batch(&rows, i) {
+            let req = bulk::domain_request(row, zone);
+            errors[i] = check_domain(&req, selected).await.err().map(|e| e.to_string());
+        }
+    }
+
+    let mut stopped = stop_on_error && errors.iter().any(Option::is_some);
+    let mut results = vec![];
+
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(error) = errors[i].take() {
+            results.push(RowResult::new(i, row, RowStatus::Invalid, Some(error)));
+            continue;
+        }
+        if stopped {
+            results.push(RowResult::new(i, row, RowStatus::Skipped, None));
+            continue;
+        }
+
+        let mut result = RowResult::new(i, row, RowStatus::Created, None);
+        if let Err(e) = provision_row(row, zone, selected, &mut result.slugs).await {
+            result.status = RowStatus::Failed;
+            result.error = Some(e.to_string());
+            stopped = stop_on_error;
+        }
+        results.push(result);
+    }
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Bulk provisioning finished",
+        "created": bulk::count(&results, RowStatus::Created),
+        "invalid": bulk::count(&results, RowStatus::Invalid),
+        "failed": bulk::count(&results, RowStatus::Failed),
+        "skipped": bulk::count(&results, RowStatus::Skipped),
+        "data": results
+    })))
+}
+
+/// Creates one bulk row's domain and then its slug pages, recording each
+/// slug in `added` as it goes.
+async fn provision_row(
+    row: &BulkRow,
+    zone_name: Option<&str>,
+    zone: &Zone,
+    added: &mut Vec<String>,
+) -> Result<(), ErrorKind> {
+    let req = bulk::domain_request(row, zone_name);
+    let options = check_domain(&req, zone).await?;
+    provision_domain(&req, &options, zone).await?;
+
+    for slug in &row.slugs {
+        let (row, added_slug, cfg) = (row.clone(), slug.clone(), zone.config.clone());
+        updater::blocking(move || {
+            updater::add_slug_page(
+                &row.user_id,
+                &row.business_id,
+                &added_slug.slug,
+                &added_slug.site_id,
+                &cfg,
+            )
+        })
+        .await
+        .map_err(|e| ErrorKind::Error(format!("Slug {}: {}", slug.slug, e)))?;
+        added.push(slug.slug.clone());
+    }
+
+    Ok(())
+}
+
 #[delete("/domain", data = "<req>")]
 pub async fn delete_domain_endpoint(
     req: Json<SubdomainRequest>,
-    cfg: &State<Config>,
-    cloudflare: &State<Cloudflare>,
-) -> Result<Json<JsonValue>, Status> {
-    if !(cloudflare
-        .check_exists(&req.subdomain)
-        .await
-        .map_err(|_| Status::InternalServerError)?)
-    {
-        return Err(Status::NotFound);
+    credentials: &State<CredentialStore>,
+    custom_domains: &State<CustomDomainStore>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(req.zone.as_deref())?;
+    let (cfg, provider) = (&zone.config, zone.provider.as_ref());
+
+    if !provider.check_exists(&req.subdomain).await? {
+        return Err(ErrorKind::NotFound);
     }
 
-    match cloudflare.delete_subdomain_dns_record(&req.subdomain).await {
-        Ok(_) => {}
-        Err(_) => return Err(Status::InternalServerError),
+    let hostname = format!("{}.{}", req.subdomain, cfg.dns_suffix);
+    // Nested hostnames would be left without their parent.
+    if let Some(site) = updater::find_nested_sites(&hostname, cfg)?.first() {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} still has {} nested under it, delete that first",
+            hostname,
+            site.server_names.join(" ")
+        )));
     }
 
-    updater::delete_domain(&req.user_id, &req.business_id)
-        .map_err(|_| Status::InternalServerError)?;
+    let provisioning = PROVISIONING.read().await;
+    provider.delete_subdomain_dns_record(&req.subdomain).await?;
+    credentials.revoke(&hostname).await?;
+
+    let (user_id, business_id) = (req.user_id.clone(), req.business_id.clone());
+    let (subdomain, site_cfg) = (req.subdomain.clone(), cfg.clone());
+    updater::blocking(move || updater::delete_domain(&user_id, &business_id, &subdomain, &site_cfg))
+        .await?;
+    drop(provisioning);
+    custom_domains
+        .remove_all(&req.subdomain, &cfg.dns_suffix)
+        .await?;
     Ok(Json(json!({
         "status": 200,
         "message": "Domain deleted successfully"
@@ -233,16 +459,19 @@ pub async fn delete_domain_endpoint(
 #[post("/slug", data = "<req>")]
 pub async fn add_slug_page_endpoint(
     req: Json<SlugRequest>,
-    cfg: &State<Config>,
-    cloudflare: &State<Cloudflare>,
+    zones: &State<Zones>,
 ) -> Result<Json<JsonValue>, Status> {
-    updater::add_slug_page(
-        &req.user_id,
-        &req.business_id,
-        &req.slug,
-        &req.site_id,
-        &cfg.base_url,
-    )
+    let cfg = zones
+        .select(req.zone.as_deref())
+        .map_err(|_| Status::NotFound)?
+        .config
+        .clone();
+
+    let req = req.into_inner();
+    updater::blocking(move || {
+        updater::add_slug_page(&req.user_id, &req.business_id, &req.slug, &req.site_id, &cfg)
+    })
+    .await
     .map_err(|_| Status::InternalServerError)?;
     Ok(Json(json!({
         "status": 200,
@@ -253,16 +482,27 @@ pub async fn add_slug_page_endpoint(
 #[put("/slug", data = "<req>")]
 pub async fn update_slug_page_endpoint(
     req: Json<SlugRequest>,
-    cfg: &State<Config>,
+    zones: &State<Zones>,
 ) -> Result<Json<JsonValue>, Status> {
-    updater::update_slug_page(
-        &req.user_id,
-        &req.business_id,
-        &req.slug,
-        &req.previous_slug,
-        &req.site_id,
-        req.rewrite_target.as_deref(),
-    )
+    let cfg = zones
+        .select(req.zone.as_deref())
+        .map_err(|_| Status::NotFound)?
+        .config
+        .clone();
+
+    let req = req.into_inner();
+    updater::blocking(move || {
+        updater::update_slug_page(
+            &req.user_id,
+            &req.business_id,
+            &req.slug,
+            &req.previous_slug,
+            &req.site_id,
+            req.rewrite_target.as_deref(),
+            &cfg,
+        )
+    })
+    .await
     .map_err(|_| Status::InternalServerError)?;
     Ok(Json(json!({
         "status": 200,
@@ -273,36 +513,898 @@ pub async fn update_slug_page_endpoint(
 #[delete("/slug", data = "<req>")]
 pub async fn delete_slug_page_endpoint(
     req: Json<SlugRequest>,
-    cfg: &State<Config>,
-    cloudflare: &State<Cloudflare>,
+    zones: &State<Zones>,
 ) -> Result<Json<JsonValue>, Status> {
-    updater::delete_slug_page(&req.user_id, &req.business_id, &req.slug)
-        .map_err(|_| Status::InternalServerError)?;
+    let cfg = zones
+        .select(req.zone.as_deref())
+        .map_err(|_| Status::NotFound)?
+        .config
+        .clone();
+
+    let req = req.into_inner();
+    updater::blocking(move || {
+        updater::delete_slug_page(&req.user_id, &req.business_id, &req.slug, &cfg)
+    })
+    .await
+    .map_err(|_| Status::InternalServerError)?;
     Ok(Json(json!({
         "status": 200,
         "message": "Slug page deleted successfully"
     })))
 }
 
+/// Reads an uploaded body of at most [`UPLOAD_LIMIT`] MiB.
+async fn read_upload(data: Data<'_>) -> Result<String, ErrorKind> {
+    let body = data.open(UPLOAD_LIMIT.mebibytes()).into_string().await?;
+    if !body.is_complete() {
+        return Err(ErrorKind::ValidationError(format!(
+            "Uploads are limited to {} MiB",
+            UPLOAD_LIMIT
+        )));
+    }
+
+    Ok(body.into_inner())
+}
+
+/// A nested hostname like `blog.alice` may only be created by the user whose
+/// site already serves its parent `alice`.
+async fn authorize_nested_host(
+    provider: &dyn DnsProvider,
+    user_id: &str,
+    parent: &str,
+    cfg: &Config,
+) -> Result<(), ErrorKind> {
+    if !provider.check_exists(parent).await? {
+        return Err(ErrorKind::NotFound);
+    }
+
+    match updater::find_site(&format!("{}.{}", parent, cfg.dns_suffix), cfg)? {
+        Some(site) if site.user_id == user_id => Ok(()),
+        _ => Err(ErrorKind::Forbidden),
+    }
+}
+
+async fn authorize_subdomain(
+    writer: &Writer<String>,
+    key: &ApiKey,
+    subdomain: &str,
+) -> Result<User, ErrorKind> {
+    let user = match writer.find(&key.0).await? {
+        Some(user) => user,
+        None => return Err(ErrorKind::NotFound),
+    };
+
+    if !owns_subdomain(&user.subdomain_claim, subdomain) {
+        return Err(ErrorKind::Forbidden);
+    }
+
+    Ok(user)
+}
+
+/// Owners manage their records only where the provider implements the
+/// generic record methods.
+fn record_provider(zone: &Zone) -> Result<&dyn DnsProvider, ErrorKind> {
+    if !zone.provider.manages_records() {
+        return Err(ErrorKind::NotImplemented(format!(
+            "Managing records is not supported by the {:?} provider of {}",
+            zone.config.dns_provider, zone.config.dns_suffix
+        )));
+    }
+    Ok(zone.provider.as_ref())
+}
+
+/// The address records of a subdomain, of its wildcard and of any hostname a
+/// site serves point at the origin; floy-dns keeps those itself.
+fn check_not_origin(record: &DnsRecord, subdomain: &str, cfg: &Config) -> Result<(), ErrorKind> {
+    if !ADDRESS_RECORD_TYPES.contains(&record.record_type.as_str()) {
+        return Ok(());
+    }
+
+    let hostname = record.name.trim_start_matches("*.");
+    if hostname == format!("{}.{}", subdomain, cfg.dns_suffix)
+        || updater::find_site(hostname, cfg)?.is_some()
+    {
+        return Err(ErrorKind::ValidationError(format!(
+            "The address records of {} are managed by floy-dns",
+            hostname
+        )));
+    }
+
+    Ok(())
+}
+
+fn prepare_record(
+    record: DnsRecord,
+    subdomain: &str,
+    cfg: &Config,
+) -> Result<DnsRecord, ErrorKind> {
+    let mut record = DnsRecord {
+        id: None,
+        record_type: record.record_type.to_uppercase(),
+        ..record
+    };
+
+    record.name = qualify_record_name(&record.name, subdomain, &cfg.dns_suffix)?;
+
+    validate_record(&record)?;
+    check_not_origin(&record, subdomain, cfg)?;
+
+    Ok(record)
+}
+
+async fn find_owned_record(
+    provider: &dyn DnsProvider,
+    id: &str,
+    subdomain: &str,
+    cfg: &Config,
+) -> Result<DnsRecord, ErrorKind> {
+    let record = provider.get_record(id).await?;
+
+    if qualify_record_name(&record.name, subdomain, &cfg.dns_suffix).is_err() {
+        return Err(ErrorKind::Forbidden);
+    }
+
+    Ok(record)
+}
+
+#[get("/zones")]
+pub async fn list_zones_endpoint(zones: &State<Zones>) -> Json<JsonValue> {
+    let suffixes: Vec<&str> = zones
+        .iter()
+        .map(|zone| zone.config.dns_suffix.as_str())
+        .collect();
+
+    Json(json!({
+        "status": 200,
+        "message": "Zones found",
+        "default": zones.default_zone().config.dns_suffix,
+        "data": suffixes
+    }))
+}
+
+#[get("/domain/<sub>/records?<zone>")]
+pub async fn list_records_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let provider = zones.select(zone)?.provider.as_ref();
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let records = provider.list_subdomain_records(sub).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Records found",
+        "data": records
+    })))
+}
+
+#[post("/domain/<sub>/records?<zone>", data = "<req>")]
+pub async fn create_record_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    req: Json<DnsRecord>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let (cfg, provider) = (&zone.config, record_provider(zone)?);
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let record = prepare_record(req.into_inner(), sub, cfg)?;
+
+    let id = provider.create_record(&record).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Record created successfully",
+        "id": id
+    })))
+}
+
+#[put("/domain/<sub>/records/<id>?<zone>", data = "<req>")]
+pub async fn update_record_endpoint(
+    sub: &str,
+    id: &str,
+    zone: Option<&str>,
+    req: Json<DnsRecord>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let (cfg, provider) = (&zone.config, record_provider(zone)?);
+    authorize_subdomain(writer, &key, sub).await?;
+    let existing = find_owned_record(provider, id, sub, cfg).await?;
+    check_not_origin(&existing, sub, cfg)?;
+
+    let record = prepare_record(req.into_inner(), sub, cfg)?;
+
+    provider.update_record(id, &record).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Record updated successfully"
+    })))
+}
+
+#[delete("/domain/<sub>/records/<id>?<zone>")]
+pub async fn delete_record_endpoint(
+    sub: &str,
+    id: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let (cfg, provider) = (&zone.config, record_provider(zone)?);
+    authorize_subdomain(writer, &key, sub).await?;
+    let existing = find_owned_record(provider, id, sub, cfg).await?;
+    check_not_origin(&existing, sub, cfg)?;
+
+    provider.delete_record(id).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Record deleted successfully"
+    })))
+}
+
+#[patch("/domain/<sub>/records/<id>/proxied?<zone>", data = "<req>")]
+pub async fn set_proxied_endpoint(
+    sub: &str,
+    id: &str,
+    zone: Option<&str>,
+    req: Json<ProxiedRequest>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let (cfg, provider) = (&zone.config, record_provider(zone)?);
+    authorize_subdomain(writer, &key, sub).await?;
+    let record = find_owned_record(provider, id, sub, cfg).await?;
+
+    if req.proxied {
+        validate_ttl_and_proxied(&record.record_type, DnsRecord::automatic_ttl(), true)?;
+    }
+
+    let record = provider.set_proxied(id, req.proxied).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": if record.proxied { "Record is now proxied" } else { "Record is now DNS only" },
+        "data": record
+    })))
+}
+
+#[post("/domain/<sub>/ddns?<zone>")]
+pub async fn issue_ddns_secret_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    credentials: &State<CredentialStore>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    authorize_subdomain(writer, &key, sub).await?;
+
+    if !zone.provider.check_exists(sub).await? {
+        return Err(ErrorKind::NotFound);
+    }
+
+    let hostname = format!("{}.{}", sub, zone.config.dns_suffix);
+    let secret = credentials.issue(&hostname).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Update secret issued, it will not be shown again",
+        "hostname": hostname,
+        "secret": secret
+    })))
+}
+
+#[delete("/domain/<sub>/ddns?<zone>")]
+pub async fn revoke_ddns_secret_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    credentials: &State<CredentialStore>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let hostname = format!("{}.{}", sub, zone.config.dns_suffix);
+    if !credentials.revoke(&hostname).await? {
+        return Err(ErrorKind::NotFound);
+    }
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Update secret revoked"
+    })))
+}
+
+#[get("/domain/<sub>/custom?<zone>")]
+pub async fn list_custom_domains_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    custom_domains: &State<CustomDomainStore>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let domains = custom_domains.list(sub, &zone.config.dns_suffix).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Custom domains found",
+        "data": domains
+    })))
+}
+
+/// Starts attaching a custom domain to the subdomain's site. The domain is
+/// only served once its TXT record has been verified.
+#[post("/domain/<sub>/custom?<zone>", data = "<req>")]
+pub async fn add_custom_domain_endpoint(
+    sub: &str,
+    zone: Option<&str>,
+    req: Json<CustomDomainRequest>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    custom_domains: &State<CustomDomainStore>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let selected = zones.select(zone)?;
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let hostname = req.hostname.trim().trim_end_matches('.').to_lowercase();
+    if !validate_hostname(&hostname) || !hostname.contains('.') {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} is not a valid hostname",
+            hostname
+        )));
+    }
+    if zones.for_hostname(&hostname).is_some() || zones.select(Some(&hostname)).is_ok() {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} belongs to a zone managed by floy-dns",
+            hostname
+        )));
+    }
+
+    let suffix = &selected.config.dns_suffix;
+    let site = match updater::find_site(&format!("{}.{}", sub, suffix), &selected.config)? {
+        Some(site) => site,
+        None => return Err(ErrorKind::NotFound),
+    };
+
+    let domain = custom_domains
+        .add(CustomDomain::new(
+            &hostname,
+            &site.user_id,
+            &site.business_id,
+            sub,
+            suffix,
+        ))
+        .await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Publish the TXT record and then verify the domain",
+        "data": {
+            "hostname": domain.hostname,
+            "type": "TXT",
+            "name": domain.txt_name(),
+            "value": domain.txt_value()
+        }
+    })))
+}
+
+/// Checks the domain's TXT record and, once it is published, adds the
+/// domain to the site's `server_name`. With ACME configured the site's
+/// certificate is then reissued to cover it.
+#[post("/domain/<sub>/custom/<hostname>/verify?<zone>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_custom_domain_endpoint(
+    sub: &str,
+    hostname: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    custom_domains: &State<CustomDomainStore>,
+    zones: &State<Zones>,
+    certificates: &State<Certificates>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let cfg = &zone.config;
+    let domain = match custom_domains.get(hostname).await? {
+        Some(domain) if domain.subdomain == sub && domain.zone == cfg.dns_suffix => domain,
+        _ => return Err(ErrorKind::NotFound),
+    };
+
+    if !custom_domains::check_verification(&domain, &cfg.txt_resolver).await? {
+        return Err(ErrorKind::ValidationError(format!(
+            "TXT record {} with value {} not found",
+            domain.txt_name(),
+            domain.txt_value()
+        )));
+    }
+
+    let (verified, site_cfg) = (domain.clone(), cfg.clone());
+    updater::blocking(move || {
+        updater::add_server_name(
+            &verified.user_id,
+            &verified.business_id,
+            &verified.hostname,
+            &site_cfg,
+        )
+    })
+    .await?;
+    custom_domains.mark_verified(&domain.hostname).await?;
+    if cfg.acme_enabled() {
+        certificates.start(zone.clone(), &domain.user_id, &domain.business_id, None);
+    }
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Custom domain verified",
+        "domain": domain.hostname
+    })))
+}
+
+#[delete("/domain/<sub>/custom/<hostname>?<zone>")]
+pub async fn delete_custom_domain_endpoint(
+    sub: &str,
+    hostname: &str,
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    custom_domains: &State<CustomDomainStore>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let cfg = &zones.select(zone)?.config;
+    authorize_subdomain(writer, &key, sub).await?;
+
+    let domain = match custom_domains.get(hostname).await? {
+        Some(domain) if domain.subdomain == sub && domain.zone == cfg.dns_suffix => domain,
+        _ => return Err(ErrorKind::NotFound),
+    };
+
+    if domain.verified {
+        let (removed, site_cfg) = (domain.clone(), cfg.clone());
+        updater::blocking(move || {
+            updater::remove_server_name(
+                &removed.user_id,
+                &removed.business_id,
+                &removed.hostname,
+                &site_cfg,
+            )
+        })
+        .await?;
+    }
+    custom_domains.remove(&domain.hostname).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Custom domain removed"
+    })))
+}
+
+/// dyndns2 protocol as spoken by ddclient and most routers. The update secret
+/// is the basic auth password and `hostname` may list several hosts.
+#[get("/nic/update?<hostname>&<myip>")]
+pub async fn dyndns2_update_endpoint(
+    hostname: Option<&str>,
+    myip: Option<&str>,
+    auth: Option<BasicAuth>,
+    client_ip: Option<IpAddr>,
+    credentials: &State<CredentialStore>,
+    zones: &State<Zones>,
+) -> String {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return "badauth".to_string(),
+    };
+
+    let ip = match myip.filter(|ip| !ip.is_empty()) {
+        Some(ip) => ip.parse::<IpAddr>().ok(),
+        None => client_ip,
+    };
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return "911".to_string(),
+    };
+
+    let hostnames: Vec<&str> = hostname
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .collect();
+
+    if hostnames.is_empty() {
+        return "notfqdn".to_string();
+    }
+
+    let mut lines = vec![];
+    for hostname in hostnames {
+        let result = authenticated_update(zones, credentials, hostname, &auth.password, ip).await;
+
+        lines.push(match result {
+            Ok(UpdateOutcome::Good(ip)) => format!("good {}", ip),
+            Ok(UpdateOutcome::NoChange(ip)) => format!("nochg {}", ip),
+            Err(UpdateError::NotFqdn) => "notfqdn".to_string(),
+            Err(UpdateError::BadAuth) => "badauth".to_string(),
+            Err(UpdateError::NoHost) => "nohost".to_string(),
+            Err(UpdateError::Failed(e)) => {
+                error!("Dynamic DNS update of {} failed: {}", hostname, e);
+                "911".to_string()
+            }
+        });
+    }
+
+    lines.join("\n")
+}
+
+/// DuckDNS protocol: `domains` is a comma separated list sharing one `token`,
+/// and the answer is a single `OK` or `KO`.
+#[get("/duckdns/update?<query..>")]
+pub async fn duckdns_update_endpoint(
+    query: DuckDnsQuery,
+    client_ip: Option<IpAddr>,
+    credentials: &State<CredentialStore>,
+    zones: &State<Zones>,
+) -> &'static str {
+    let mut ips = vec![];
+    match query.ip.as_deref().filter(|ip| !ip.is_empty()) {
+        Some(ip) => ips.push(ip.parse::<IpAddr>().ok()),
+        None => ips.push(client_ip),
+    }
+    if let Some(ipv6) = query.ipv6.as_deref().filter(|ip| !ip.is_empty()) {
+        ips.push(ipv6.parse::<IpAddr>().ok());
+    }
+
+    let ips: Vec<IpAddr> = match ips.into_iter().collect::<Option<_>>() {
+        Some(ips) => ips,
+        None => return "KO",
+    };
+
+    for domain in query
+        .domains
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        for ip in &ips {
+            let result = authenticated_update(zones, credentials, domain, &query.token, *ip).await;
+
+            if let Err(e) = result {
+                if let UpdateError::Failed(e) = e {
+                    error!("Dynamic DNS update of {} failed: {}", domain, e);
+                }
+                return "KO";
+            }
+        }
+    }
+
+    "OK"
+}
+
+#[get("/admin/records?<filter..>")]
+pub async fn list_zone_records_endpoint(
+    filter: RecordFilter,
+    _admin: AdminKey,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let provider = zones.select(filter.zone.as_deref())?.provider.as_ref();
+
+    let records = provider
+        .list_records(filter.record_type.as_deref(), filter.suffix.as_deref())
+        .await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Records found",
+        "count": records.len(),
+        "data": records
+    })))
+}
+
+/// Renders the managed records of the zone, i.e. those under any claimed
+/// subdomain, as a BIND master file.
+#[get("/admin/zone/export?<zone>")]
+pub async fn export_zone_endpoint(
+    zone: Option<&str>,
+    _admin: AdminKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<(ContentType, String), ErrorKind> {
+    let zone = zones.select(zone)?;
+    let claims: Vec<String> = writer
+        .all()
+        .await?
+        .into_iter()
+        .map(|u| u.subdomain_claim)
+        .collect();
+
+    export_zone(zone, &claims).await
+}
+
+/// Same as [`export_zone_endpoint`] but limited to the caller's subdomain.
+#[get("/zone/export?<zone>")]
+pub async fn export_user_zone_endpoint(
+    zone: Option<&str>,
+    key: ApiKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<(ContentType, String), ErrorKind> {
+    let zone = zones.select(zone)?;
+    let user = match writer.find(&key.0).await? {
+        Some(user) => user,
+        None => return Err(ErrorKind::NotFound),
+    };
+
+    export_zone(zone, &[user.subdomain_claim]).await
+}
+
+async fn export_zone(zone: &Zone, claims: &[String]) -> Result<(ContentType, String), ErrorKind> {
+    let suffix = &zone.config.dns_suffix;
+    let records = zone.provider.list_records(None, Some(suffix)).await?;
+    let records = zonefile::managed_records(records, suffix, claims);
+
+    Ok((ContentType::Plain, zonefile::render(suffix, &records)))
+}
+
+/// Imports a BIND zone file sent as the request body, or as the `zone_file`
+/// of a JSON body whose `owners` map claimed subdomains to their user and
+/// business. Only the plan is returned unless `apply=true` is passed.
+#[post("/admin/zone/import?<zone>&<apply>", data = "<data>")]
+pub async fn import_zone_endpoint(
+    zone: Option<&str>,
+    apply: Option<bool>,
+    content_type: Option<&ContentType>,
+    data: Data<'_>,
+    _admin: AdminKey,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+
+    let body = read_upload(data).await?;
+    let req = if content_type.is_some_and(|ct| ct.is_json()) {
+        let req: importer::ImportRequest = rocket::serde::json::from_str(&body)
+            .map_err(|e| ErrorKind::ValidationError(format!("Invalid import: {}", e)))?;
+        importer::validate_owners(&req.owners)?;
+        req
+    } else {
+        importer::ImportRequest {
+            zone_file: body,
+            owners: vec![],
+        }
+    };
+
+    let dry_run = !apply.unwrap_or(false);
+    let (cfg, provider) = (&zone.config, zone.provider.as_ref());
+    let report = importer::import(provider, cfg, &req.zone_file, &req.owners, dry_run).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": if dry_run { "Import planned" } else { "Import finished" },
+        "data": report
+    })))
+}
+
+/// Hit rate of each zone's record lookup cache.
+#[get("/admin/cache")]
+pub async fn cache_stats_endpoint(_admin: AdminKey, zones: &State<Zones>) -> Json<JsonValue> {
+    let stats: Vec<JsonValue> = zones
+        .iter()
+        .map(|zone| {
+            json!({
+                "zone": zone.config.dns_suffix,
+                "cache": zone.provider.cache_stats()
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "status": 200,
+        "message": "Cache statistics",
+        "data": stats
+    }))
+}
+
+#[get("/admin/reconcile?<zone>")]
+pub async fn drift_report_endpoint(
+    zone: Option<&str>,
+    _admin: AdminKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let report = reconcile(zone.provider.as_ref(), zones, writer, &zone.config, false).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": if report.has_drift() { "Drift detected" } else { "No drift" },
+        "data": report
+    })))
+}
+
+#[post("/admin/reconcile?<zone>")]
+pub async fn reconcile_endpoint(
+    zone: Option<&str>,
+    _admin: AdminKey,
+    writer: &State<Writer<String>>,
+    zones: &State<Zones>,
+) -> Result<Json<JsonValue>, ErrorKind> {
+    let zone = zones.select(zone)?;
+    let report = reconcile(zone.provider.as_ref(), zones, writer, &zone.config, true).await?;
+
+    Ok(Json(json!({
+        "status": 200,
+        "message": "Reconciliation finished",
+        "data": report
+    })))
+}
+
 #[options("/<_..>")]
 fn handle_cors() -> Status {
     Status::Ok
 }
 
 pub async fn build_endpoints() -> Rocket<Build> {
-    rocket::build().mount(
-        "/api",
-        routes![
-            handle_cors,
-            register,
-            auth,
-            whoami,
-            create_domain_endpoint,
-            delete_domain_endpoint,
-            add_slug_page_endpoint,
-            update_slug_page_endpoint,
-            delete_slug_page_endpoint,
-            verify_account,
-        ],
-    )
+    rocket::build()
+        .mount(
+            "/api",
+            routes![
+                handle_cors,
+                register,
+                auth,
+                whoami,
+                create_domain_endpoint,
+                propagation_endpoint,
+                bulk_provision_endpoint,
+                delete_domain_endpoint,
+                add_slug_page_endpoint,
+                update_slug_page_endpoint,
+                delete_slug_page_endpoint,
+                list_zones_endpoint,
+                list_records_endpoint,
+                create_record_endpoint,
+                update_record_endpoint,
+                delete_record_endpoint,
+                set_proxied_endpoint,
+                issue_ddns_secret_endpoint,
+                revoke_ddns_secret_endpoint,
+                list_custom_domains_endpoint,
+                add_custom_domain_endpoint,
+                verify_custom_domain_endpoint,
+                delete_custom_domain_endpoint,
+                list_zone_records_endpoint,
+                export_zone_endpoint,
+                export_user_zone_endpoint,
+                import_zone_endpoint,
+                cache_stats_endpoint,
+                drift_report_endpoint,
+                reconcile_endpoint,
+                verify_account,
+            ],
+        )
+        .mount(
+            "/",
+            routes![dyndns2_update_endpoint, duckdns_update_endpoint],
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::localdns::localdns::{LocalDns, LocalZone};
+
+    #[tokio::test]
+    async fn test_failed_site_removes_records() {
+        let dir = std::env::temp_dir().join(format!("floy-provision-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config {
+            dns_suffix: "floy.id".to_string(),
+            ip: "10.0.0.1".to_string(),
+            ipv6: Some("2001:db8::1".to_string()),
+            prefix: "/srv/sites".to_string(),
+            sites_available_dir: dir.join("available").display().to_string(),
+            sites_enabled_dir: dir.join("enabled").display().to_string(),
+            ..Default::default()
+        };
+        let local = Arc::new(
+            LocalZone::load("floy.id", config.nameservers(), vec![], None)
+                .await
+                .unwrap(),
+        );
+        let zone = Zone {
+            provider: Arc::new(LocalDns::from_zone(config.clone(), local.clone())),
+            config,
+        };
+
+        // The business already has a site, so no second one is written.
+        updater::create_domain("u1", "b1", "alice", false, &zone.config).unwrap();
+        let req = SubdomainRequest {
+            user_id: "u1".to_string(),
+            business_id: "b1".to_string(),
+            subdomain: "bob".to_string(),
+            wildcard: false,
+            ttl: None,
+            proxied: None,
+            zone: None,
+        };
+        let options = RecordOptions::default();
+
+        let err = provision_domain(&req, &options, &zone).await.unwrap_err();
+        assert!(matches!(err, ErrorKind::RecordAlreadyExists(_)));
+        assert!(local.records().iter().all(|r| r.name != "bob.floy.id"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_origin_records_are_not_self_service() {
+        let dir = std::env::temp_dir().join(format!("floy-origin-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = Config {
+            dns_suffix: "floy.id".to_string(),
+            prefix: "/srv/sites".to_string(),
+            sites_available_dir: dir.join("available").display().to_string(),
+            sites_enabled_dir: dir.join("enabled").display().to_string(),
+            ..Default::default()
+        };
+        updater::create_domain("u1", "b2", "blog.alice", false, &cfg).unwrap();
+        let record = |record_type: &str, name: &str| {
+            let content = if record_type == "TXT" { "hello" } else { "10.0.0.9" };
+            DnsRecord::new(
+                record_type.to_string(),
+                name.to_string(),
+                1,
+                content.to_string(),
+                false,
+            )
+        };
+
+        for name in ["alice.floy.id", "*.alice.floy.id", "blog.alice.floy.id"] {
+            let err = check_not_origin(&record("A", name), "alice", &cfg).unwrap_err();
+            assert!(matches!(err, ErrorKind::ValidationError(_)), "{}", name);
+        }
+        check_not_origin(&record("A", "www.alice.floy.id"), "alice", &cfg).unwrap();
+        check_not_origin(&record("TXT", "alice.floy.id"), "alice", &cfg).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_records_need_a_managing_provider() {
+        let config = Config {
+            dns_provider: crate::config::ProviderKind::PowerDns,
+            dns_suffix: "floy.id".to_string(),
+            ..Default::default()
+        };
+        let zone = Zone {
+            provider: crate::provider::provider::build_provider(&config).await,
+            config,
+        };
+
+        let err = record_provider(&zone).err().unwrap();
+        assert_eq!(Status::NotImplemented, err.status());
+    }
 }
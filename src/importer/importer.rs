@@ -0,0 +1,536 @@
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::common::errors::ErrorKind;
+use crate::common::records::validate_record;
+use crate::config::Config;
+use crate::models::DnsRecord;
+use crate::provider::provider::DnsProvider;
+use crate::reconciler::reconciler::claim_of;
+use crate::updater::updater::{self, Site};
+use crate::zonefile::zonefile;
+use crate::zones::zones::Zones;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Action {
+    Create,
+    Skip,
+    /// Nothing maps the record's subdomain to a user and business yet.
+    NeedsOwner,
+}
+
+/// Maps a claimed subdomain of the imported zone to the user and business
+/// its records belong to.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Owner {
+    pub subdomain: String,
+    pub user_id: String,
+    pub business_id: String,
+}
+
+impl Owner {
+    /// Parses the `<subdomain>=<user_id>/<business_id>` form of `--owner`.
+    pub fn parse(s: &str) -> Option<Owner> {
+        let (subdomain, ids) = s.split_once('=')?;
+        let (user_id, business_id) = ids.split_once('/')?;
+        Some(Owner {
+            subdomain: subdomain.trim().to_string(),
+            user_id: user_id.trim().to_string(),
+            business_id: business_id.trim().to_string(),
+        })
+    }
+}
+
+/// The import endpoint's JSON body, for passing an owner mapping along
+/// with the zone file.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportRequest {
+    pub zone_file: String,
+    #[serde(default)]
+    pub owners: Vec<Owner>,
+}
+
+fn validate_owner(owner: &Owner) -> Result<(), String> {
+    if owner.subdomain.is_empty() || owner.subdomain.contains('.') {
+        return Err(format!("{} is not a claimed subdomain", owner.subdomain));
+    }
+    if owner.user_id.is_empty() || owner.business_id.is_empty() {
+        return Err(format!(
+            "{} needs a user_id and a business_id",
+            owner.subdomain
+        ));
+    }
+    Ok(())
+}
+
+/// Reads an owner mapping sent either as a JSON array or as CSV with a
+/// `subdomain,user_id,business_id` header.
+pub fn parse_owners(body: &str, json: bool) -> Result<Vec<Owner>, ErrorKind> {
+    let owners: Vec<Owner> = if json {
+        rocket::serde::json::from_str(body)
+            .map_err(|e| ErrorKind::ValidationError(format!("Invalid owners: {}", e)))?
+    } else {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body.as_bytes());
+        reader
+            .deserialize::<Owner>()
+            .enumerate()
+            // Line 1 is the header.
+            .map(|(i, owner)| {
+                owner.map_err(|e| ErrorKind::ValidationError(format!("line {}: {}", i + 2, e)))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    validate_owners(&owners)?;
+    Ok(owners)
+}
+
+pub fn validate_owners(owners: &[Owner]) -> Result<(), ErrorKind> {
+    owners
+        .iter()
+        .try_for_each(validate_owner)
+        .map_err(ErrorKind::ValidationError)
+}
+
+/// What the importer intends to do with one record of the zone file.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PlannedRecord {
+    pub action: Action,
+    pub record: DnsRecord,
+    /// Owner of the record's claimed subdomain, from the owner mapping or
+    /// else from the site serving it.
+    pub user_id: Option<String>,
+    pub business_id: Option<String>,
+    /// Why the record is skipped or needs an owner.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportResult {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub created: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub plan: Vec<PlannedRecord>,
+    /// One entry per record created or attempted, empty on dry runs.
+    pub results: Vec<ImportResult>,
+}
+
+impl ImportReport {
+    pub fn count(&self, action: Action) -> usize {
+        self.plan.iter().filter(|p| p.action == action).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|r| !r.created).count()
+    }
+}
+
+fn same_record(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.name == b.name
+        && a.record_type == b.record_type
+        && a.content == b.content
+        && a.priority == b.priority
+        && a.data == b.data
+}
+
+/// Decides for every parsed record whether to create it and on whose
+/// behalf. A record belongs to the user and business `owners` maps its
+/// claimed subdomain to or, failing that, whose site serves the subdomain.
+/// Records without either need an owner before they are imported. Records
+/// that already exist and the zone's own SOA/NS records are skipped.
+pub(crate) fn plan(
+    records: Vec<DnsRecord>,
+    existing: &[DnsRecord],
+    owners: &[Owner],
+    sites: &[Site],
+    suffix: &str,
+) -> Vec<PlannedRecord> {
+    let mut planned: Vec<PlannedRecord> = vec![];
+
+    for record in records {
+        let claim = claim_of(&record.name, suffix);
+        let owner = claim.as_ref().and_then(|claim| {
+            let hostname = format!("{}.{}", claim, suffix);
+            owners
+                .iter()
+                .find(|owner| &owner.subdomain == claim)
+                .map(|owner| (owner.user_id.clone(), owner.business_id.clone()))
+                .or_else(|| {
+                    sites
+                        .iter()
+                        .find(|site| site.server_names.contains(&hostname))
+                        .map(|site| (site.user_id.clone(), site.business_id.clone()))
+                })
+        });
+
+        let reason = if record.record_type == "SOA"
+            || (record.record_type == "NS" && record.name == suffix)
+        {
+            Some("Managed by the DNS provider".to_string())
+        } else {
+            match &claim {
+                None => Some(format!("{} is outside of any subdomain", record.name)),
+                Some(_) => match validate_record(&record) {
+                    Err(e) => Some(e.to_string()),
+                    Ok(_) if existing.iter().any(|r| same_record(r, &record)) => {
+                        Some("Record already exists".to_string())
+                    }
+                    Ok(_)
+                        if planned.iter().any(|p| {
+                            p.action == Action::Create && same_record(&p.record, &record)
+                        }) =>
+                    {
+                        Some("Duplicate of an earlier record".to_string())
+                    }
+                    Ok(_) => None,
+                },
+            }
+        };
+
+        let (action, reason) = match (reason, &owner, &claim) {
+            (Some(reason), _, _) => (Action::Skip, Some(reason)),
+            (None, None, Some(claim)) => (
+                Action::NeedsOwner,
+                Some(format!(
+                    "No owner is mapped for {} and no site serves it",
+                    claim
+                )),
+            ),
+            (None, _, _) => (Action::Create, None),
+        };
+
+        let (user_id, business_id) = owner.unzip();
+        planned.push(PlannedRecord {
+            action,
+            user_id,
+            business_id,
+            reason,
+            record,
+        });
+    }
+
+    planned
+}
+
+/// Parses `zone_file` against the zone `cfg` is scoped to and plans the
+/// import. Unless `dry_run` is set every record planned for creation is then
+/// created, carrying on past failures so the report covers the whole file.
+pub async fn import(
+    provider: &dyn DnsProvider,
+    cfg: &Config,
+    zone_file: &str,
+    owners: &[Owner],
+    dry_run: bool,
+) -> Result<ImportReport, ErrorKind> {
+    let records = zonefile::parse(zone_file, &cfg.dns_suffix)?;
+    let existing = provider.list_records(None, Some(&cfg.dns_suffix)).await?;
+    let sites = updater::list_sites(cfg)?;
+
+    let mut report = ImportReport {
+        dry_run,
+        plan: plan(records, &existing, owners, &sites, &cfg.dns_suffix),
+        results: vec![],
+    };
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    for planned in report.plan.iter().filter(|p| p.action == Action::Create) {
+        let result = provider.create_record(&planned.record).await;
+
+        report.results.push(ImportResult {
+            name: planned.record.name.clone(),
+            record_type: planned.record.record_type.clone(),
+            created: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            id: result.ok(),
+        });
+    }
+
+    Ok(report)
+}
+
+fn describe(record: &DnsRecord) -> String {
+    format!(
+        "{} {} {}",
+        record.name,
+        record.record_type,
+        zonefile::rdata(record)
+    )
+}
+
+const USAGE: &str = "usage: floy-dns import <zone-file> [--zone <suffix>] \
+[--owners <owners.csv|owners.json>] [--owner <subdomain>=<user_id>/<business_id>]... [--apply]";
+
+/// Reads the `--owners` file, JSON when it is named `*.json` and CSV
+/// otherwise.
+fn read_owners(path: &str) -> Result<Vec<Owner>, String> {
+    let body = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    parse_owners(&body, path.ends_with(".json")).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// `floy-dns import`: prints the plan for a zone file and, with `--apply`,
+/// creates the records and prints the outcome of each. Returns the exit code.
+pub async fn run_cli(args: &[String]) -> i32 {
+    let mut path = None;
+    let mut zone = None;
+    let mut owners = vec![];
+    let mut apply = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--apply" => apply = true,
+            "--zone" => zone = args.next().map(String::as_str),
+            "--owners" => match args.next().map(|path| read_owners(path)) {
+                Some(Ok(mapped)) => owners.extend(mapped),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            },
+            "--owner" => match args.next().and_then(|owner| Owner::parse(owner)) {
+                Some(owner) => match validate_owner(&owner) {
+                    Ok(_) => owners.push(owner),
+                    Err(e) => {
+                        eprintln!("--owner: {}", e);
+                        return 2;
+                    }
+                },
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    let zone_file = match std::fs::read_to_string(path) {
+        Ok(zone_file) => zone_file,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", path, e);
+            return 1;
+        }
+    };
+
+    let zones = Zones::new(&Config::new()).await;
+    let result = match zones.select(zone) {
+        Ok(zone) => {
+            import(
+                zone.provider.as_ref(),
+                &zone.config,
+                &zone_file,
+                &owners,
+                !apply,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Import failed: {}", e);
+            return 1;
+        }
+    };
+
+    for planned in &report.plan {
+        match planned.action {
+            Action::Create => println!(
+                "create  {}  ({}/{})",
+                describe(&planned.record),
+                planned.user_id.as_deref().unwrap_or_default(),
+                planned.business_id.as_deref().unwrap_or_default()
+            ),
+            Action::Skip => println!(
+                "skip    {}  ({})",
+                describe(&planned.record),
+                planned.reason.as_deref().unwrap_or_default()
+            ),
+            Action::NeedsOwner => println!(
+                "needs owner  {}  ({})",
+                describe(&planned.record),
+                planned.reason.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    if report.dry_run {
+        println!(
+            "\n{} to create, {} need an owner, {} skipped. \
+             Dry run, pass --apply to create the records.",
+            report.count(Action::Create),
+            report.count(Action::NeedsOwner),
+            report.count(Action::Skip)
+        );
+        return 0;
+    }
+
+    println!();
+    for result in &report.results {
+        match &result.error {
+            None => println!(
+                "ok      {} {} ({})",
+                result.name,
+                result.record_type,
+                result.id.as_deref().unwrap_or_default()
+            ),
+            Some(e) => println!("failed  {} {}: {}", result.name, result.record_type, e),
+        }
+    }
+
+    println!(
+        "\n{} created, {} failed, {} need an owner, {} skipped.",
+        report.results.len() - report.failed(),
+        report.failed(),
+        report.count(Action::NeedsOwner),
+        report.count(Action::Skip)
+    );
+
+    if report.failed() > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn record(record_type: &str, name: &str, content: &str) -> DnsRecord {
+        DnsRecord::new(
+            record_type.to_string(),
+            name.to_string(),
+            3600,
+            content.to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_plan() {
+        let sites = vec![Site {
+            user_id: "u1".to_string(),
+            business_id: "b1".to_string(),
+            server_names: vec!["alice.floy.id".to_string()],
+            available_path: PathBuf::new(),
+            enabled_path: PathBuf::new(),
+        }];
+        let existing = vec![record("A", "alice.floy.id", "10.0.0.1")];
+        let records = vec![
+            record("SOA", "floy.id", "ns1.floy.id. admin.floy.id. 1 2 3 4 5"),
+            record("A", "alice.floy.id", "10.0.0.1"),
+            record("A", "www.alice.floy.id", "10.0.0.2"),
+            record("A", "www.alice.floy.id", "10.0.0.2"),
+            record("A", "bob.floy.id", "10.0.0.3"),
+            record("A", "blog.alice.floy.id", "not-an-ip"),
+            record("A", "floy.id", "10.0.0.4"),
+        ];
+
+        let plan = plan(records, &existing, &[], &sites, "floy.id");
+        let actions: Vec<Action> = plan.iter().map(|p| p.action).collect();
+
+        assert_eq!(
+            vec![
+                Action::Skip,
+                Action::Skip,
+                Action::Create,
+                Action::Skip,
+                Action::NeedsOwner,
+                Action::Skip,
+                Action::Skip,
+            ],
+            actions
+        );
+        assert_eq!(Some("u1".to_string()), plan[2].user_id);
+        assert_eq!(Some("b1".to_string()), plan[2].business_id);
+        assert_eq!(Some("Record already exists"), plan[1].reason.as_deref());
+        assert_eq!(
+            Some("No owner is mapped for bob and no site serves it"),
+            plan[4].reason.as_deref()
+        );
+        assert_eq!(None, plan[4].user_id);
+    }
+
+    #[test]
+    fn test_plan_with_owners() {
+        let owners = parse_owners("subdomain,user_id,business_id\nbob,u2,b2\n", false).unwrap();
+        let records = vec![
+            record("A", "bob.floy.id", "10.0.0.3"),
+            record("TXT", "bob.floy.id", "v=spf1 -all"),
+            record("A", "carol.floy.id", "10.0.0.5"),
+        ];
+
+        let plan = plan(records, &[], &owners, &[], "floy.id");
+        let actions: Vec<Action> = plan.iter().map(|p| p.action).collect();
+
+        assert_eq!(
+            vec![Action::Create, Action::Create, Action::NeedsOwner],
+            actions
+        );
+        assert_eq!(Some("u2".to_string()), plan[1].user_id);
+        assert_eq!(Some("b2".to_string()), plan[1].business_id);
+        assert_eq!(None, plan[2].user_id);
+    }
+
+    #[test]
+    fn test_parse_owners() {
+        assert_eq!(
+            Some(Owner {
+                subdomain: "bob".to_string(),
+                user_id: "u2".to_string(),
+                business_id: "b2".to_string(),
+            }),
+            Owner::parse("bob=u2/b2")
+        );
+        assert_eq!(None, Owner::parse("bob=u2"));
+
+        let json = r#"[{"subdomain": "bob", "user_id": "u2", "business_id": "b2"}]"#;
+        assert_eq!(1, parse_owners(json, true).unwrap().len());
+
+        let err = parse_owners("subdomain,user_id\nbob,u2\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        assert!(parse_owners("subdomain,user_id,business_id\nbob.alice,u2,b2\n", false).is_err());
+    }
+}
@@ -0,0 +1,607 @@
+use std::io::ErrorKind as IoErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rocket::serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::common::errors::ErrorKind;
+use crate::config::Config;
+use crate::models::DnsRecord;
+use crate::provider::provider::{
+    record_type_for, DnsProvider, RecordOptions, ADDRESS_RECORD_TYPES,
+};
+
+/// TTL given to records asking for Cloudflare's automatic TTL.
+pub const DEFAULT_TTL: u32 = 300;
+
+/// Record types the built-in server answers for.
+pub const SERVED_RECORD_TYPES: [&str; 4] = ["A", "AAAA", "TXT", "CNAME"];
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+struct ZoneState {
+    /// SOA serial, bumped by every change.
+    serial: u32,
+    records: Vec<DnsRecord>,
+}
+
+/// Records of one zone served by the built-in DNS server. They live in
+/// memory, so the server sees a change as soon as it is made, and are saved
+/// as JSON after every change.
+pub struct LocalZone {
+    origin: String,
+    nameservers: Vec<String>,
+    /// Addresses of nameservers inside the zone that have no record of
+    /// their own.
+    glue: Vec<String>,
+    path: Option<PathBuf>,
+    state: RwLock<ZoneState>,
+    write_lock: Mutex<()>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_lowercase()
+}
+
+fn new_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+impl LocalZone {
+    /// Loads the zone from `path`, starting empty when the file does not
+    /// exist yet. Without a path nothing is saved.
+    pub async fn load(
+        origin: &str,
+        nameservers: Vec<String>,
+        glue: Vec<String>,
+        path: Option<PathBuf>,
+    ) -> Result<Self, std::io::Error> {
+        let state = match &path {
+            Some(path) => match fs::read_to_string(path).await {
+                Ok(content) => rocket::serde::json::from_str(&content)
+                    .map_err(|e| std::io::Error::new(IoErrorKind::InvalidData, e))?,
+                Err(e) if e.kind() == IoErrorKind::NotFound => ZoneState::default(),
+                Err(e) => return Err(e),
+            },
+            None => ZoneState::default(),
+        };
+
+        Ok(LocalZone {
+            origin: normalize(origin),
+            nameservers: nameservers.iter().map(|ns| normalize(ns)).collect(),
+            glue,
+            path,
+            state: RwLock::new(ZoneState {
+                serial: state.serial.max(1),
+                ..state
+            }),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn nameservers(&self) -> &[String] {
+        &self.nameservers
+    }
+
+    pub fn glue(&self) -> &[String] {
+        &self.glue
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.state.read().unwrap().serial
+    }
+
+    /// True when `name` is the origin or below it.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize(name);
+        name == self.origin || name.ends_with(&format!(".{}", self.origin))
+    }
+
+    pub fn records(&self) -> Vec<DnsRecord> {
+        self.state.read().unwrap().records.clone()
+    }
+
+    fn find(&self, name: &str, record_type: &str) -> Option<DnsRecord> {
+        self.state
+            .read()
+            .unwrap()
+            .records
+            .iter()
+            .find(|r| r.name == name && r.record_type == record_type)
+            .cloned()
+    }
+
+    /// Applies `edit` to the records, then bumps the serial and saves the
+    /// zone. Nothing is saved when `edit` fails.
+    async fn edit<T>(
+        &self,
+        edit: impl FnOnce(&mut Vec<DnsRecord>) -> Result<T, ErrorKind>,
+    ) -> Result<T, ErrorKind> {
+        let _guard = self.write_lock.lock().await;
+
+        let (result, content) = {
+            let mut state = self.state.write().unwrap();
+            let mut records = state.records.clone();
+            let result = edit(&mut records)?;
+
+            state.records = records;
+            state.serial = state.serial.wrapping_add(1).max(1);
+            (result, rocket::serde::json::to_string(&*state).unwrap())
+        };
+
+        if let Some(path) = &self.path {
+            fs::write(path, content).await?;
+        }
+
+        Ok(result)
+    }
+}
+
+/// Checks that `record` is something the built-in server can answer with
+/// and returns it with its name normalized and an actual TTL.
+fn validate(zone: &LocalZone, record: &DnsRecord) -> Result<DnsRecord, ErrorKind> {
+    let record_type = record.record_type.to_uppercase();
+    let name = normalize(&record.name);
+
+    if !SERVED_RECORD_TYPES.contains(&record_type.as_str()) {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} records are not supported by the built-in DNS server",
+            record_type
+        )));
+    }
+    if !zone.contains(&name) {
+        return Err(ErrorKind::ValidationError(format!(
+            "{} is not in zone {}",
+            name,
+            zone.origin()
+        )));
+    }
+
+    let valid = match record_type.as_str() {
+        "A" => record.content.parse::<Ipv4Addr>().is_ok(),
+        "AAAA" => record.content.parse::<Ipv6Addr>().is_ok(),
+        "CNAME" => !normalize(&record.content).is_empty() && name != zone.origin(),
+        _ => true,
+    };
+    if !valid {
+        return Err(ErrorKind::ValidationError(format!(
+            "Invalid {} record for {}: {}",
+            record_type, name, record.content
+        )));
+    }
+
+    Ok(DnsRecord {
+        record_type,
+        name,
+        ttl: if record.ttl == DnsRecord::automatic_ttl() {
+            DEFAULT_TTL
+        } else {
+            record.ttl
+        },
+        content: if record.record_type.eq_ignore_ascii_case("CNAME") {
+            normalize(&record.content)
+        } else {
+            record.content.clone()
+        },
+        // Nothing sits in front of the built-in server.
+        proxied: false,
+        priority: None,
+        data: None,
+        ..record.clone()
+    })
+}
+
+/// A CNAME cannot share its name with any other record, and the same record
+/// cannot be added twice.
+fn check_conflicts(records: &[DnsRecord], record: &DnsRecord) -> Result<(), ErrorKind> {
+    let conflict = records.iter().any(|r| {
+        r.name == record.name
+            && r.id != record.id
+            && (r.record_type == "CNAME"
+                || record.record_type == "CNAME"
+                || (r.record_type == record.record_type && r.content == record.content))
+    });
+
+    if conflict {
+        return Err(ErrorKind::RecordAlreadyExists(format!(
+            "{} {}",
+            record.name, record.record_type
+        )));
+    }
+
+    Ok(())
+}
+
+/// [`DnsProvider`] keeping the records itself, for deployments that answer
+/// DNS queries with floy-dns' own server instead of a hosted provider.
+pub struct LocalDns {
+    config: Config,
+    zone: Arc<LocalZone>,
+}
+
+impl LocalDns {
+    pub async fn new(config: Config) -> Self {
+        let path =
+            PathBuf::from(&config.local_zone_dir).join(format!("{}.json", config.dns_suffix));
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await.unwrap();
+        }
+
+        let glue = std::iter::once(config.ip.clone())
+            .chain(config.ipv6.clone())
+            .collect();
+        let zone = LocalZone::load(&config.dns_suffix, config.nameservers(), glue, Some(path))
+            .await
+            .unwrap();
+
+        LocalDns {
+            config,
+            zone: Arc::new(zone),
+        }
+    }
+
+    pub fn from_zone(config: Config, zone: Arc<LocalZone>) -> Self {
+        LocalDns { config, zone }
+    }
+
+    fn fqdn(&self, subdomain: &str, wildcard: bool) -> String {
+        if wildcard {
+            normalize(&format!("*.{}.{}", subdomain, &self.config.dns_suffix))
+        } else {
+            normalize(&format!("{}.{}", subdomain, &self.config.dns_suffix))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl DnsProvider for LocalDns {
+    async fn add_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        ip: &str,
+        options: &RecordOptions,
+    ) -> Result<(), ErrorKind> {
+        let record_type = record_type_for(ip);
+        let name = self.fqdn(subdomain, false);
+
+        if self.zone.find(&name, record_type).is_some() {
+            return Err(ErrorKind::RecordAlreadyExists(name));
+        }
+
+        let mut names = vec![name];
+        if options.wildcard {
+            names.push(self.fqdn(subdomain, true));
+        }
+
+        let mut records = vec![];
+        for name in names {
+            records.push(validate(
+                &self.zone,
+                &DnsRecord::new(
+                    record_type.to_owned(),
+                    name,
+                    options.ttl,
+                    ip.to_owned(),
+                    false,
+                ),
+            )?);
+        }
+
+        self.zone
+            .edit(|existing| {
+                for record in records {
+                    check_conflicts(existing, &record)?;
+                    existing.push(DnsRecord {
+                        id: Some(new_id()),
+                        ..record
+                    });
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        record_type: &str,
+        wildcard: bool,
+    ) -> Result<(String, String), ErrorKind> {
+        self.zone
+            .find(&self.fqdn(subdomain, wildcard), record_type)
+            .map(|r| (r.content, r.id.unwrap_or_default()))
+            .ok_or(ErrorKind::NotFound)
+    }
+
+    async fn update_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        ip: &str,
+    ) -> Result<(), ErrorKind> {
+        let record_type = record_type_for(ip);
+        let names = [self.fqdn(subdomain, false), self.fqdn(subdomain, true)];
+
+        self.zone
+            .edit(|records| {
+                if !records
+                    .iter()
+                    .any(|r| r.name == names[0] && r.record_type == record_type)
+                {
+                    return Err(ErrorKind::NotFound);
+                }
+
+                for record in records
+                    .iter_mut()
+                    .filter(|r| names.contains(&r.name) && r.record_type == record_type)
+                {
+                    record.content = ip.to_owned();
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete_subdomain_dns_record(&self, subdomain: &str) -> Result<(), ErrorKind> {
+        let names = [self.fqdn(subdomain, false), self.fqdn(subdomain, true)];
+
+        self.zone
+            .edit(|records| {
+                let before = records.len();
+                records.retain(|r| {
+                    !(names.contains(&r.name)
+                        && ADDRESS_RECORD_TYPES.contains(&r.record_type.as_str()))
+                });
+
+                if records.len() == before {
+                    Err(ErrorKind::NotFound)
+                } else {
+                    Ok(())
+                }
+            })
+            .await
+    }
+
+    async fn check_exists(&self, subdomain: &str) -> Result<bool, ErrorKind> {
+        let name = self.fqdn(subdomain, false);
+
+        Ok(ADDRESS_RECORD_TYPES
+            .iter()
+            .any(|record_type| self.zone.find(&name, record_type).is_some()))
+    }
+
+    async fn list_records(
+        &self,
+        record_type: Option<&str>,
+        name_suffix: Option<&str>,
+    ) -> Result<Vec<DnsRecord>, ErrorKind> {
+        Ok(self
+            .zone
+            .records()
+            .into_iter()
+            .filter(|r| {
+                record_type.is_none_or(|t| t.eq_ignore_ascii_case(&r.record_type))
+                    && name_suffix.is_none_or(|suffix| r.name.ends_with(suffix))
+            })
+            .collect())
+    }
+
+    async fn list_subdomain_records(&self, subdomain: &str) -> Result<Vec<DnsRecord>, ErrorKind> {
+        let name = self.fqdn(subdomain, false);
+        let nested = format!(".{}", name);
+
+        Ok(self
+            .list_records(None, Some(&name))
+            .await?
+            .into_iter()
+            .filter(|r| r.name == name || r.name.ends_with(&nested))
+            .collect())
+    }
+
+    async fn get_record(&self, id: &str) -> Result<DnsRecord, ErrorKind> {
+        self.zone
+            .records()
+            .into_iter()
+            .find(|r| r.id.as_deref() == Some(id))
+            .ok_or(ErrorKind::NotFound)
+    }
+
+    async fn create_record(&self, record: &DnsRecord) -> Result<String, ErrorKind> {
+        let id = new_id();
+        let record = DnsRecord {
+            id: Some(id.clone()),
+            ..validate(&self.zone, record)?
+        };
+
+        self.zone
+            .edit(|records| {
+                check_conflicts(records, &record)?;
+                records.push(record);
+                Ok(id)
+            })
+            .await
+    }
+
+    async fn update_record(&self, id: &str, record: &DnsRecord) -> Result<(), ErrorKind> {
+        let record = DnsRecord {
+            id: Some(id.to_string()),
+            ..validate(&self.zone, record)?
+        };
+
+        self.zone
+            .edit(|records| {
+                check_conflicts(records, &record)?;
+                let existing = records
+                    .iter_mut()
+                    .find(|r| r.id.as_deref() == Some(id))
+                    .ok_or(ErrorKind::NotFound)?;
+                *existing = record;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete_record(&self, id: &str) -> Result<(), ErrorKind> {
+        self.zone
+            .edit(|records| {
+                let before = records.len();
+                records.retain(|r| r.id.as_deref() != Some(id));
+
+                if records.len() == before {
+                    Err(ErrorKind::NotFound)
+                } else {
+                    Ok(())
+                }
+            })
+            .await
+    }
+
+    fn manages_records(&self) -> bool {
+        true
+    }
+
+    fn local_zone(&self) -> Option<Arc<LocalZone>> {
+        Some(self.zone.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn provider(path: Option<PathBuf>) -> LocalDns {
+        let config = Config {
+            dns_suffix: "floy.id".to_string(),
+            ip: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let zone = LocalZone::load("floy.id", config.nameservers(), vec![], path)
+            .await
+            .unwrap();
+
+        LocalDns::from_zone(config, Arc::new(zone))
+    }
+
+    #[tokio::test]
+    async fn test_subdomain_records() {
+        let dns = provider(None).await;
+        let options = RecordOptions {
+            wildcard: true,
+            ..Default::default()
+        };
+
+        dns.add_subdomain_dns_record("alice", "10.0.0.2", &options)
+            .await
+            .unwrap();
+        assert!(matches!(
+            dns.add_subdomain_dns_record("alice", "10.0.0.2", &options)
+                .await,
+            Err(ErrorKind::RecordAlreadyExists(_))
+        ));
+        assert!(dns.check_exists("alice").await.unwrap());
+
+        dns.update_subdomain_dns_record("alice", "10.0.0.3")
+            .await
+            .unwrap();
+        let (content, _) = dns
+            .get_subdomain_dns_record("alice", "A", true)
+            .await
+            .unwrap();
+        assert_eq!("10.0.0.3", content);
+
+        let records = dns.list_subdomain_records("alice").await.unwrap();
+        assert_eq!(2, records.len());
+        assert!(records.iter().all(|r| r.ttl == DEFAULT_TTL && !r.proxied));
+
+        dns.delete_subdomain_dns_record("alice").await.unwrap();
+        assert!(!dns.check_exists("alice").await.unwrap());
+        assert!(matches!(
+            dns.delete_subdomain_dns_record("alice").await,
+            Err(ErrorKind::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_generic_records() {
+        let dns = provider(None).await;
+        let serial = dns.zone.serial();
+
+        let txt = DnsRecord::new(
+            "TXT".to_string(),
+            "_acme.alice.floy.id".to_string(),
+            120,
+            "token".to_string(),
+            false,
+        );
+        let id = dns.create_record(&txt).await.unwrap();
+        assert_eq!("token", dns.get_record(&id).await.unwrap().content);
+        assert!(dns.zone.serial() > serial);
+
+        let cname = DnsRecord::new(
+            "CNAME".to_string(),
+            "_acme.alice.floy.id".to_string(),
+            120,
+            "elsewhere.example.com.".to_string(),
+            false,
+        );
+        assert!(matches!(
+            dns.create_record(&cname).await,
+            Err(ErrorKind::RecordAlreadyExists(_))
+        ));
+
+        let mx = DnsRecord::new(
+            "MX".to_string(),
+            "alice.floy.id".to_string(),
+            120,
+            "mail.floy.id".to_string(),
+            false,
+        );
+        assert!(matches!(
+            dns.create_record(&mx).await,
+            Err(ErrorKind::ValidationError(_))
+        ));
+
+        let outside = DnsRecord {
+            name: "alice.example.com".to_string(),
+            ..txt.clone()
+        };
+        assert!(matches!(
+            dns.create_record(&outside).await,
+            Err(ErrorKind::ValidationError(_))
+        ));
+
+        dns.delete_record(&id).await.unwrap();
+        assert!(matches!(
+            dns.get_record(&id).await,
+            Err(ErrorKind::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_zone_is_saved() {
+        let path = std::env::temp_dir().join(format!("floy-zone-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let dns = provider(Some(path.clone())).await;
+        dns.add_subdomain_dns_record("bob", "2001:db8::2", &RecordOptions::default())
+            .await
+            .unwrap();
+        let serial = dns.zone.serial();
+
+        let reloaded = provider(Some(path.clone())).await;
+        assert_eq!(serial, reloaded.zone.serial());
+        let (content, _) = reloaded
+            .get_subdomain_dns_record("bob", "AAAA", false)
+            .await
+            .unwrap();
+        assert_eq!("2001:db8::2", content);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
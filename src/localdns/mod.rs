@@ -0,0 +1,2 @@
+pub mod localdns;
+pub mod server;
@@ -0,0 +1,547 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA, CNAME, NS, SOA, TXT};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::localdns::localdns::{LocalZone, DEFAULT_TTL};
+use crate::models::DnsRecord;
+
+/// Payload size advertised to EDNS clients, the DNS flag day 2020 value.
+const EDNS_PAYLOAD: u16 = 1232;
+
+/// Longest CNAME chain followed inside a zone.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How long an idle TCP connection is kept open.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SOA_REFRESH: i32 = 3600;
+const SOA_RETRY: i32 = 600;
+const SOA_EXPIRE: i32 = 604800;
+
+fn fqdn(name: &str) -> Option<Name> {
+    Name::from_ascii(format!("{}.", name.trim_end_matches('.'))).ok()
+}
+
+fn rdata(record: &DnsRecord) -> Option<RData> {
+    match record.record_type.as_str() {
+        "A" => record.content.parse().ok().map(|ip| RData::A(A(ip))),
+        "AAAA" => record.content.parse().ok().map(|ip| RData::AAAA(AAAA(ip))),
+        "CNAME" => fqdn(&record.content).map(|name| RData::CNAME(CNAME(name))),
+        "TXT" => {
+            // Cloudflare style content may come quoted.
+            let content = record
+                .content
+                .strip_prefix('"')
+                .and_then(|c| c.strip_suffix('"'))
+                .filter(|c| !c.contains('"'))
+                .unwrap_or(&record.content);
+            let strings = content
+                .as_bytes()
+                .chunks(255)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect();
+
+            Some(RData::TXT(TXT::new(strings)))
+        }
+        _ => None,
+    }
+}
+
+fn to_record(owner: &Name, record: &DnsRecord) -> Option<Record> {
+    let ttl = if record.ttl == DnsRecord::automatic_ttl() {
+        DEFAULT_TTL
+    } else {
+        record.ttl
+    };
+
+    rdata(record).map(|rdata| Record::from_rdata(owner.clone(), ttl, rdata))
+}
+
+fn soa(zone: &LocalZone) -> Option<Record> {
+    let origin = fqdn(zone.origin())?;
+    let soa = SOA::new(
+        fqdn(zone.nameservers().first()?)?,
+        fqdn(&format!("hostmaster.{}", zone.origin()))?,
+        zone.serial(),
+        SOA_REFRESH,
+        SOA_RETRY,
+        SOA_EXPIRE,
+        DEFAULT_TTL,
+    );
+
+    Some(Record::from_rdata(origin, DEFAULT_TTL, RData::SOA(soa)))
+}
+
+fn ns(zone: &LocalZone) -> Vec<Record> {
+    let origin = match fqdn(zone.origin()) {
+        Some(origin) => origin,
+        None => return vec![],
+    };
+
+    zone.nameservers()
+        .iter()
+        .filter_map(|ns| fqdn(ns))
+        .map(|ns| Record::from_rdata(origin.clone(), DEFAULT_TTL, RData::NS(NS(ns))))
+        .collect()
+}
+
+/// The zone's records plus addresses for its in-zone nameservers that have
+/// none of their own.
+fn zone_records(zone: &LocalZone) -> Vec<DnsRecord> {
+    let mut records = zone.records();
+
+    for ns in zone.nameservers().iter().filter(|ns| zone.contains(ns)) {
+        if records.iter().any(|r| &r.name == ns) {
+            continue;
+        }
+
+        for ip in zone.glue() {
+            let record_type = match ip.parse::<std::net::IpAddr>() {
+                Ok(std::net::IpAddr::V4(_)) => "A",
+                Ok(std::net::IpAddr::V6(_)) => "AAAA",
+                Err(_) => continue,
+            };
+            records.push(DnsRecord::new(
+                record_type.to_string(),
+                ns.clone(),
+                DEFAULT_TTL,
+                ip.clone(),
+                false,
+            ));
+        }
+    }
+
+    records
+}
+
+/// Records at `name`, expanding the closest encloser's wildcard when the
+/// name does not exist. `None` means the name does not exist at all.
+fn node<'a>(zone: &LocalZone, records: &'a [DnsRecord], name: &str) -> Option<Vec<&'a DnsRecord>> {
+    let exists = |name: &str| {
+        name == zone.origin()
+            || records
+                .iter()
+                .any(|r| r.name == name || r.name.ends_with(&format!(".{}", name)))
+    };
+
+    if exists(name) {
+        return Some(records.iter().filter(|r| r.name == name).collect());
+    }
+
+    let mut encloser = name;
+    while let Some((_, parent)) = encloser.split_once('.') {
+        encloser = parent;
+        if exists(encloser) {
+            let wildcard = format!("*.{}", encloser);
+            let matches: Vec<_> = records.iter().filter(|r| r.name == wildcard).collect();
+            return Some(matches).filter(|m| !m.is_empty());
+        }
+    }
+
+    None
+}
+
+/// Builds the authoritative answer to `request` from `zones`.
+pub fn answer(zones: &[Arc<LocalZone>], request: &Message) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .add_queries(request.queries().to_vec());
+
+    if request.op_code() != OpCode::Query {
+        response.set_response_code(ResponseCode::NotImp);
+        return response;
+    }
+
+    let query = match request.queries() {
+        [query] => query,
+        _ => {
+            response.set_response_code(ResponseCode::FormErr);
+            return response;
+        }
+    };
+
+    let qtype = query.query_type();
+    let mut name = query.name().to_ascii().trim_end_matches('.').to_lowercase();
+
+    let zone = match zones
+        .iter()
+        .filter(|zone| zone.contains(&name))
+        .max_by_key(|zone| zone.origin().len())
+    {
+        Some(zone) => zone,
+        None => {
+            response.set_response_code(ResponseCode::Refused);
+            return response;
+        }
+    };
+    response.set_authoritative(true);
+
+    let records = zone_records(zone);
+    let mut owner = query.name().clone();
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        let found = match node(zone, &records, &name) {
+            Some(found) => found,
+            None => {
+                response.set_response_code(ResponseCode::NXDomain);
+                break;
+            }
+        };
+
+        let mut answers: Vec<Record> = vec![];
+        if name == zone.origin() {
+            answers.extend(soa(zone));
+            answers.extend(ns(zone));
+        }
+        answers.extend(found.iter().filter_map(|r| to_record(&owner, r)));
+
+        let cname = answers
+            .iter()
+            .find(|r| r.record_type() == RecordType::CNAME)
+            .cloned();
+
+        match cname {
+            Some(cname) if qtype != RecordType::CNAME && qtype != RecordType::ANY => {
+                response.add_answer(cname.clone());
+
+                let target = match cname.data() {
+                    Some(RData::CNAME(CNAME(target))) => target.clone(),
+                    _ => break,
+                };
+                // Targets outside our zone are left to the resolver.
+                name = target.to_ascii().trim_end_matches('.').to_lowercase();
+                if !zone.contains(&name) {
+                    break;
+                }
+                owner = target;
+            }
+            _ => {
+                response.add_answers(
+                    answers
+                        .into_iter()
+                        .filter(|r| qtype == RecordType::ANY || r.record_type() == qtype),
+                );
+                break;
+            }
+        }
+    }
+
+    if qtype == RecordType::NS {
+        let targets: Vec<String> = response
+            .answers()
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::NS(NS(ns))) => Some(ns.to_ascii().trim_end_matches('.').to_lowercase()),
+                _ => None,
+            })
+            .collect();
+
+        for target in targets {
+            let owner = match fqdn(&target) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            for record in records
+                .iter()
+                .filter(|r| r.name == target && (r.record_type == "A" || r.record_type == "AAAA"))
+            {
+                if let Some(record) = to_record(&owner, record) {
+                    response.add_additional(record);
+                }
+            }
+        }
+    }
+
+    // NODATA and NXDOMAIN answers carry the SOA for negative caching.
+    if response
+        .answers()
+        .iter()
+        .all(|r| r.record_type() == RecordType::CNAME)
+        && !(qtype == RecordType::CNAME && response.answer_count() > 0)
+    {
+        if let Some(soa) = soa(zone) {
+            response.add_name_server(soa);
+        }
+    }
+
+    response
+}
+
+/// Answers one wire format request, `limit` being the largest response the
+/// transport takes. Unparsable requests are dropped.
+fn handle(zones: &[Arc<LocalZone>], bytes: &[u8], limit: Option<usize>) -> Option<Vec<u8>> {
+    let request = Message::from_vec(bytes).ok()?;
+    let mut response = answer(zones, &request);
+
+    let limit = match request.extensions() {
+        Some(edns) => {
+            let mut reply = Edns::new();
+            reply.set_max_payload(EDNS_PAYLOAD);
+            response.set_edns(reply);
+            limit.map(|_| edns.max_payload().clamp(512, EDNS_PAYLOAD) as usize)
+        }
+        None => limit.map(|_| 512),
+    };
+
+    let bytes = response.to_vec().ok()?;
+    match limit {
+        Some(limit) if bytes.len() > limit => {
+            // Let the client retry over TCP.
+            response.take_answers();
+            response.take_name_servers();
+            response.take_additionals();
+            response.set_truncated(true);
+            response.to_vec().ok()
+        }
+        _ => Some(bytes),
+    }
+}
+
+/// The built-in authoritative DNS server, listening on one address over
+/// both UDP and TCP.
+pub struct DnsServer {
+    zones: Arc<Vec<Arc<LocalZone>>>,
+    udp: UdpSocket,
+    tcp: TcpListener,
+}
+
+impl DnsServer {
+    pub async fn bind(zones: Vec<Arc<LocalZone>>, addr: &str) -> Result<Self, std::io::Error> {
+        let udp = UdpSocket::bind(addr).await?;
+        // Bind TCP to the port UDP got, which differs from `addr` for port 0.
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+
+        Ok(DnsServer {
+            zones: Arc::new(zones),
+            udp,
+            tcp,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.udp.local_addr()
+    }
+
+    pub async fn run(self) {
+        let tcp_zones = self.zones.clone();
+        let tcp = self.tcp;
+        tokio::spawn(async move {
+            loop {
+                match tcp.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_tcp(tcp_zones.clone(), stream));
+                    }
+                    Err(e) => error!("DNS server failed to accept a TCP connection: {}", e),
+                }
+            }
+        });
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, peer) = match self.udp.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("DNS server failed to receive a UDP packet: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(reply) = handle(&self.zones, &buf[..len], Some(512)) {
+                if let Err(e) = self.udp.send_to(&reply, peer).await {
+                    error!("DNS server failed to answer {}: {}", peer, e);
+                }
+            }
+        }
+    }
+}
+
+/// Answers length prefixed requests on one TCP connection until the client
+/// closes it or goes idle.
+async fn serve_tcp(zones: Arc<Vec<Arc<LocalZone>>>, mut stream: TcpStream) {
+    loop {
+        let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            _ => return,
+        };
+
+        let mut buf = vec![0u8; len];
+        if stream.read_exact(&mut buf).await.is_err() {
+            return;
+        }
+
+        let reply = match handle(&zones, &buf, None) {
+            Some(reply) if reply.len() <= u16::MAX as usize => reply,
+            _ => return,
+        };
+
+        if stream.write_u16(reply.len() as u16).await.is_err()
+            || stream.write_all(&reply).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Starts serving `zones` on `addr` in the background.
+pub async fn spawn(zones: Vec<Arc<LocalZone>>, addr: &str) {
+    match DnsServer::bind(zones, addr).await {
+        Ok(server) => {
+            info!("DNS server listening on {}", addr);
+            tokio::spawn(server.run());
+        }
+        Err(e) => error!("DNS server failed to listen on {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::op::Query;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::localdns::localdns::LocalDns;
+    use crate::provider::provider::{DnsProvider, RecordOptions};
+
+    async fn zone() -> (LocalDns, Arc<LocalZone>) {
+        let config = Config {
+            dns_suffix: "floy.id".to_string(),
+            ip: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let zone = LocalZone::load(
+            "floy.id",
+            config.nameservers(),
+            vec!["10.0.0.53".to_string()],
+            None,
+        )
+        .await
+        .unwrap();
+        let zone = Arc::new(zone);
+
+        (LocalDns::from_zone(config, zone.clone()), zone)
+    }
+
+    fn query(name: &str, record_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request
+            .set_id(7)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), record_type));
+        request
+    }
+
+    fn contents(message: &Message) -> Vec<String> {
+        message
+            .answers()
+            .iter()
+            .map(|r| r.data().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let (dns, zone) = zone().await;
+        let zones = vec![zone];
+
+        dns.add_subdomain_dns_record(
+            "alice",
+            "10.0.0.2",
+            &RecordOptions {
+                wildcard: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        dns.create_record(&DnsRecord::new(
+            "CNAME".to_string(),
+            "www.floy.id".to_string(),
+            1,
+            "alice.floy.id".to_string(),
+            false,
+        ))
+        .await
+        .unwrap();
+
+        let response = answer(&zones, &query("alice.floy.id.", RecordType::A));
+        assert!(response.authoritative());
+        assert_eq!(vec!["10.0.0.2"], contents(&response));
+
+        let response = answer(&zones, &query("blog.alice.floy.id.", RecordType::A));
+        assert_eq!(vec!["10.0.0.2"], contents(&response));
+        assert_eq!(
+            "blog.alice.floy.id.",
+            response.answers()[0].name().to_ascii()
+        );
+
+        let response = answer(&zones, &query("www.floy.id.", RecordType::A));
+        assert_eq!(vec!["alice.floy.id.", "10.0.0.2"], contents(&response));
+
+        let response = answer(&zones, &query("alice.floy.id.", RecordType::TXT));
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert!(response.answers().is_empty());
+        assert_eq!(RecordType::SOA, response.name_servers()[0].record_type());
+
+        let response = answer(&zones, &query("bob.floy.id.", RecordType::A));
+        assert_eq!(ResponseCode::NXDomain, response.response_code());
+        assert_eq!(RecordType::SOA, response.name_servers()[0].record_type());
+
+        let response = answer(&zones, &query("floy.id.", RecordType::NS));
+        assert_eq!(vec!["ns1.floy.id."], contents(&response));
+        assert_eq!(
+            "10.0.0.53",
+            response.additionals()[0].data().unwrap().to_string()
+        );
+
+        let response = answer(&zones, &query("ns1.floy.id.", RecordType::A));
+        assert_eq!(vec!["10.0.0.53"], contents(&response));
+
+        let response = answer(&zones, &query("example.com.", RecordType::A));
+        assert_eq!(ResponseCode::Refused, response.response_code());
+        assert!(!response.authoritative());
+    }
+
+    #[tokio::test]
+    async fn test_serve_udp_and_tcp() {
+        let (dns, zone) = zone().await;
+        let serial = zone.serial();
+        let server = DnsServer::bind(vec![zone], "127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        dns.add_subdomain_dns_record("alice", "2001:db8::2", &RecordOptions::default())
+            .await
+            .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = query("alice.floy.id.", RecordType::AAAA).to_vec().unwrap();
+        socket.send_to(&request, addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        let len = socket.recv(&mut buf).await.unwrap();
+        let response = Message::from_vec(&buf[..len]).unwrap();
+        assert_eq!(7, response.id());
+        assert_eq!(vec!["2001:db8::2"], contents(&response));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = query("floy.id.", RecordType::SOA).to_vec().unwrap();
+        stream.write_u16(request.len() as u16).await.unwrap();
+        stream.write_all(&request).await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        let response = Message::from_vec(&buf).unwrap();
+        match response.answers()[0].data() {
+            Some(RData::SOA(soa)) => assert!(soa.serial() > serial),
+            other => panic!("expected a SOA record, got {:?}", other),
+        }
+    }
+}
@@ -1,36 +1,96 @@
 #![allow(dead_code)]
 #![allow(unused_mut)]
 #![allow(unused)]
+#![allow(clippy::module_inception)]
 
 #[macro_use]
 extern crate rocket;
 
-use crate::cloudflare::cloudflare::Cloudflare;
+use rocket::fairing::AdHoc;
+
+use crate::acme::acme::Certificates;
+use crate::common::credentials::CredentialStore;
 use crate::common::errors::build_catchers;
 use crate::common::writers::Writer;
 use crate::config::Config;
+use crate::custom_domains::custom_domains::CustomDomainStore;
 use crate::endpoints::build_endpoints;
+use crate::importer::importer::run_cli;
+use crate::localdns::server;
+use crate::propagation::propagation::PropagationTracker;
+use crate::reconciler::reconciler::spawn_periodic;
+use crate::zones::zones::Zones;
 
+mod acme;
+mod bulk;
 mod cloudflare;
 mod common;
 mod config;
+mod custom_domains;
+mod ddns;
 mod endpoints;
+mod importer;
+mod localdns;
 mod models;
+mod nginx;
 mod parser;
+mod powerdns;
+mod propagation;
+mod provider;
+mod reconciler;
 mod updater;
+mod zonefile;
+mod zones;
 
-#[launch]
-async fn rocket() -> rocket::Rocket<rocket::Build> {
+#[rocket::main]
+async fn main() {
     dotenv::dotenv().ok();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("import") {
+        std::process::exit(run_cli(&args[1..]).await);
+    }
+
+    if let Err(e) = rocket().await.launch().await {
+        error!("Rocket failed to launch: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn rocket() -> rocket::Rocket<rocket::Build> {
     let config = Config::new();
     let writer = Writer::new(config.database_path.clone()).await.unwrap();
-    let cloudflare = Cloudflare::new(config.clone()).await;
+    let zones = Zones::new(&config).await;
+    let credentials = CredentialStore::new(config.ddns_credentials_path.clone());
+    let custom_domains = CustomDomainStore::new(config.custom_domains_path.clone());
+
+    let reconciler = (zones.clone(), config.clone());
+    let local_zones: Vec<_> = zones.iter().filter_map(|z| z.provider.local_zone()).collect();
+    let dns_listen = config.dns_listen.clone();
+    let certificates = Certificates::new(&config);
+    let renewals = (certificates.clone(), zones.clone());
 
     build_endpoints()
         .await
         .manage(writer)
         .manage(config)
-        .manage(cloudflare)
+        .manage(zones)
+        .manage(credentials)
+        .manage(custom_domains)
+        .manage(PropagationTracker::new())
+        .manage(certificates)
         .attach(build_catchers().await)
+        .attach(AdHoc::on_liftoff("Reconciler", |_| {
+            Box::pin(async move { spawn_periodic(reconciler.0, reconciler.1) })
+        }))
+        .attach(AdHoc::on_liftoff("DNS server", |_| {
+            Box::pin(async move {
+                if !local_zones.is_empty() {
+                    server::spawn(local_zones, &dns_listen).await;
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Certificates", |_| {
+            Box::pin(async move { acme::acme::spawn_periodic(renewals.0, renewals.1) })
+        }))
 }
@@ -1,5 +1,6 @@
 use std::fmt;
 
+use rocket::serde::json::Value;
 use rocket::serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -31,6 +32,8 @@ pub struct DNS {
 #[derive(Serialize, Deserialize)]
 pub struct Record {
     pub id: String,
+    #[serde(rename = "type", default)]
+    pub record_type: String,
     pub content: String,
 }
 
@@ -39,14 +42,114 @@ pub struct Records {
     pub result: Vec<Record>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct PdnsRecord {
+    pub content: String,
+    pub disabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PdnsRRSet {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changetype: Option<String>,
+    #[serde(default)]
+    pub records: Vec<PdnsRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PdnsZone {
+    #[serde(default)]
+    pub rrsets: Vec<PdnsRRSet>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DnsRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     #[serde(rename = "type")]
     pub record_type: String,
     pub name: String,
+    #[serde(default = "DnsRecord::automatic_ttl")]
     pub ttl: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub content: String,
+    #[serde(default)]
     pub proxied: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DnsRecords {
+    pub result: Vec<DnsRecord>,
+    pub result_info: Option<ResultInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct ResultInfo {
+    pub page: u32,
+    pub per_page: u32,
+    pub total_pages: u32,
+    pub count: u32,
+    pub total_count: u32,
+}
+
+#[derive(Deserialize, Default)]
+pub struct CfMessage {
+    #[serde(default)]
+    pub code: u32,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct CfEnvelope {
+    #[serde(default)]
+    pub success: bool,
+    #[serde(default)]
+    pub errors: Vec<CfMessage>,
+    #[serde(default)]
+    pub messages: Vec<CfMessage>,
+}
+
+#[derive(FromForm)]
+pub struct RecordFilter {
+    #[field(name = "type")]
+    pub record_type: Option<String>,
+    pub suffix: Option<String>,
+    pub zone: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct DuckDnsQuery {
+    pub domains: String,
+    pub token: String,
+    pub ip: Option<String>,
+    pub ipv6: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DnsRecordResponse {
+    pub result: DnsRecord,
+}
+
+#[derive(Deserialize)]
+pub struct RecordId {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeletedRecord {
+    pub result: RecordId,
 }
 
 #[derive(Deserialize)]
@@ -54,7 +157,24 @@ pub struct DnsRecord {
 pub struct SubdomainRequest {
     pub user_id: String,
     pub business_id: String,
+    /// Either a claimed subdomain (`alice`) or a hostname nested under one
+    /// (`blog.alice`).
     pub subdomain: String,
+    /// Also serve `*.<subdomain>` from the same record and site.
+    #[serde(default)]
+    pub wildcard: bool,
+    /// TTL in seconds, defaults to `1` (automatic).
+    pub ttl: Option<u32>,
+    /// Defaults to `true` (orange cloud).
+    pub proxied: Option<bool>,
+    /// Suffix of the zone to use, defaults to the first configured zone.
+    pub zone: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProxiedRequest {
+    pub proxied: bool,
 }
 
 #[derive(Deserialize)]
@@ -67,6 +187,36 @@ pub struct SlugRequest {
     pub site_id: String,
     pub rewrite_target: Option<String>,
     pub subdomain: String,
+    pub zone: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CustomDomainRequest {
+    pub hostname: String,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct BulkSlug {
+    pub slug: String,
+    pub site_id: String,
+}
+
+/// One row of a bulk provisioning batch: a domain and the slug pages of its
+/// site, created as `POST /domain` and `POST /slug` would.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct BulkRow {
+    pub user_id: String,
+    pub business_id: String,
+    pub subdomain: String,
+    #[serde(default)]
+    pub slugs: Vec<BulkSlug>,
+    #[serde(default)]
+    pub wildcard: bool,
+    pub ttl: Option<u32>,
+    pub proxied: Option<bool>,
 }
 
 impl DnsRecord {
@@ -78,21 +228,26 @@ impl DnsRecord {
         proxied: bool,
     ) -> Self {
         DnsRecord {
+            id: None,
             record_type,
             name,
             ttl,
             content,
             proxied,
+            priority: None,
+            data: None,
+            comment: None,
         }
     }
+
+    /// Cloudflare treats a TTL of 1 as "automatic".
+    pub fn automatic_ttl() -> u32 {
+        1
+    }
 }
 
 impl fmt::Display for DnsRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{{\"type\":\"{}\",\"name\":\"{}\",\"ttl\":{},\"content\":\"{}\",\"proxied\":{}}}",
-            self.record_type, self.name, self.ttl, self.content, self.proxied
-        )
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| fmt::Error)?)
     }
 }
@@ -0,0 +1,802 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use pest::iterators::Pair;
+use pest::Parser;
+
+use crate::parser::parser::{validate_config, NginxParser, Rule};
+
+const INDENT: &str = "    ";
+
+/// Directory next to a site that holds its temp file and backup.
+const BACKUP_DIR: &str = ".floy-backup";
+
+/// Where ACME servers fetch HTTP-01 challenge tokens from.
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// A directive the model has no field for, kept as written. Block
+/// directives such as `if (...) { ... }` carry their inner directives.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Directive {
+    pub name: String,
+    pub args: Vec<String>,
+    pub block: Option<Vec<Directive>>,
+}
+
+impl Directive {
+    pub fn new(name: &str, args: &[&str]) -> Self {
+        Directive {
+            name: name.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            block: None,
+        }
+    }
+}
+
+/// A `location [modifier] path { ... }` block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    /// `=`, `~`, `~*` or `^~`, if any.
+    pub modifier: Option<String>,
+    pub path: String,
+    pub directives: Vec<Directive>,
+}
+
+impl Location {
+    pub fn new(path: &str, directives: Vec<Directive>) -> Self {
+        Location {
+            modifier: None,
+            path: path.to_string(),
+            directives,
+        }
+    }
+
+    /// The block of a slug page, serving `target` for exactly `/<slug>`.
+    pub fn slug(slug: &str, target: &str) -> Self {
+        let pattern = format!("^/{}$", slug);
+
+        Location::new(
+            &format!("/{}", slug),
+            vec![Directive::new("rewrite", &[&pattern, target, "break"])],
+        )
+    }
+
+    /// Serves ACME HTTP-01 challenge tokens from `webroot`, ahead of any
+    /// slug page or redirect.
+    pub fn acme_challenge(webroot: &str) -> Self {
+        Location {
+            modifier: Some("^~".to_string()),
+            path: ACME_CHALLENGE_PATH.to_string(),
+            directives: vec![Directive::new("root", &[webroot])],
+        }
+    }
+
+    fn is_acme_challenge(&self) -> bool {
+        self.modifier.as_deref() == Some("^~") && self.path == ACME_CHALLENGE_PATH
+    }
+}
+
+/// The certificate a site is served over HTTPS with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tls {
+    pub certificate: String,
+    pub certificate_key: String,
+}
+
+/// The `server` block of a site file. A site served over HTTPS also has a
+/// block redirecting plain HTTP, which is derived from this one on render
+/// and not kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NginxServer {
+    pub server_names: Vec<String>,
+    pub root: Option<String>,
+    pub index: Vec<String>,
+    /// `listen` and every other directive without a field of its own, in
+    /// file order.
+    pub directives: Vec<Directive>,
+    pub locations: Vec<Location>,
+    /// Directory ACME HTTP-01 challenges are answered from, see
+    /// [`Location::acme_challenge`].
+    pub acme_webroot: Option<String>,
+    pub tls: Option<Tls>,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn unquote(quoted: &str) -> String {
+    let inner = &quoted[1..quoted.len() - 1];
+    let mut unquoted = String::with_capacity(inner.len());
+
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next @ ('"' | '\'' | '\\'))) => {
+                unquoted.push(next);
+                chars.next();
+            }
+            _ => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
+
+/// Quotes arguments nginx would otherwise split or stop at, so a brace in a
+/// rewrite target stays part of the target.
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || "{};\"'#".contains(c));
+
+    if plain {
+        arg.to_string()
+    } else {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn parse_directive(pair: Pair<Rule>) -> Directive {
+    let mut directive = Directive {
+        name: String::new(),
+        args: vec![],
+        block: None,
+    };
+
+    for inner in pair.into_inner() {
+        match inner.as_rule() {
+            Rule::name => directive.name = inner.as_str().to_string(),
+            Rule::argument => {
+                let value = inner.into_inner().next().unwrap();
+                directive.args.push(match value.as_rule() {
+                    Rule::bare => value.as_str().to_string(),
+                    _ => unquote(value.as_str()),
+                });
+            }
+            Rule::block => {
+                directive.block = Some(inner.into_inner().map(parse_directive).collect());
+            }
+            _ => {}
+        }
+    }
+
+    directive
+}
+
+fn render_directives(out: &mut String, directives: &[Directive], depth: usize) {
+    for directive in directives {
+        let indent = INDENT.repeat(depth);
+        let mut line = directive.name.clone();
+        for arg in &directive.args {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+
+        match &directive.block {
+            Some(block) => {
+                let _ = writeln!(out, "{}{} {{", indent, line);
+                render_directives(out, block, depth + 1);
+                let _ = writeln!(out, "{}}}", indent);
+            }
+            None => {
+                let _ = writeln!(out, "{}{};", indent, line);
+            }
+        }
+    }
+}
+
+impl NginxServer {
+    /// A new site serving `server_names` from `root`. Anything not matched
+    /// by a slug page is a 404.
+    pub fn new(server_names: Vec<String>, root: &str) -> Self {
+        NginxServer {
+            server_names,
+            root: Some(root.to_string()),
+            index: vec!["index.html".to_string()],
+            directives: vec![Directive::new("listen", &["80"])],
+            locations: vec![Location::new("/", vec![Directive::new("return", &["404"])])],
+            acme_webroot: None,
+            tls: None,
+        }
+    }
+
+    /// Parses a site file. Blocks after the first are the redirect of an
+    /// HTTPS site and only contribute the ACME webroot they serve.
+    pub fn parse(content: &str) -> Result<Self> {
+        let site = NginxParser::parse(Rule::site, content)
+            .map_err(|err| invalid(format!("Invalid nginx config: {}", err)))?
+            .next()
+            .unwrap();
+
+        let mut servers = site.into_inner().filter(|p| p.as_rule() == Rule::server);
+        let mut parsed = Self::parse_server(servers.next().unwrap())?;
+        for server in servers {
+            if parsed.acme_webroot.is_none() {
+                parsed.acme_webroot = Self::parse_server(server)?.acme_webroot;
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn parse_server(server: Pair<Rule>) -> Result<Self> {
+        let mut parsed = NginxServer {
+            server_names: vec![],
+            root: None,
+            index: vec![],
+            directives: vec![],
+            locations: vec![],
+            acme_webroot: None,
+            tls: None,
+        };
+        let mut certificate = None;
+        let mut certificate_key = None;
+
+        for pair in server.into_inner() {
+            let directive = parse_directive(pair);
+
+            match directive.name.as_str() {
+                "server_name" => parsed.server_names.extend(directive.args),
+                "root" => parsed.root = directive.args.into_iter().next(),
+                "index" => parsed.index = directive.args,
+                "ssl_certificate" => certificate = Some(directive),
+                "ssl_certificate_key" => certificate_key = Some(directive),
+                "location" => {
+                    let mut args = directive.args;
+                    let path = args
+                        .pop()
+                        .ok_or_else(|| invalid("location without a path"))?;
+                    let location = Location {
+                        modifier: args.pop(),
+                        path,
+                        directives: directive.block.unwrap_or_default(),
+                    };
+
+                    if location.is_acme_challenge() {
+                        parsed.acme_webroot = location
+                            .directives
+                            .iter()
+                            .find(|d| d.name == "root")
+                            .and_then(|d| d.args.first().cloned());
+                    } else {
+                        parsed.locations.push(location);
+                    }
+                }
+                _ => parsed.directives.push(directive),
+            }
+        }
+
+        let first_arg = |d: &Directive| d.args.first().cloned();
+        match (
+            certificate.as_ref().and_then(first_arg),
+            certificate_key.as_ref().and_then(first_arg),
+        ) {
+            (Some(certificate), Some(certificate_key)) => {
+                parsed.tls = Some(Tls {
+                    certificate,
+                    certificate_key,
+                })
+            }
+            // Half a certificate is kept as written.
+            _ => parsed
+                .directives
+                .extend(certificate.into_iter().chain(certificate_key)),
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn render(&self) -> String {
+        let mut directives = self.directives.clone();
+        if !self.server_names.is_empty() {
+            directives.push(self.server_name_directive());
+        }
+        if let Some(root) = &self.root {
+            directives.push(Directive::new("root", &[root]));
+        }
+        if !self.index.is_empty() {
+            directives.push(Directive {
+                name: "index".to_string(),
+                args: self.index.clone(),
+                block: None,
+            });
+        }
+
+        let mut locations = vec![];
+        match &self.tls {
+            Some(tls) => {
+                directives.push(Directive::new("ssl_certificate", &[&tls.certificate]));
+                directives.push(Directive::new(
+                    "ssl_certificate_key",
+                    &[&tls.certificate_key],
+                ));
+            }
+            None => locations.extend(self.acme_webroot.as_deref().map(Location::acme_challenge)),
+        }
+        locations.extend(self.locations.iter().cloned());
+
+        let mut out = render_server(&directives, &locations);
+        if self.tls.is_some() {
+            out.push('\n');
+            out.push_str(&self.render_redirect());
+        }
+        out
+    }
+
+    fn server_name_directive(&self) -> Directive {
+        Directive {
+            name: "server_name".to_string(),
+            args: self.server_names.clone(),
+            block: None,
+        }
+    }
+
+    /// The plain HTTP block of an HTTPS site: it answers ACME challenges
+    /// and sends everything else to HTTPS.
+    fn render_redirect(&self) -> String {
+        let mut directives: Vec<Directive> = self
+            .directives
+            .iter()
+            .filter(|d| d.name == "listen")
+            .map(|listen| Directive {
+                name: "listen".to_string(),
+                args: listen
+                    .args
+                    .iter()
+                    .take(1)
+                    .map(|address| with_port(address, "443", "80"))
+                    .collect(),
+                block: None,
+            })
+            .collect();
+        if !self.server_names.is_empty() {
+            directives.push(self.server_name_directive());
+        }
+
+        let mut locations: Vec<Location> = self
+            .acme_webroot
+            .as_deref()
+            .map(Location::acme_challenge)
+            .into_iter()
+            .collect();
+        locations.push(Location::new(
+            "/",
+            vec![Directive::new(
+                "return",
+                &["301", "https://$host$request_uri"],
+            )],
+        ));
+
+        render_server(&directives, &locations)
+    }
+
+    /// Serves the site over HTTPS with `tls`: listeners on port 80 move to
+    /// 443 and plain HTTP is redirected.
+    pub fn enable_tls(&mut self, tls: Tls) {
+        for listen in self.directives.iter_mut().filter(|d| d.name == "listen") {
+            if let Some(address) = listen.args.first_mut() {
+                *address = with_port(address, "80", "443");
+            }
+            if !listen.args.iter().any(|arg| arg == "ssl") {
+                listen.args.push("ssl".to_string());
+            }
+        }
+
+        self.tls = Some(tls);
+    }
+
+    /// Renders the site to a `.tmp` file, validates it there and only then
+    /// renames it over `path`, so nginx never sees a half written or invalid
+    /// file. The version being replaced is kept as a `.bak` file for
+    /// [`NginxServer::rollback`]. Both live in `.floy-backup/` next to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.write(path, true)
+    }
+
+    /// Like [`NginxServer::save`] but leaves the backup alone, so a rollback
+    /// still goes back to the version before an earlier save.
+    pub fn save_keeping_backup(&self, path: &Path) -> Result<()> {
+        self.write(path, false)
+    }
+
+    fn write(&self, path: &Path, backup: bool) -> Result<()> {
+        let tmp = backup_path(path, "tmp");
+        if let Some(dir) = tmp.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&tmp, self.render())?;
+
+        if let Err(e) = validate_config(&tmp) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+
+        if backup {
+            let backup = backup_path(path, "bak");
+            if path.exists() {
+                fs::copy(path, &backup)?;
+            } else if backup.exists() {
+                // A backup of an older site would resurrect it on rollback.
+                fs::remove_file(&backup)?;
+            }
+        }
+
+        fs::rename(&tmp, path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+
+    /// Removes the site at `path`. With `backup` set the site becomes its
+    /// backup, otherwise an earlier backup is kept, so that
+    /// [`NginxServer::rollback`] can bring it back.
+    pub fn delete(path: &Path, backup: bool) -> Result<()> {
+        let backup_file = backup_path(path, "bak");
+
+        if !path.exists() {
+            if backup && backup_file.exists() {
+                // A backup of an older site would resurrect it on rollback.
+                fs::remove_file(&backup_file)?;
+            }
+            return Ok(());
+        }
+
+        if backup {
+            if let Some(dir) = backup_file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(path, &backup_file)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    /// Drops the backup of a site that is deleted for good.
+    pub fn discard_backup(path: &Path) -> Result<()> {
+        let backup = backup_path(path, "bak");
+        if backup.exists() {
+            fs::remove_file(&backup)?;
+        }
+        if let Some(dir) = backup.parent() {
+            // Only goes once no other site keeps a backup there.
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+
+    /// Undoes the last [`NginxServer::save`] to `path`: puts the backup back,
+    /// or removes the file if the save created it.
+    pub fn rollback(path: &Path) -> Result<()> {
+        let backup = backup_path(path, "bak");
+
+        if backup.exists() {
+            fs::rename(backup, path)
+        } else if path.exists() {
+            fs::remove_file(path)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn location_index(&self, path: &str) -> Option<usize> {
+        self.locations
+            .iter()
+            .position(|l| l.modifier.is_none() && l.path == path)
+    }
+
+    pub fn location(&self, path: &str) -> Option<&Location> {
+        self.location_index(path).map(|i| &self.locations[i])
+    }
+
+    /// Adds a location, failing when one with the same path exists.
+    pub fn add_location(&mut self, location: Location) -> Result<()> {
+        if location.modifier.is_none() && self.location(&location.path).is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("location {} sudah ada", location.path),
+            ));
+        }
+
+        self.locations.push(location);
+        Ok(())
+    }
+
+    /// Puts `location` where the location at `path` was.
+    pub fn replace_location(&mut self, path: &str, location: Location) -> Result<()> {
+        let index = self.location_index(path).ok_or_else(|| not_found(path))?;
+
+        if location.path != path && self.location(&location.path).is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("location {} sudah ada", location.path),
+            ));
+        }
+
+        self.locations[index] = location;
+        Ok(())
+    }
+
+    pub fn remove_location(&mut self, path: &str) -> Result<Location> {
+        let index = self.location_index(path).ok_or_else(|| not_found(path))?;
+
+        Ok(self.locations.remove(index))
+    }
+}
+
+/// `address` (`80`, `*:80`, `[::]:80`, ...) with port `from` changed to
+/// `to`.
+fn with_port(address: &str, from: &str, to: &str) -> String {
+    match address.strip_suffix(from) {
+        Some(host) if host.is_empty() || host.ends_with(':') => format!("{}{}", host, to),
+        _ => address.to_string(),
+    }
+}
+
+fn render_server(directives: &[Directive], locations: &[Location]) -> String {
+    let mut out = String::from("server {\n");
+    render_directives(&mut out, directives, 1);
+
+    for location in locations {
+        let directive = Directive {
+            name: "location".to_string(),
+            args: location
+                .modifier
+                .iter()
+                .chain([&location.path])
+                .cloned()
+                .collect(),
+            block: Some(location.directives.clone()),
+        };
+
+        out.push('\n');
+        render_directives(&mut out, &[directive], 1);
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+/// `<dir>/.floy-backup/<name>.<extension>` for `path` = `<dir>/<name>`.
+/// nginx's `include <dir>/*;` skips dot entries, so the copies are never
+/// loaded as sites of their own.
+fn backup_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+
+    path.with_file_name(BACKUP_DIR).join(name)
+}
+
+fn not_found(path: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("location {} tidak ditemukan", path),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITE: &str = "server {
+    listen 80;
+    server_name alice.floy.id *.alice.floy.id;
+    root /srv/sites/u1/b1;
+    index index.html;
+
+    location / {
+        return 404;
+    }
+
+    location /about {
+        rewrite ^/about$ /s1/index.html break;
+    }
+}
+";
+
+    #[test]
+    fn test_parse_and_render() {
+        let server = NginxServer::parse(SITE).unwrap();
+
+        assert_eq!(
+            vec!["alice.floy.id", "*.alice.floy.id"],
+            server.server_names
+        );
+        assert_eq!(Some("/srv/sites/u1/b1".to_string()), server.root);
+        assert_eq!(vec![Directive::new("listen", &["80"])], server.directives);
+        assert_eq!(
+            Some(&Location::slug("about", "/s1/index.html")),
+            server.location("/about")
+        );
+        assert_eq!(SITE, server.render());
+
+        let new = NginxServer::new(
+            vec!["alice.floy.id".to_string(), "*.alice.floy.id".to_string()],
+            "/srv/sites/u1/b1",
+        );
+        assert_eq!(
+            SITE.replace(
+                "\n    location /about {\n        rewrite ^/about$ /s1/index.html break;\n    }\n",
+                ""
+            ),
+            new.render()
+        );
+    }
+
+    #[test]
+    fn test_parse_keeps_unknown_directives() {
+        let server = NginxServer::parse(
+            "server {\n\
+             \tlisten 443 ssl; # TLS\n\
+             \tserver_name shop.floy.id;\n\
+             \tadd_header X-Frame-Options 'SAME ORIGIN';\n\
+             \tlocation ~* \\.png$ { expires 30d; }\n\
+             }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                Directive::new("listen", &["443", "ssl"]),
+                Directive::new("add_header", &["X-Frame-Options", "SAME ORIGIN"]),
+            ],
+            server.directives
+        );
+        assert_eq!(Some("~*".to_string()), server.locations[0].modifier);
+        assert_eq!("\\.png$", server.locations[0].path);
+
+        assert_eq!(server, NginxServer::parse(&server.render()).unwrap());
+    }
+
+    #[test]
+    fn test_https_site() {
+        let mut server = NginxServer::parse(SITE).unwrap();
+        server.acme_webroot = Some("/var/www/acme".to_string());
+
+        let http = server.render();
+        assert!(http.contains(
+            "\n    location ^~ /.well-known/acme-challenge/ {\n        root /var/www/acme;\n    }\n"
+        ));
+        assert_eq!(server, NginxServer::parse(&http).unwrap());
+
+        server
+            .directives
+            .push(Directive::new("listen", &["[::]:80"]));
+        server.enable_tls(Tls {
+            certificate: "/certs/alice.floy.id/fullchain.pem".to_string(),
+            certificate_key: "/certs/alice.floy.id/privkey.pem".to_string(),
+        });
+
+        let https = server.render();
+        assert_eq!(
+            "server {
+    listen 443 ssl;
+    listen [::]:443 ssl;
+    server_name alice.floy.id *.alice.floy.id;
+    root /srv/sites/u1/b1;
+    index index.html;
+    ssl_certificate /certs/alice.floy.id/fullchain.pem;
+    ssl_certificate_key /certs/alice.floy.id/privkey.pem;
+
+    location / {
+        return 404;
+    }
+
+    location /about {
+        rewrite ^/about$ /s1/index.html break;
+    }
+}
+
+server {
+    listen 80;
+    listen [::]:80;
+    server_name alice.floy.id *.alice.floy.id;
+
+    location ^~ /.well-known/acme-challenge/ {
+        root /var/www/acme;
+    }
+
+    location / {
+        return 301 https://$host$request_uri;
+    }
+}
+",
+            https
+        );
+        assert_eq!(server, NginxServer::parse(&https).unwrap());
+    }
+
+    #[test]
+    fn test_slug_locations() {
+        let mut server = NginxServer::new(vec!["alice.floy.id".to_string()], "/srv");
+
+        server
+            .add_location(Location::slug("about", "/s1/index.html"))
+            .unwrap();
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            server
+                .add_location(Location::slug("about", "/s2/index.html"))
+                .unwrap_err()
+                .kind()
+        );
+
+        // A brace in the target is quoted instead of ending the block.
+        server
+            .replace_location("/about", Location::slug("team", "/s2/{index}.html"))
+            .unwrap();
+        assert!(server.location("/about").is_none());
+
+        let reparsed = NginxServer::parse(&server.render()).unwrap();
+        assert_eq!(
+            Some(&Location::slug("team", "/s2/{index}.html")),
+            reparsed.location("/team")
+        );
+        assert!(server
+            .render()
+            .contains("rewrite ^/team$ \"/s2/{index}.html\" break;"));
+
+        server.remove_location("/team").unwrap();
+        assert_eq!(
+            ErrorKind::NotFound,
+            server.remove_location("/team").unwrap_err().kind()
+        );
+        assert_eq!(1, server.locations.len());
+    }
+
+    #[test]
+    fn test_save_and_rollback() {
+        let dir = std::env::temp_dir().join(format!("floy-nginx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nginx.conf");
+        let _ = fs::remove_file(dir.join(".floy-backup/nginx.conf.bak"));
+
+        let mut server = NginxServer::new(vec!["alice.floy.id".to_string()], "/srv");
+        server.save(&path).unwrap();
+        let first = fs::read_to_string(&path).unwrap();
+
+        server
+            .add_location(Location::slug("about", "/s1/index.html"))
+            .unwrap();
+        server.save(&path).unwrap();
+        assert_eq!(
+            first,
+            fs::read_to_string(dir.join(".floy-backup/nginx.conf.bak")).unwrap()
+        );
+        // Nothing but the site is left where `include <dir>/*;` looks.
+        assert!(!dir.join("nginx.conf.bak").exists());
+
+        // An invalid change never replaces the live file.
+        let saved = fs::read_to_string(&path).unwrap();
+        server.directives.push(Directive::new("", &["}"]));
+        assert!(server.save(&path).is_err());
+        assert_eq!(saved, fs::read_to_string(&path).unwrap());
+        assert!(!dir.join(".floy-backup/nginx.conf.tmp").exists());
+
+        NginxServer::rollback(&path).unwrap();
+        assert_eq!(first, fs::read_to_string(&path).unwrap());
+
+        // Rolling back a new site removes it.
+        let new = dir.join("nginx.new.conf");
+        server.directives.pop();
+        server.save(&new).unwrap();
+        NginxServer::rollback(&new).unwrap();
+        assert!(!new.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_rejects_broken_config() {
+        assert!(NginxServer::parse("server { listen 80; ").is_err());
+        assert!(NginxServer::parse("server { location / { return 404; }").is_err());
+        assert!(NginxServer::parse("server { listen 80; } }").is_err());
+    }
+}
@@ -10,7 +10,7 @@ pub struct NginxParser;
 
 pub fn validate_config(path: &Path) -> Result<()> {
     let content = fs::read_to_string(path)?;
-    NginxParser::parse(Rule::server, &content)
+    NginxParser::parse(Rule::site, &content)
         .map(|_| ())
         .map_err(|err| {
             eprintln!("Error parsing {}: {}", path.display(), err);
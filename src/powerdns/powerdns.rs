@@ -0,0 +1,462 @@
+use reqwest::Client;
+
+use crate::common::errors::ErrorKind;
+use crate::config::Config;
+use crate::models::{DnsRecord, PdnsRRSet, PdnsRecord, PdnsZone};
+use crate::provider::provider::{
+    record_type_for, DnsProvider, RecordOptions, ADDRESS_RECORD_TYPES,
+};
+
+const DEFAULT_TTL: u32 = 300;
+
+/// [`DnsProvider`] backed by the PowerDNS authoritative server HTTP API.
+///
+/// PowerDNS has no per-record ids, so the fully qualified rrset name is used
+/// wherever Cloudflare would hand back a record id.
+pub struct PowerDns {
+    client: Client,
+    config: Config,
+}
+
+impl PowerDns {
+    pub async fn new(config: Config) -> Self {
+        let mut headers = reqwest::header::HeaderMap::new();
+
+        headers.insert("Content-Type", "application/json".parse().unwrap());
+        headers.insert("X-API-Key", config.pdns_api_key.parse().unwrap());
+
+        let client = Client::builder().default_headers(headers).build().unwrap();
+
+        PowerDns { client, config }
+    }
+
+    fn zone_url(&self) -> String {
+        format!(
+            "{}/api/v1/servers/{}/zones/{}",
+            self.config.pdns_api_url.trim_end_matches('/'),
+            &self.config.pdns_server_id,
+            &self.config.pdns_zone
+        )
+    }
+
+    fn fqdn(&self, subdomain: &str, wildcard: bool) -> String {
+        if wildcard {
+            format!("*.{}.{}.", subdomain, &self.config.dns_suffix)
+        } else {
+            format!("{}.{}.", subdomain, &self.config.dns_suffix)
+        }
+    }
+
+    fn rrset(name: String, record_type: &str, ip: &str, changetype: &str) -> PdnsRRSet {
+        PdnsRRSet {
+            name,
+            record_type: record_type.to_owned(),
+            ttl: Some(DEFAULT_TTL),
+            changetype: Some(changetype.to_owned()),
+            records: vec![PdnsRecord {
+                content: ip.to_owned(),
+                disabled: false,
+            }],
+        }
+    }
+
+    fn delete_rrset(name: String, record_type: &str) -> PdnsRRSet {
+        PdnsRRSet {
+            records: vec![],
+            ..Self::rrset(name, record_type, "", "DELETE")
+        }
+    }
+
+    /// Fetches every non-empty rrset named `name`, whatever its type.
+    async fn get_rrsets(&self, name: &str) -> Result<Vec<PdnsRRSet>, ErrorKind> {
+        let res = self
+            .client
+            .get(self.zone_url())
+            .query(&[("rrset_name", name)])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(ErrorKind::Error("Failed to get DNS record".to_string()));
+        }
+
+        let zone = res.json::<PdnsZone>().await?;
+
+        Ok(zone
+            .rrsets
+            .into_iter()
+            .filter(|r| r.name == name && !r.records.is_empty())
+            .collect())
+    }
+
+    async fn get_rrset(
+        &self,
+        name: &str,
+        record_type: &str,
+    ) -> Result<Option<PdnsRRSet>, ErrorKind> {
+        Ok(self
+            .get_rrsets(name)
+            .await?
+            .into_iter()
+            .find(|r| r.record_type == record_type))
+    }
+
+    async fn patch_rrsets(&self, rrsets: Vec<PdnsRRSet>, error: &str) -> Result<(), ErrorKind> {
+        let res = self
+            .client
+            .patch(self.zone_url())
+            .json(&PdnsZone { rrsets })
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(ErrorKind::Error(error.to_string()))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl DnsProvider for PowerDns {
+    async fn add_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        ip: &str,
+        options: &RecordOptions,
+    ) -> Result<(), ErrorKind> {
+        let name = self.fqdn(subdomain, false);
+        let record_type = record_type_for(ip);
+        // PowerDNS has no proxy and no notion of an automatic TTL.
+        let ttl = if options.ttl == DnsRecord::automatic_ttl() {
+            DEFAULT_TTL
+        } else {
+            options.ttl
+        };
+
+        if self.get_rrset(&name, record_type).await?.is_some() {
+            return Err(ErrorKind::RecordAlreadyExists(
+                name.trim_end_matches('.').to_string(),
+            ));
+        }
+
+        let mut names = vec![name];
+        if options.wildcard {
+            names.push(self.fqdn(subdomain, true));
+        }
+
+        let rrsets = names
+            .into_iter()
+            .map(|name| PdnsRRSet {
+                ttl: Some(ttl),
+                ..Self::rrset(name, record_type, ip, "REPLACE")
+            })
+            .collect();
+
+        self.patch_rrsets(rrsets, "Failed to add DNS record").await
+    }
+
+    async fn get_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        record_type: &str,
+        wildcard: bool,
+    ) -> Result<(String, String), ErrorKind> {
+        let name = self.fqdn(subdomain, wildcard);
+
+        match self.get_rrset(&name, record_type).await? {
+            Some(rrset) => Ok((rrset.records[0].content.clone(), rrset.name)),
+            None => Err(ErrorKind::NotFound),
+        }
+    }
+
+    async fn update_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        ip: &str,
+    ) -> Result<(), ErrorKind> {
+        let name = self.fqdn(subdomain, false);
+        let record_type = record_type_for(ip);
+
+        if self.get_rrset(&name, record_type).await?.is_none() {
+            return Err(ErrorKind::NotFound);
+        }
+
+        let mut rrsets = vec![Self::rrset(name, record_type, ip, "REPLACE")];
+
+        let wildcard = self.fqdn(subdomain, true);
+        if self.get_rrset(&wildcard, record_type).await?.is_some() {
+            rrsets.push(Self::rrset(wildcard, record_type, ip, "REPLACE"));
+        }
+
+        self.patch_rrsets(rrsets, "Failed to update DNS record")
+            .await
+    }
+
+    async fn delete_subdomain_dns_record(&self, subdomain: &str) -> Result<(), ErrorKind> {
+        if !self.check_exists(subdomain).await? {
+            return Err(ErrorKind::NotFound);
+        }
+
+        let mut rrsets = vec![];
+        for record_type in ADDRESS_RECORD_TYPES {
+            rrsets.push(Self::delete_rrset(self.fqdn(subdomain, false), record_type));
+            rrsets.push(Self::delete_rrset(self.fqdn(subdomain, true), record_type));
+        }
+
+        self.patch_rrsets(rrsets, "Failed to delete DNS record")
+            .await
+    }
+
+    async fn check_exists(&self, subdomain: &str) -> Result<bool, ErrorKind> {
+        let name = self.fqdn(subdomain, false);
+
+        Ok(self
+            .get_rrsets(&name)
+            .await?
+            .iter()
+            .any(|r| ADDRESS_RECORD_TYPES.contains(&r.record_type.as_str())))
+    }
+
+    async fn list_records(
+        &self,
+        record_type: Option<&str>,
+        name_suffix: Option<&str>,
+    ) -> Result<Vec<DnsRecord>, ErrorKind> {
+        let res = self.client.get(self.zone_url()).send().await?;
+
+        if !res.status().is_success() {
+            return Err(ErrorKind::Error("Failed to get DNS records".to_string()));
+        }
+
+        let zone = res.json::<PdnsZone>().await?;
+
+        let mut records = vec![];
+        for rrset in zone.rrsets {
+            let name = rrset.name.trim_end_matches('.').to_string();
+
+            if record_type.is_some_and(|t| !t.eq_ignore_ascii_case(&rrset.record_type))
+                || name_suffix.is_some_and(|suffix| !name.ends_with(suffix))
+            {
+                continue;
+            }
+
+            for record in rrset.records.iter().filter(|r| !r.disabled) {
+                records.push(DnsRecord {
+                    id: Some(rrset.name.clone()),
+                    ..DnsRecord::new(
+                        rrset.record_type.clone(),
+                        name.clone(),
+                        rrset.ttl.unwrap_or(DEFAULT_TTL),
+                        record.content.clone(),
+                        false,
+                    )
+                });
+            }
+        }
+
+        Ok(records)
+    }
+
+    async fn list_subdomain_records(&self, subdomain: &str) -> Result<Vec<DnsRecord>, ErrorKind> {
+        let name = format!("{}.{}", subdomain, &self.config.dns_suffix);
+        let nested = format!(".{}", name);
+
+        Ok(self
+            .list_records(None, Some(&name))
+            .await?
+            .into_iter()
+            .filter(|r| r.name == name || r.name.ends_with(&nested))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{body_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    const ZONE_PATH: &str = "/api/v1/servers/localhost/zones/floy.id.";
+
+    async fn provider(server: &MockServer) -> PowerDns {
+        PowerDns::new(Config {
+            pdns_api_url: server.uri(),
+            pdns_api_key: "secret".to_string(),
+            pdns_server_id: "localhost".to_string(),
+            pdns_zone: "floy.id.".to_string(),
+            dns_suffix: "floy.id".to_string(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    fn zone_with(name: &str, record_type: &str, ip: &str) -> serde_json::Value {
+        serde_json::json!({
+            "rrsets": [{
+                "name": name,
+                "type": record_type,
+                "ttl": 300,
+                "records": [{ "content": ip, "disabled": false }]
+            }]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_get_subdomain_dns_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(ZONE_PATH))
+            .and(header("X-API-Key", "secret"))
+            .and(query_param("rrset_name", "alice.floy.id."))
+            .respond_with(ResponseTemplate::new(200).set_body_json(zone_with(
+                "alice.floy.id.",
+                "A",
+                "10.0.0.1",
+            )))
+            .mount(&server)
+            .await;
+
+        let pdns = provider(&server).await;
+        let (content, id) = pdns
+            .get_subdomain_dns_record("alice", "A", false)
+            .await
+            .unwrap();
+
+        assert_eq!("10.0.0.1", content);
+        assert_eq!("alice.floy.id.", id);
+        assert!(pdns.check_exists("alice").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_add_subdomain_dns_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(ZONE_PATH))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "rrsets": [] })),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path(ZONE_PATH))
+            .and(body_json(serde_json::json!({
+                "rrsets": [{
+                    "name": "bob.floy.id.",
+                    "type": "A",
+                    "ttl": 300,
+                    "changetype": "REPLACE",
+                    "records": [{ "content": "10.0.0.2", "disabled": false }]
+                }]
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let pdns = provider(&server).await;
+
+        assert!(!pdns.check_exists("bob").await.unwrap());
+        pdns.add_subdomain_dns_record("bob", "10.0.0.2", &RecordOptions::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_existing_subdomain_fails() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(ZONE_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(zone_with(
+                "bob.floy.id.",
+                "A",
+                "10.0.0.2",
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let pdns = provider(&server).await;
+
+        assert!(pdns
+            .add_subdomain_dns_record("bob", "10.0.0.2", &RecordOptions::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_subdomain_dns_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(ZONE_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(zone_with(
+                "bob.floy.id.",
+                "A",
+                "10.0.0.2",
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path(ZONE_PATH))
+            .and(body_json(serde_json::json!({
+                "rrsets": [
+                    { "name": "bob.floy.id.", "type": "A", "ttl": 300, "changetype": "DELETE", "records": [] },
+                    { "name": "*.bob.floy.id.", "type": "A", "ttl": 300, "changetype": "DELETE", "records": [] },
+                    { "name": "bob.floy.id.", "type": "AAAA", "ttl": 300, "changetype": "DELETE", "records": [] },
+                    { "name": "*.bob.floy.id.", "type": "AAAA", "ttl": 300, "changetype": "DELETE", "records": [] }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let pdns = provider(&server).await;
+
+        pdns.delete_subdomain_dns_record("bob").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_aaaa_next_to_a_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(ZONE_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(zone_with(
+                "bob.floy.id.",
+                "A",
+                "10.0.0.2",
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path(ZONE_PATH))
+            .and(body_json(serde_json::json!({
+                "rrsets": [{
+                    "name": "bob.floy.id.",
+                    "type": "AAAA",
+                    "ttl": 300,
+                    "changetype": "REPLACE",
+                    "records": [{ "content": "2001:db8::2", "disabled": false }]
+                }]
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let pdns = provider(&server).await;
+
+        pdns.add_subdomain_dns_record("bob", "2001:db8::2", &RecordOptions::default())
+            .await
+            .unwrap();
+        assert!(pdns
+            .get_subdomain_dns_record("bob", "AAAA", false)
+            .await
+            .is_err());
+    }
+}
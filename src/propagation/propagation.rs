@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::serde::Serialize;
+
+use crate::common::resolver::lookup;
+use crate::config::Config;
+use crate::provider::provider::record_type_for;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum PropagationStatus {
+    /// Some resolvers do not return the expected content yet.
+    Pending,
+    /// Every resolver returns the expected content.
+    Propagated,
+    /// The timeout passed before every resolver caught up.
+    TimedOut,
+    /// No resolvers are configured, so nothing was checked.
+    Unchecked,
+}
+
+/// How far a new name has made it through the configured resolvers.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Propagation {
+    pub status: PropagationStatus,
+    pub record_type: String,
+    /// Content the resolvers have to return. Proxied names resolve to the
+    /// proxy's addresses, so for them any answer will do.
+    pub expected: Option<String>,
+    /// Resolvers that do not return the expected content yet.
+    pub pending: Vec<String>,
+}
+
+/// A name to watch until it resolves.
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub record_type: String,
+    pub expected: Option<String>,
+    pub resolvers: Vec<String>,
+    pub timeout: Duration,
+    pub interval: Duration,
+}
+
+impl Check {
+    /// Watches `name` until it points at `ip`, using the resolvers and
+    /// timings of `cfg`.
+    pub fn new(name: &str, ip: &str, proxied: bool, cfg: &Config) -> Self {
+        Check {
+            name: name.to_string(),
+            record_type: record_type_for(ip).to_string(),
+            expected: Some(ip.to_string()).filter(|_| !proxied),
+            resolvers: cfg.propagation_resolvers.clone(),
+            timeout: Duration::from_secs(cfg.propagation_timeout),
+            interval: Duration::from_secs(cfg.propagation_interval),
+        }
+    }
+
+    fn report(&self, status: PropagationStatus, pending: Vec<String>) -> Propagation {
+        Propagation {
+            status,
+            record_type: self.record_type.clone(),
+            expected: self.expected.clone(),
+            pending,
+        }
+    }
+
+    /// True when `resolver` returns the expected content. A failed lookup
+    /// counts as not resolved yet.
+    async fn resolves(&self, resolver: &str) -> bool {
+        match lookup(resolver, &self.name, &self.record_type).await {
+            Ok(contents) => match &self.expected {
+                Some(expected) => contents.iter().any(|c| c == expected),
+                None => !contents.is_empty(),
+            },
+            Err(e) => {
+                warn!(
+                    "Propagation check of {} on {} failed: {}",
+                    self.name, resolver, e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Queries the check's resolvers every `interval` until all of them return
+/// the expected content or `timeout` passes. `progress` sees the state
+/// after every round.
+pub async fn wait_for(check: &Check, mut progress: impl FnMut(&Propagation)) -> Propagation {
+    if check.resolvers.is_empty() {
+        return check.report(PropagationStatus::Unchecked, vec![]);
+    }
+
+    let started = Instant::now();
+    let mut pending = check.resolvers.clone();
+
+    loop {
+        let mut still_pending = vec![];
+        for resolver in pending {
+            if !check.resolves(&resolver).await {
+                still_pending.push(resolver);
+            }
+        }
+        pending = still_pending;
+
+        if pending.is_empty() {
+            return check.report(PropagationStatus::Propagated, pending);
+        }
+        if started.elapsed() + check.interval > check.timeout {
+            return check.report(PropagationStatus::TimedOut, pending);
+        }
+
+        progress(&check.report(PropagationStatus::Pending, pending.clone()));
+        tokio::time::sleep(check.interval).await;
+    }
+}
+
+/// Propagation state of recently provisioned names, kept in memory.
+#[derive(Clone, Default)]
+pub struct PropagationTracker {
+    checks: Arc<RwLock<HashMap<String, Propagation>>>,
+}
+
+impl PropagationTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Propagation> {
+        self.checks.read().unwrap().get(name).cloned()
+    }
+
+    fn set(&self, name: &str, propagation: Propagation) {
+        self.checks
+            .write()
+            .unwrap()
+            .insert(name.to_string(), propagation);
+    }
+
+    /// Starts watching `check.name` in the background and returns its
+    /// initial state.
+    pub fn start(&self, check: Check) -> Propagation {
+        let initial = if check.resolvers.is_empty() {
+            check.report(PropagationStatus::Unchecked, vec![])
+        } else {
+            check.report(PropagationStatus::Pending, check.resolvers.clone())
+        };
+        self.set(&check.name, initial.clone());
+
+        if initial.status == PropagationStatus::Pending {
+            let tracker = self.clone();
+            tokio::spawn(async move {
+                let result = wait_for(&check, |progress| {
+                    tracker.set(&check.name, progress.clone())
+                })
+                .await;
+                if result.status == PropagationStatus::TimedOut {
+                    warn!(
+                        "{} did not propagate to {}",
+                        check.name,
+                        result.pending.join(", ")
+                    );
+                }
+                tracker.set(&check.name, result);
+            });
+        }
+
+        initial
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::localdns::localdns::{LocalDns, LocalZone};
+    use crate::localdns::server::DnsServer;
+    use crate::provider::provider::{DnsProvider, RecordOptions};
+
+    /// Serves an empty `floy.id` zone with the built-in DNS server, standing
+    /// in for a public resolver.
+    async fn resolver() -> (LocalDns, String) {
+        let config = Config {
+            dns_suffix: "floy.id".to_string(),
+            ip: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let zone = Arc::new(
+            LocalZone::load("floy.id", config.nameservers(), vec![], None)
+                .await
+                .unwrap(),
+        );
+
+        let server = DnsServer::bind(vec![zone.clone()], "127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        tokio::spawn(server.run());
+
+        (LocalDns::from_zone(config, zone), addr)
+    }
+
+    fn check(resolver: &str, expected: Option<&str>, timeout_ms: u64) -> Check {
+        Check {
+            name: "alice.floy.id".to_string(),
+            record_type: "A".to_string(),
+            expected: expected.map(str::to_string),
+            resolvers: vec![resolver.to_string()],
+            timeout: Duration::from_millis(timeout_ms),
+            interval: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_propagated_once_record_resolves() {
+        let (dns, addr) = resolver().await;
+        let tracker = PropagationTracker::new();
+
+        let initial = tracker.start(check(&addr, Some("10.0.0.1"), 5000));
+        assert_eq!(PropagationStatus::Pending, initial.status);
+        assert_eq!(vec![addr.clone()], initial.pending);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            PropagationStatus::Pending,
+            tracker.get("alice.floy.id").unwrap().status
+        );
+
+        dns.add_subdomain_dns_record("alice", "10.0.0.1", &RecordOptions::default())
+            .await
+            .unwrap();
+
+        for _ in 0..40 {
+            if tracker.get("alice.floy.id").unwrap().status != PropagationStatus::Pending {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let propagation = tracker.get("alice.floy.id").unwrap();
+        assert_eq!(PropagationStatus::Propagated, propagation.status);
+        assert!(propagation.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_times_out_on_wrong_content() {
+        let (dns, addr) = resolver().await;
+        dns.add_subdomain_dns_record("alice", "10.0.0.2", &RecordOptions::default())
+            .await
+            .unwrap();
+
+        let result = wait_for(&check(&addr, Some("10.0.0.1"), 300), |_| {}).await;
+        assert_eq!(PropagationStatus::TimedOut, result.status);
+        assert_eq!(vec![addr.clone()], result.pending);
+
+        // Proxied names only have to resolve.
+        let result = wait_for(&check(&addr, None, 300), |_| {}).await;
+        assert_eq!(PropagationStatus::Propagated, result.status);
+    }
+
+    #[tokio::test]
+    async fn test_unchecked_without_resolvers() {
+        let check = Check::new("alice.floy.id", "10.0.0.1", true, &Config::default());
+        assert_eq!(None, check.expected);
+
+        let tracker = PropagationTracker::new();
+        assert_eq!(PropagationStatus::Unchecked, tracker.start(check).status);
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rocket::serde::Serialize;
+
+use crate::cloudflare::cloudflare::Cloudflare;
+use crate::common::errors::ErrorKind;
+use crate::config::{Config, ProviderKind};
+use crate::localdns::localdns::{LocalDns, LocalZone};
+use crate::models::DnsRecord;
+use crate::powerdns::powerdns::PowerDns;
+
+/// Record types that point a subdomain at the origin.
+pub const ADDRESS_RECORD_TYPES: [&str; 2] = ["A", "AAAA"];
+
+/// Picks `AAAA` for IPv6 addresses and `A` for everything else.
+pub fn record_type_for(ip: &str) -> &'static str {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => "AAAA",
+        _ => "A",
+    }
+}
+
+/// How the address records of a new subdomain are created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordOptions {
+    /// Also create a `*.` wildcard pointing at the same address.
+    pub wildcard: bool,
+    /// TTL in seconds, `1` meaning automatic.
+    pub ttl: u32,
+    /// Route traffic through the provider's proxy (Cloudflare's orange cloud).
+    pub proxied: bool,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            wildcard: false,
+            ttl: DnsRecord::automatic_ttl(),
+            proxied: true,
+        }
+    }
+}
+
+/// Hit rate of a provider's lookup cache.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct CacheStats {
+    /// Lookups answered from the cache, counted once per lookup however
+    /// many record types it covers.
+    pub hits: u64,
+    pub misses: u64,
+    /// `hits / (hits + misses)`, `0` before the first lookup.
+    pub hit_rate: f64,
+    pub entries: usize,
+}
+
+/// Operations floy-dns needs from an authoritative DNS backend.
+///
+/// Subdomains are passed without the zone suffix; every implementation
+/// appends `dns_suffix` itself. The generic record methods have default
+/// implementations that report the operation as unsupported, so a backend
+/// only needs the subdomain methods to be usable.
+#[rocket::async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Creates an `A` or `AAAA` record depending on the address family of `ip`,
+    /// plus a matching `*.` wildcard record when `options.wildcard` is set.
+    async fn add_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        ip: &str,
+        options: &RecordOptions,
+    ) -> Result<(), ErrorKind>;
+
+    /// Returns the `(content, id)` pair of the subdomain's `record_type`
+    /// record, or of its `*.` wildcard when `wildcard` is set.
+    /// Fails with [`ErrorKind::NotFound`] when there is no such record.
+    async fn get_subdomain_dns_record(
+        &self,
+        subdomain: &str,
+        record_type: &str,
+        wildcard: bool,
+    ) -> Result<(String, String), ErrorKind>;
+
+    /// Points the record whose type matches the address family of `ip`, and
+    /// its wildcard if the subdomain has one, at `ip`. TTL and proxying are
+    /// left as they are.
+    async fn update_subdomain_dns_record(&self, subdomain: &str, ip: &str)
+        -> Result<(), ErrorKind>;
+
+    /// Removes the `A` and `AAAA` records of the subdomain and any wildcards.
+    async fn delete_subdomain_dns_record(&self, subdomain: &str) -> Result<(), ErrorKind>;
+
+    /// True when the subdomain has an `A` or an `AAAA` record.
+    async fn check_exists(&self, subdomain: &str) -> Result<bool, ErrorKind>;
+
+    /// Lists every record in the zone, optionally narrowed to one record type
+    /// and to names ending in `name_suffix`.
+    async fn list_records(
+        &self,
+        record_type: Option<&str>,
+        name_suffix: Option<&str>,
+    ) -> Result<Vec<DnsRecord>, ErrorKind> {
+        Err(unsupported())
+    }
+
+    /// Lists every record named `<subdomain>.<suffix>` or nested below it.
+    async fn list_subdomain_records(&self, subdomain: &str) -> Result<Vec<DnsRecord>, ErrorKind> {
+        Err(unsupported())
+    }
+
+    async fn get_record(&self, id: &str) -> Result<DnsRecord, ErrorKind> {
+        Err(unsupported())
+    }
+
+    /// Creates `record` as-is and returns the provider's id for it.
+    async fn create_record(&self, record: &DnsRecord) -> Result<String, ErrorKind> {
+        Err(unsupported())
+    }
+
+    async fn update_record(&self, id: &str, record: &DnsRecord) -> Result<(), ErrorKind> {
+        Err(unsupported())
+    }
+
+    async fn delete_record(&self, id: &str) -> Result<(), ErrorKind> {
+        Err(unsupported())
+    }
+
+    /// Turns proxying of an existing record on or off. Proxied records are
+    /// switched to automatic TTL.
+    async fn set_proxied(&self, id: &str, proxied: bool) -> Result<DnsRecord, ErrorKind> {
+        Err(unsupported())
+    }
+
+    /// Whether the generic record methods are implemented, i.e. whether
+    /// owners can manage the records under their subdomain.
+    fn manages_records(&self) -> bool {
+        false
+    }
+
+    /// Statistics of the provider's lookup cache, if it has one.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// The records the built-in DNS server answers with, for providers that
+    /// keep them in floy-dns itself.
+    fn local_zone(&self) -> Option<Arc<LocalZone>> {
+        None
+    }
+}
+
+fn unsupported() -> ErrorKind {
+    ErrorKind::NotImplemented("Operation not supported by this DNS provider".to_string())
+}
+
+pub async fn build_provider(config: &Config) -> Arc<dyn DnsProvider> {
+    match config.dns_provider {
+        ProviderKind::Cloudflare => Arc::new(Cloudflare::new(config.clone()).await),
+        ProviderKind::PowerDns => Arc::new(PowerDns::new(config.clone()).await),
+        ProviderKind::Builtin => Arc::new(LocalDns::new(config.clone()).await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_type_for() {
+        assert_eq!("A", record_type_for("203.0.113.7"));
+        assert_eq!("AAAA", record_type_for("2001:db8::1"));
+    }
+}
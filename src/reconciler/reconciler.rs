@@ -0,0 +1,615 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::common::errors::ErrorKind;
+use crate::common::writers::Writer;
+use crate::config::Config;
+use crate::models::DnsRecord;
+use crate::provider::provider::{
+    record_type_for, DnsProvider, RecordOptions, ADDRESS_RECORD_TYPES,
+};
+use crate::updater::updater::{self, Site};
+use crate::zones::zones::Zones;
+
+/// Held for reading while a domain's records and site are created or
+/// deleted, and for writing while a repair runs, so a repair never sees a
+/// domain halfway through and removes its fresh record as an orphan.
+pub static PROVISIONING: RwLock<()> = RwLock::const_new(());
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct SiteRef {
+    pub user_id: String,
+    pub business_id: String,
+    pub hostname: String,
+}
+
+/// A site hostname without the origin record of one address family.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct MissingRecord {
+    pub user_id: String,
+    pub business_id: String,
+    pub hostname: String,
+    pub record_type: String,
+    pub content: String,
+}
+
+/// Differences between the DNS zone, the nginx sites and the user store.
+#[derive(Serialize, Default, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DriftReport {
+    /// Origin records with no nginx site serving them. Repair only deletes
+    /// those named like a site, `<sub>.<suffix>`.
+    pub dns_without_site: Vec<String>,
+    /// Origin records missing for a site's `server_name`, one per address
+    /// family, so a site with an `A` but no `AAAA` record shows up too.
+    pub sites_without_dns: Vec<MissingRecord>,
+    /// Sites that exist in `sites-available` but are not linked.
+    pub missing_symlinks: Vec<SiteRef>,
+    /// Links in `sites-enabled` pointing at a file that no longer exists.
+    pub dangling_symlinks: Vec<String>,
+    /// Hostnames under a subdomain nobody has claimed.
+    pub unclaimed_hostnames: Vec<String>,
+    pub repaired: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl DriftReport {
+    pub fn has_drift(&self) -> bool {
+        !(self.dns_without_site.is_empty()
+            && self.sites_without_dns.is_empty()
+            && self.missing_symlinks.is_empty()
+            && self.dangling_symlinks.is_empty()
+            && self.unclaimed_hostnames.is_empty())
+    }
+}
+
+/// Returns the claimed subdomain a hostname belongs to, i.e. the label right
+/// in front of the suffix (`alice` for `blog.alice.<suffix>`).
+pub(crate) fn claim_of(hostname: &str, suffix: &str) -> Option<String> {
+    hostname
+        .strip_suffix(suffix)?
+        .strip_suffix('.')?
+        .rsplit('.')
+        .next()
+        .filter(|label| !label.is_empty() && *label != "*")
+        .map(str::to_string)
+}
+
+/// Compares the zone's origin records with its sites. Every site hostname
+/// should have one record per entry of `origins`. `in_zone` tells the
+/// hostnames of this zone from those of a zone nested under it.
+fn compare(
+    records: &[DnsRecord],
+    origins: &[&String],
+    sites: &[Site],
+    dangling: Vec<String>,
+    claims: &HashSet<String>,
+    suffix: &str,
+    in_zone: &dyn Fn(&str) -> bool,
+) -> DriftReport {
+    let mut report = DriftReport {
+        dangling_symlinks: dangling,
+        ..Default::default()
+    };
+
+    // Wildcard names are left out on both sides; the site's own hostname
+    // stands for them.
+    let dns_hosts: BTreeSet<String> = records
+        .iter()
+        .filter(|r| !r.name.starts_with("*."))
+        .map(|r| r.name.clone())
+        .collect();
+    let mut site_hosts = BTreeSet::new();
+
+    for site in sites {
+        for hostname in site
+            .server_names
+            .iter()
+            .filter(|h| in_zone(h) && !h.starts_with("*."))
+        {
+            site_hosts.insert(hostname.clone());
+
+            let site_ref = SiteRef {
+                user_id: site.user_id.clone(),
+                business_id: site.business_id.clone(),
+                hostname: hostname.clone(),
+            };
+
+            for origin in origins {
+                if !records
+                    .iter()
+                    .any(|r| &r.name == hostname && &&r.content == origin)
+                {
+                    report.sites_without_dns.push(MissingRecord {
+                        user_id: site.user_id.clone(),
+                        business_id: site.business_id.clone(),
+                        hostname: hostname.clone(),
+                        record_type: record_type_for(origin).to_string(),
+                        content: origin.to_string(),
+                    });
+                }
+            }
+            if fs::symlink_metadata(&site.enabled_path).is_err() {
+                report.missing_symlinks.push(site_ref);
+            }
+        }
+    }
+
+    report.dns_without_site = dns_hosts.difference(&site_hosts).cloned().collect();
+
+    report.unclaimed_hostnames = dns_hosts
+        .union(&site_hosts)
+        .filter(|h| claim_of(h, suffix).is_none_or(|claim| !claims.contains(&claim)))
+        .cloned()
+        .collect();
+
+    report
+}
+
+/// Rebuilds the options a missing record was created with: the wildcard from
+/// the site's `*.` server name, the TTL and proxying from the records of the
+/// hostname that are still there.
+fn recreate_options(
+    missing: &MissingRecord,
+    records: &[DnsRecord],
+    sites: &[Site],
+) -> RecordOptions {
+    let wildcard_name = format!("*.{}", missing.hostname);
+    let serves_wildcard = sites.iter().any(|s| {
+        s.user_id == missing.user_id
+            && s.business_id == missing.business_id
+            && s.server_names.contains(&wildcard_name)
+    });
+    let has_wildcard = records
+        .iter()
+        .any(|r| r.name == wildcard_name && r.content == missing.content);
+    let sibling = records
+        .iter()
+        .find(|r| r.name == missing.hostname || r.name == wildcard_name);
+
+    let defaults = RecordOptions::default();
+    RecordOptions {
+        wildcard: serves_wildcard && !has_wildcard,
+        ttl: sibling.map_or(defaults.ttl, |r| r.ttl),
+        proxied: sibling.map_or(defaults.proxied, |r| r.proxied),
+    }
+}
+
+async fn repair(
+    report: &mut DriftReport,
+    provider: &dyn DnsProvider,
+    records: &[DnsRecord],
+    sites: &[Site],
+    cfg: &Config,
+) {
+    let suffix = format!(".{}", cfg.dns_suffix);
+
+    for hostname in report.dns_without_site.clone() {
+        let subdomain = hostname.trim_end_matches(&suffix);
+        // Sites are provisioned as `<sub>.<suffix>`; nested names are
+        // records users created themselves and are only reported.
+        if subdomain.contains('.') {
+            continue;
+        }
+        match provider.delete_subdomain_dns_record(subdomain).await {
+            Ok(_) => report
+                .repaired
+                .push(format!("deleted orphan record {}", hostname)),
+            Err(e) => report.errors.push(format!("{}: {}", hostname, e)),
+        }
+    }
+
+    for missing in report.sites_without_dns.clone() {
+        let subdomain = missing.hostname.trim_end_matches(&suffix);
+        let options = recreate_options(&missing, records, sites);
+        match provider
+            .add_subdomain_dns_record(subdomain, &missing.content, &options)
+            .await
+        {
+            Ok(_) => report.repaired.push(format!(
+                "recreated {} record {} -> {}",
+                missing.record_type, missing.hostname, missing.content
+            )),
+            Err(e) => report.errors.push(format!("{}: {}", missing.hostname, e)),
+        }
+    }
+
+    for dangling in report.dangling_symlinks.clone() {
+        let relink = sites
+            .iter()
+            .find(|s| s.enabled_path.to_string_lossy() == dangling);
+
+        let result = match relink {
+            Some(site) => updater::enable_site(site).map(|_| format!("re-linked {}", dangling)),
+            None => {
+                fs::remove_file(&dangling).map(|_| format!("removed dangling link {}", dangling))
+            }
+        };
+
+        match result {
+            Ok(action) => report.repaired.push(action),
+            Err(e) => report.errors.push(format!("{}: {}", dangling, e)),
+        }
+    }
+
+    for site_ref in report.missing_symlinks.clone() {
+        let site = sites.iter().find(|s| {
+            s.user_id == site_ref.user_id
+                && s.business_id == site_ref.business_id
+                && s.server_names.contains(&site_ref.hostname)
+        });
+
+        let result = match site {
+            Some(site) => updater::enable_site(site),
+            None => continue,
+        };
+
+        match result {
+            Ok(_) => report.repaired.push(format!(
+                "linked {}/{}",
+                site_ref.user_id, site_ref.business_id
+            )),
+            Err(e) => report.errors.push(format!("{}: {}", site_ref.hostname, e)),
+        }
+    }
+}
+
+/// Compares the origin records in DNS, the nginx sites on disk and the user
+/// store, and fixes what it can when `fix` is set.
+///
+/// Only `A`/`AAAA` records pointing at the configured origin count as managed,
+/// so unrelated records in the zone are never touched. A repair waits for
+/// domains being provisioned or deleted, see [`PROVISIONING`].
+pub async fn reconcile(
+    provider: &dyn DnsProvider,
+    zones: &Zones,
+    writer: &Writer<String>,
+    cfg: &Config,
+    fix: bool,
+) -> Result<DriftReport, ErrorKind> {
+    let origins: Vec<&String> = std::iter::once(&cfg.ip).chain(cfg.ipv6.iter()).collect();
+    // With `floy.site` and `eu.floy.site` both configured, the hostnames
+    // under `eu.floy.site` belong to that zone only.
+    let in_zone = |hostname: &str| {
+        zones
+            .for_hostname(hostname)
+            .is_some_and(|zone| zone.config.dns_suffix == cfg.dns_suffix)
+    };
+
+    let _repairing = if fix {
+        Some(PROVISIONING.write().await)
+    } else {
+        None
+    };
+
+    let records: Vec<DnsRecord> = provider
+        .list_records(None, Some(&cfg.dns_suffix))
+        .await?
+        .into_iter()
+        .filter(|r| {
+            ADDRESS_RECORD_TYPES.contains(&r.record_type.as_str())
+                && origins.contains(&&r.content)
+                && in_zone(r.name.trim_start_matches("*."))
+        })
+        .collect();
+
+    let sites = updater::list_sites(cfg)?;
+
+    let dangling = updater::list_dangling_symlinks(cfg)?
+        .into_iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+
+    let claims = writer
+        .all()
+        .await?
+        .into_iter()
+        .map(|u| u.subdomain_claim)
+        .collect();
+
+    let mut report = compare(
+        &records,
+        &origins,
+        &sites,
+        dangling,
+        &claims,
+        &cfg.dns_suffix,
+        &in_zone,
+    );
+
+    if fix {
+        repair(&mut report, provider, &records, &sites, cfg).await;
+    }
+
+    Ok(report)
+}
+
+/// Runs [`reconcile`] for every zone every `reconcile_interval` seconds in
+/// the background.
+pub fn spawn_periodic(zones: Zones, cfg: Config) {
+    if cfg.reconcile_interval == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let writer = match Writer::new(cfg.database_path.clone()).await {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!("Reconciler could not open the user store: {}", e);
+                return;
+            }
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(cfg.reconcile_interval));
+
+        loop {
+            interval.tick().await;
+
+            for zone in zones.iter() {
+                let result = reconcile(
+                    zone.provider.as_ref(),
+                    &zones,
+                    &writer,
+                    &zone.config,
+                    cfg.reconcile_repair,
+                )
+                .await;
+
+                match result {
+                    Ok(report) if report.has_drift() => warn!(
+                        "Drift detected in {}: {}",
+                        zone.config.dns_suffix,
+                        rocket::serde::json::to_string(&report).unwrap_or_default()
+                    ),
+                    Ok(_) => {}
+                    Err(e) => error!("Reconciliation of {} failed: {}", zone.config.dns_suffix, e),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::ZoneConfig;
+    use crate::localdns::localdns::{LocalDns, LocalZone};
+
+    fn site(user_id: &str, business_id: &str, hostname: &str) -> Site {
+        Site {
+            user_id: user_id.to_string(),
+            business_id: business_id.to_string(),
+            server_names: vec![hostname.to_string()],
+            available_path: PathBuf::from("/nonexistent/available"),
+            enabled_path: PathBuf::from("/nonexistent/enabled"),
+        }
+    }
+
+    fn record(name: &str, content: &str) -> DnsRecord {
+        DnsRecord::new(
+            record_type_for(content).to_string(),
+            name.to_string(),
+            1,
+            content.to_string(),
+            true,
+        )
+    }
+
+    #[test]
+    fn test_claim_of() {
+        assert_eq!(
+            Some("alice".to_string()),
+            claim_of("alice.floy.id", "floy.id")
+        );
+        assert_eq!(
+            Some("alice".to_string()),
+            claim_of("blog.alice.floy.id", "floy.id")
+        );
+        assert_eq!(None, claim_of("floy.id", "floy.id"));
+        assert_eq!(None, claim_of("alice.example.com", "floy.id"));
+    }
+
+    #[test]
+    fn test_compare() {
+        let ip = "10.0.0.1".to_string();
+        let records = vec![record("alice.floy.id", &ip), record("orphan.floy.id", &ip)];
+        let sites = vec![
+            site("u1", "b1", "alice.floy.id"),
+            site("u2", "b2", "bob.floy.id"),
+        ];
+        let claims: HashSet<String> = ["alice", "bob"].iter().map(|c| c.to_string()).collect();
+
+        let report = compare(
+            &records,
+            &[&ip],
+            &sites,
+            vec!["/etc/nginx/sites-enabled/u3/b3/nginx.conf".to_string()],
+            &claims,
+            "floy.id",
+            &|h| h.ends_with(".floy.id"),
+        );
+
+        assert_eq!(vec!["orphan.floy.id".to_string()], report.dns_without_site);
+        assert_eq!(1, report.sites_without_dns.len());
+        assert_eq!("bob.floy.id", report.sites_without_dns[0].hostname);
+        assert_eq!("A", report.sites_without_dns[0].record_type);
+        assert_eq!(2, report.missing_symlinks.len());
+        assert_eq!(1, report.dangling_symlinks.len());
+        assert_eq!(
+            vec!["orphan.floy.id".to_string()],
+            report.unclaimed_hostnames
+        );
+        assert!(report.has_drift());
+    }
+
+    #[test]
+    fn test_compare_missing_family() {
+        let (ip, ipv6) = ("10.0.0.1".to_string(), "2001:db8::1".to_string());
+        let records = vec![record("alice.floy.id", &ip)];
+        let sites = vec![site("u1", "b1", "alice.floy.id")];
+        let claims: HashSet<String> = ["alice".to_string()].into();
+
+        let report = compare(
+            &records,
+            &[&ip, &ipv6],
+            &sites,
+            vec![],
+            &claims,
+            "floy.id",
+            &|h| h.ends_with(".floy.id"),
+        );
+
+        assert!(report.dns_without_site.is_empty());
+        assert_eq!(1, report.sites_without_dns.len());
+        assert_eq!("AAAA", report.sites_without_dns[0].record_type);
+        assert_eq!(ipv6, report.sites_without_dns[0].content);
+    }
+
+    #[test]
+    fn test_recreate_options() {
+        let mut alice = site("u1", "b1", "alice.floy.id");
+        alice.server_names.push("*.alice.floy.id".to_string());
+        let mut surviving = record("alice.floy.id", "10.0.0.1");
+        surviving.ttl = 300;
+        surviving.proxied = false;
+        let missing = MissingRecord {
+            user_id: "u1".to_string(),
+            business_id: "b1".to_string(),
+            hostname: "alice.floy.id".to_string(),
+            record_type: "AAAA".to_string(),
+            content: "2001:db8::1".to_string(),
+        };
+
+        let options =
+            recreate_options(&missing, &[surviving.clone()], std::slice::from_ref(&alice));
+        assert_eq!(
+            RecordOptions {
+                wildcard: true,
+                ttl: 300,
+                proxied: false,
+            },
+            options
+        );
+
+        // The wildcard of this family is still there, only the name is gone.
+        let wildcard = record("*.alice.floy.id", "2001:db8::1");
+        let options = recreate_options(&missing, &[surviving, wildcard], &[alice]);
+        assert!(!options.wildcard);
+
+        let options = recreate_options(&missing, &[], &[site("u1", "b1", "alice.floy.id")]);
+        assert_eq!(RecordOptions::default(), options);
+    }
+
+    #[test]
+    fn test_compare_wildcard_site() {
+        let ip = "10.0.0.1".to_string();
+        let records = vec![record("alice.floy.id", &ip), record("*.alice.floy.id", &ip)];
+        let mut wildcard = site("u1", "b1", "alice.floy.id");
+        wildcard.server_names.push("*.alice.floy.id".to_string());
+        let claims: HashSet<String> = ["alice".to_string()].into();
+
+        let report = compare(
+            &records,
+            &[&ip],
+            &[wildcard],
+            vec![],
+            &claims,
+            "floy.id",
+            &|h| h.ends_with(".floy.id"),
+        );
+
+        assert!(report.dns_without_site.is_empty());
+        assert!(report.sites_without_dns.is_empty());
+        assert!(report.unclaimed_hostnames.is_empty());
+        assert_eq!(1, report.missing_symlinks.len());
+    }
+
+    #[tokio::test]
+    async fn test_compare_nested_zone() {
+        let config = Config {
+            zones: ["floy.site", "eu.floy.site"]
+                .iter()
+                .map(|suffix| ZoneConfig {
+                    dns_suffix: suffix.to_string(),
+                    ip: "10.0.0.1".to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let zones = Zones::new(&config).await;
+        let in_zone = |h: &str| {
+            zones
+                .for_hostname(h)
+                .is_some_and(|zone| zone.config.dns_suffix == "floy.site")
+        };
+
+        let ip = "10.0.0.1".to_string();
+        let records = vec![record("bob.floy.site", &ip)];
+        let sites = vec![
+            site("u1", "b1", "alice.eu.floy.site"),
+            site("u2", "b2", "bob.floy.site"),
+        ];
+        let claims: HashSet<String> = ["bob".to_string()].into();
+
+        let report = compare(
+            &records,
+            &[&ip],
+            &sites,
+            vec![],
+            &claims,
+            "floy.site",
+            &in_zone,
+        );
+
+        assert!(report.sites_without_dns.is_empty());
+        assert!(report.unclaimed_hostnames.is_empty());
+        assert_eq!(1, report.missing_symlinks.len());
+        assert_eq!("bob.floy.site", report.missing_symlinks[0].hostname);
+    }
+
+    #[tokio::test]
+    async fn test_repair_keeps_nested_records() {
+        let cfg = Config {
+            dns_suffix: "floy.id".to_string(),
+            ip: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let local = Arc::new(
+            LocalZone::load("floy.id", cfg.nameservers(), vec![], None)
+                .await
+                .unwrap(),
+        );
+        let provider = LocalDns::from_zone(cfg.clone(), local.clone());
+        for subdomain in ["orphan", "blog.alice"] {
+            provider
+                .add_subdomain_dns_record(subdomain, "10.0.0.1", &RecordOptions::default())
+                .await
+                .unwrap();
+        }
+
+        let mut report = DriftReport {
+            dns_without_site: vec![
+                "orphan.floy.id".to_string(),
+                "blog.alice.floy.id".to_string(),
+            ],
+            ..Default::default()
+        };
+        repair(&mut report, &provider, &[], &[], &cfg).await;
+
+        let names: Vec<String> = local.records().into_iter().map(|r| r.name).collect();
+        assert!(!names.contains(&"orphan.floy.id".to_string()));
+        assert!(names.contains(&"blog.alice.floy.id".to_string()));
+        assert_eq!(1, report.repaired.len());
+        assert!(report.errors.is_empty());
+    }
+}
@@ -1 +1,2 @@
+pub mod reload;
 pub mod updater;
@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::nginx::nginx::NginxServer;
+
+/// The reloader every `updater` change goes through.
+pub(crate) static RELOADER: Reloader = Reloader::new();
+
+#[derive(Clone)]
+struct Commands {
+    test: String,
+    reload: String,
+    debounce: Duration,
+}
+
+impl Commands {
+    fn from_config(cfg: &Config) -> Self {
+        Commands {
+            test: cfg.nginx_test_command.trim().to_string(),
+            reload: cfg.nginx_reload_command.trim().to_string(),
+            debounce: Duration::from_millis(cfg.nginx_reload_debounce_ms),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.test.is_empty() && self.reload.is_empty()
+    }
+}
+
+/// Sites changed since nginx was last tested, with their `sites-enabled`
+/// links, and the changes waiting to hear how the test went.
+struct Burst {
+    sites: BTreeMap<PathBuf, PathBuf>,
+    waiters: Vec<Sender<std::result::Result<(), String>>>,
+    generation: u64,
+}
+
+/// Tests and reloads nginx once a burst of site changes is over. When the
+/// test fails, every site changed during the burst is rolled back to the
+/// version it had before the burst and nginx is left as it was. Every
+/// change of the burst is told the outcome.
+pub struct Reloader {
+    burst: Mutex<Burst>,
+}
+
+/// Runs `command` through the shell, failing with its output when it exits
+/// unsuccessfully. An empty command does nothing.
+fn run(command: &str) -> std::result::Result<(), String> {
+    if command.is_empty() {
+        return Ok(());
+    }
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| format!("{}: {}", command, e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} exited with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+impl Reloader {
+    pub const fn new() -> Self {
+        Reloader {
+            burst: Mutex::new(Burst {
+                sites: BTreeMap::new(),
+                waiters: vec![],
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Runs `write` to change the site at `available_path`, then waits for
+    /// nginx to be tested and reloaded, failing when the test rolled the
+    /// change back. `write` is told whether this is the burst's first change
+    /// to the site; only that one may replace the site's backup.
+    ///
+    /// This blocks for the debounce and the test, so async code runs it on
+    /// the blocking pool, see [`crate::updater::updater::blocking`].
+    pub fn apply(
+        &self,
+        available_path: &Path,
+        enabled_path: &Path,
+        cfg: &Config,
+        write: impl FnOnce(bool) -> Result<()>,
+    ) -> Result<()> {
+        let commands = Commands::from_config(cfg);
+        if commands.is_empty() {
+            write(true)?;
+            return forget_if_deleted(available_path);
+        }
+
+        let (generation, outcome) = {
+            let mut burst = self.burst.lock().unwrap();
+            write(!burst.sites.contains_key(available_path))?;
+
+            burst
+                .sites
+                .insert(available_path.to_path_buf(), enabled_path.to_path_buf());
+            let (sender, outcome) = channel();
+            burst.waiters.push(sender);
+            burst.generation += 1;
+            (burst.generation, outcome)
+        };
+
+        std::thread::sleep(commands.debounce);
+        self.flush(generation, &commands);
+
+        match outcome.recv() {
+            Ok(result) => result.map_err(Error::other),
+            Err(_) => Err(Error::other("nginx tidak dites")),
+        }
+    }
+
+    /// Tests and reloads nginx unless another change arrived after the one
+    /// numbered `generation`, whose caller then takes care of it.
+    fn flush(&self, generation: u64, commands: &Commands) {
+        // Held until nginx is reloaded or the burst rolled back: a write
+        // arriving meanwhile has to back up the restored site, not the one
+        // being tested. Only callers on the blocking pool wait for it.
+        let mut burst = self.burst.lock().unwrap();
+        if burst.generation != generation {
+            return;
+        }
+        let sites = std::mem::take(&mut burst.sites);
+        let waiters = std::mem::take(&mut burst.waiters);
+
+        let result = match run(&commands.test) {
+            Ok(()) => {
+                // Deleted sites are kept as backups until nginx took it.
+                for available_path in sites.keys() {
+                    if let Err(e) = forget_if_deleted(available_path) {
+                        error!(
+                            "Failed to drop the backup of {}: {}",
+                            available_path.display(),
+                            e
+                        );
+                    }
+                }
+                run(&commands.reload).map_err(|e| {
+                    error!("nginx reload failed: {}", e);
+                    format!("nginx reload gagal: {}", e)
+                })
+            }
+            Err(e) => {
+                error!(
+                    "nginx config test failed, rolling back {} site(s): {}",
+                    sites.len(),
+                    e
+                );
+                for (available_path, enabled_path) in &sites {
+                    if let Err(e) = rollback(available_path, enabled_path) {
+                        error!("Failed to roll back {}: {}", available_path.display(), e);
+                    }
+                }
+                Err(format!(
+                    "Tes config nginx gagal, perubahan dibatalkan: {}",
+                    e
+                ))
+            }
+        };
+
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+/// Restores a site's backup. A site the burst created is removed together
+/// with its link, which nginx would otherwise fail to include, and a site
+/// it deleted is linked again.
+fn rollback(available_path: &Path, enabled_path: &Path) -> Result<()> {
+    NginxServer::rollback(available_path)?;
+
+    let linked = std::fs::symlink_metadata(enabled_path).is_ok();
+    if linked && !available_path.exists() {
+        std::fs::remove_file(enabled_path)?;
+    } else if !linked && available_path.exists() {
+        std::os::unix::fs::symlink(available_path, enabled_path)?;
+    }
+
+    Ok(())
+}
+
+fn forget_if_deleted(available_path: &Path) -> Result<()> {
+    if available_path.exists() {
+        return Ok(());
+    }
+    NginxServer::discard_backup(available_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::thread;
+
+    use super::*;
+    use crate::nginx::nginx::{Directive, Location};
+
+    /// A stand-in for nginx: `-t` takes `test_secs` and fails when a site
+    /// contains a `broken` directive, `-s reload` is logged to
+    /// `reloads.log`.
+    fn fake_nginx(dir: &Path, test_secs: f32) -> Config {
+        let script = dir.join("fake-nginx");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                 case \"$1\" in\n\
+                 -t) sleep {secs}; ! grep -rq '^ *broken;' {dir}/sites ;;\n\
+                 -s) echo reload >> {dir}/reloads.log ;;\n\
+                 esac\n",
+                secs = test_secs,
+                dir = dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Config {
+            nginx_test_command: format!("{} -t", script.display()),
+            nginx_reload_command: format!("{} -s reload", script.display()),
+            nginx_reload_debounce_ms: 100,
+            ..Default::default()
+        }
+    }
+
+    fn setup(name: &str, test_secs: f32) -> (PathBuf, Config) {
+        let dir = std::env::temp_dir().join(format!("floy-reload-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sites")).unwrap();
+        fs::create_dir_all(dir.join("enabled")).unwrap();
+
+        let cfg = fake_nginx(&dir, test_secs);
+        (dir, cfg)
+    }
+
+    fn reloads(dir: &Path) -> usize {
+        fs::read_to_string(dir.join("reloads.log"))
+            .map(|log| log.lines().count())
+            .unwrap_or(0)
+    }
+
+    fn save(
+        reloader: &Reloader,
+        dir: &Path,
+        name: &str,
+        server: &NginxServer,
+        cfg: &Config,
+    ) -> Result<()> {
+        let available_path = dir.join("sites").join(name);
+        let enabled_path = dir.join("enabled").join(name);
+
+        reloader.apply(&available_path, &enabled_path, cfg, |first| {
+            if first {
+                server.save(&available_path)?;
+            } else {
+                server.save_keeping_backup(&available_path)?;
+            }
+            if fs::symlink_metadata(&enabled_path).is_err() {
+                symlink(&available_path, &enabled_path)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn delete(reloader: &Reloader, dir: &Path, name: &str, cfg: &Config) -> Result<()> {
+        let available_path = dir.join("sites").join(name);
+        let enabled_path = dir.join("enabled").join(name);
+
+        reloader.apply(&available_path, &enabled_path, cfg, |first| {
+            fs::remove_file(&enabled_path)?;
+            NginxServer::delete(&available_path, first)
+        })
+    }
+
+    /// Saves `server` as `name` on a thread of its own after `delay_ms`, so
+    /// several changes make up one burst.
+    fn save_later(
+        reloader: &'static Reloader,
+        dir: &Path,
+        name: &'static str,
+        server: &NginxServer,
+        cfg: &Config,
+        delay_ms: u64,
+    ) -> thread::JoinHandle<Result<()>> {
+        let (dir, server, cfg) = (dir.to_path_buf(), server.clone(), cfg.clone());
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(delay_ms));
+            save(reloader, &dir, name, &server, &cfg)
+        })
+    }
+
+    #[test]
+    fn test_burst_reloads_once() {
+        static RELOADER: Reloader = Reloader::new();
+        let (dir, cfg) = setup("burst", 0.0);
+
+        let mut server = NginxServer::new(vec!["alice.floy.id".to_string()], "/srv");
+        let first = save_later(&RELOADER, &dir, "alice.conf", &server, &cfg, 0);
+        server
+            .add_location(Location::slug("about", "/s1/index.html"))
+            .unwrap();
+        let burst = [
+            first,
+            save_later(&RELOADER, &dir, "alice.conf", &server, &cfg, 20),
+            save_later(&RELOADER, &dir, "bob.conf", &server, &cfg, 40),
+        ];
+        for change in burst {
+            change.join().unwrap().unwrap();
+        }
+        assert_eq!(1, reloads(&dir));
+
+        // The change only returns once nginx was reloaded.
+        save(&RELOADER, &dir, "bob.conf", &server, &cfg).unwrap();
+        assert_eq!(2, reloads(&dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_test_rolls_back_burst() {
+        static RELOADER: Reloader = Reloader::new();
+        let (dir, cfg) = setup("rollback", 0.0);
+
+        let mut server = NginxServer::new(vec!["alice.floy.id".to_string()], "/srv");
+        save(&RELOADER, &dir, "alice.conf", &server, &cfg).unwrap();
+        assert_eq!(1, reloads(&dir));
+        let good = fs::read_to_string(dir.join("sites/alice.conf")).unwrap();
+
+        // Two changes in one burst, the second breaking nginx, plus a new
+        // site: all of them are undone and told so.
+        server
+            .add_location(Location::slug("about", "/s1/index.html"))
+            .unwrap();
+        let mut broken = server.clone();
+        broken.directives.push(Directive::new("broken", &[]));
+        let burst = [
+            save_later(&RELOADER, &dir, "alice.conf", &server, &cfg, 0),
+            save_later(&RELOADER, &dir, "alice.conf", &broken, &cfg, 20),
+            save_later(&RELOADER, &dir, "carol.conf", &server, &cfg, 40),
+        ];
+        for change in burst {
+            assert!(change.join().unwrap().is_err());
+        }
+
+        assert_eq!(1, reloads(&dir));
+        assert_eq!(
+            good,
+            fs::read_to_string(dir.join("sites/alice.conf")).unwrap()
+        );
+        assert!(!dir.join("sites/carol.conf").exists());
+        assert!(fs::symlink_metadata(dir.join("enabled/carol.conf")).is_err());
+        assert!(fs::symlink_metadata(dir.join("enabled/alice.conf")).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_during_flush_waits_for_rollback() {
+        static RELOADER: Reloader = Reloader::new();
+        let (dir, cfg) = setup("during-flush", 0.4);
+
+        let mut server = NginxServer::new(vec!["alice.floy.id".to_string()], "/srv");
+        save(&RELOADER, &dir, "alice.conf", &server, &cfg).unwrap();
+        assert_eq!(1, reloads(&dir));
+        let good = fs::read_to_string(dir.join("sites/alice.conf")).unwrap();
+
+        let mut broken = server.clone();
+        broken.directives.push(Directive::new("broken", &[]));
+        let failing = save_later(&RELOADER, &dir, "alice.conf", &broken, &cfg, 0);
+
+        // Past the debounce, nginx is being tested: this write has to wait
+        // for the rollback and then back up the restored site.
+        thread::sleep(Duration::from_millis(250));
+        server
+            .add_location(Location::slug("about", "/s1/index.html"))
+            .unwrap();
+        save(&RELOADER, &dir, "alice.conf", &server, &cfg).unwrap();
+        assert!(failing.join().unwrap().is_err());
+        assert_eq!(
+            good,
+            fs::read_to_string(dir.join("sites/.floy-backup/alice.conf.bak")).unwrap()
+        );
+
+        assert_eq!(2, reloads(&dir));
+        assert_eq!(
+            server.render(),
+            fs::read_to_string(dir.join("sites/alice.conf")).unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_test_restores_deleted_site() {
+        static RELOADER: Reloader = Reloader::new();
+        let (dir, cfg) = setup("delete", 0.0);
+
+        let server = NginxServer::new(vec!["alice.floy.id".to_string()], "/srv");
+        save(&RELOADER, &dir, "alice.conf", &server, &cfg).unwrap();
+        save(&RELOADER, &dir, "bob.conf", &server, &cfg).unwrap();
+        let good = fs::read_to_string(dir.join("sites/alice.conf")).unwrap();
+
+        let mut broken = server.clone();
+        broken.directives.push(Directive::new("broken", &[]));
+        let failing = save_later(&RELOADER, &dir, "bob.conf", &broken, &cfg, 20);
+        assert!(delete(&RELOADER, &dir, "alice.conf", &cfg).is_err());
+        assert!(failing.join().unwrap().is_err());
+
+        assert_eq!(
+            good,
+            fs::read_to_string(dir.join("sites/alice.conf")).unwrap()
+        );
+        assert!(fs::symlink_metadata(dir.join("enabled/alice.conf")).is_ok());
+
+        // Once nginx took the delete the backup goes too.
+        delete(&RELOADER, &dir, "alice.conf", &cfg).unwrap();
+        assert!(!dir.join("sites/alice.conf").exists());
+        assert!(!dir.join("sites/.floy-backup/alice.conf.bak").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,120 +1,453 @@
-use crate::config::Config;
+use crate::config::{Config, SiteLayout};
+use crate::nginx::nginx::{Location, NginxServer, Tls};
+use crate::updater::reload::RELOADER;
 use std::fs;
 use std::io::{Error, ErrorKind, Result};
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
-use crate::parser::parser::validate_config;
-
-const SITES_AVAILABLE_BASE: &str = "/etc/nginx/sites-available";
-const SITES_ENABLED_BASE: &str = "/etc/nginx/sites-enabled";
-
-pub(crate) fn get_domain_paths(user_id: &str, business_id: &str) -> (PathBuf, PathBuf) {
-    let available = Path::new(SITES_AVAILABLE_BASE)
-        .join(user_id)
-        .join(business_id)
-        .join("nginx.conf");
-    let enabled = Path::new(SITES_ENABLED_BASE)
-        .join(user_id)
-        .join(business_id)
-        .join("nginx.conf");
-    (available, enabled)
+
+/// Runs an updater change on the blocking pool. Changes wait for nginx to
+/// be tested and reloaded, which must not hold up an async worker.
+pub async fn blocking<T: Send + 'static>(
+    change: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(change)
+        .await
+        .unwrap_or_else(|e| Err(Error::other(e)))
 }
 
-pub fn create_domain(
+/// Paths of a business' site in the zone `cfg` is scoped to, see
+/// [`Config::site_file_name`]. Without a `sites_enabled_dir` the site is
+/// its own enabled path.
+pub(crate) fn get_domain_paths(
+    user_id: &str,
+    business_id: &str,
+    cfg: &Config,
+) -> (PathBuf, PathBuf) {
+    site_paths(&cfg.site_file_name(user_id, business_id), cfg)
+}
+
+/// Paths of the site serving `domain`. A nested hostname like `blog.alice`
+/// gets a site of its own rather than replacing the business' site.
+pub(crate) fn get_host_paths(
     user_id: &str,
     business_id: &str,
     domain: &str,
     cfg: &Config,
-) -> Result<()> {
-    let (available_path, enabled_path) = get_domain_paths(user_id, business_id);
+) -> (PathBuf, PathBuf) {
+    if !domain.contains('.') {
+        return get_domain_paths(user_id, business_id, cfg);
+    }
+    let hostname = format!("{}.{}", domain, cfg.dns_suffix);
+    site_paths(&cfg.host_site_file_name(user_id, business_id, &hostname), cfg)
+}
 
-    if let Some(dir) = available_path.parent() {
-        fs::create_dir_all(dir)?;
+fn site_paths(file_name: &Path, cfg: &Config) -> (PathBuf, PathBuf) {
+    let available = Path::new(&cfg.sites_available_dir).join(file_name);
+    let enabled = if cfg.sites_enabled_dir.is_empty() {
+        available.clone()
     } else {
-        return Err(Error::new(ErrorKind::InvalidData, "Path tidak valid"));
+        Path::new(&cfg.sites_enabled_dir).join(file_name)
+    };
+    (available, enabled)
+}
+
+fn is_site_file(name: &str) -> bool {
+    name.starts_with("nginx") && name.ends_with(".conf")
+}
+
+/// A site found in `sites_available_dir`.
+pub(crate) struct Site {
+    pub user_id: String,
+    pub business_id: String,
+    pub server_names: Vec<String>,
+    pub available_path: PathBuf,
+    pub enabled_path: PathBuf,
+}
+
+fn read_server_names(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("server_name"))
+        .flat_map(|names| names.trim_end_matches(';').split_whitespace())
+        .fold(vec![], |mut names, name| {
+            // HTTPS sites repeat their names in the redirect block.
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+            names
+        })
+}
+
+fn read_dir_names(path: &Path) -> Result<Vec<String>> {
+    if !path.is_dir() {
+        return Ok(vec![]);
     }
 
-    let config_template = format!(
-        r#"server {{
-    listen 80;
-    server_name {}.{};
-    root {}/{}/{};
-    index index.html;
+    let mut names = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    Ok(names)
+}
+
+fn site_at(available_path: PathBuf, user_id: &str, business_id: &str, cfg: &Config) -> Site {
+    let enabled_path = match available_path.strip_prefix(&cfg.sites_available_dir) {
+        Ok(relative) if !cfg.sites_enabled_dir.is_empty() => {
+            Path::new(&cfg.sites_enabled_dir).join(relative)
+        }
+        _ => available_path.clone(),
+    };
+
+    Site {
+        user_id: user_id.to_string(),
+        business_id: business_id.to_string(),
+        server_names: vec![],
+        available_path,
+        enabled_path,
+    }
+}
 
-    location / {{
-        return 404;
-    }}
-}}
-"#,
-        domain, cfg.dns_suffix, cfg.prefix, user_id, business_id,
-    );
+/// Sites laid out as `<user>/<business>/nginx*.conf`.
+fn list_nested_sites(cfg: &Config) -> Result<Vec<Site>> {
+    let base = Path::new(&cfg.sites_available_dir);
+    let mut sites = vec![];
 
-    fs::write(&available_path, config_template)?;
+    for user_id in read_dir_names(base)? {
+        for business_id in read_dir_names(&base.join(&user_id))? {
+            for entry in fs::read_dir(base.join(&user_id).join(&business_id))? {
+                let available_path = entry?.path();
+                let file_name = available_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
 
-    if !enabled_path.exists() {
-        symlink(&available_path, &enabled_path)?;
+                if !available_path.is_file() || !is_site_file(&file_name) {
+                    continue;
+                }
+
+                let server_names = read_server_names(&fs::read_to_string(&available_path)?);
+                sites.push(Site {
+                    server_names,
+                    ..site_at(available_path, &user_id, &business_id, cfg)
+                });
+            }
+        }
     }
 
-    Ok(())
+    Ok(sites)
 }
 
-pub fn delete_domain(user_id: &str, business_id: &str) -> Result<()> {
-    let (available_path, enabled_path) = get_domain_paths(user_id, business_id);
+/// Sites laid out as `<user>-<business>*.conf`. Ids may contain dashes, so
+/// the owner is read from the `<prefix>/<user>/<business>` root instead of
+/// the file name. Other files in the directory are skipped.
+fn list_flat_sites(cfg: &Config) -> Result<Vec<Site>> {
+    let base = Path::new(&cfg.sites_available_dir);
+    if !base.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut sites = vec![];
+    for entry in fs::read_dir(base)? {
+        let available_path = entry?.path();
+        let is_conf = available_path.extension().is_some_and(|ext| ext == "conf");
+        if !available_path.is_file() || !is_conf {
+            continue;
+        }
+
+        let server = match NginxServer::load(&available_path) {
+            Ok(server) => server,
+            Err(_) => continue,
+        };
+        let owner = server.root.as_deref().and_then(|root| {
+            root.strip_prefix(&cfg.prefix)?
+                .trim_start_matches('/')
+                .split_once('/')
+                .map(|(user, business)| (user.to_string(), business.to_string()))
+        });
 
-    if enabled_path.exists() {
-        fs::remove_file(&enabled_path)?;
+        if let Some((user_id, business_id)) = owner {
+            sites.push(Site {
+                server_names: server.server_names,
+                ..site_at(available_path, &user_id, &business_id, cfg)
+            });
+        }
     }
-    if available_path.exists() {
-        fs::remove_file(&available_path)?;
+
+    Ok(sites)
+}
+
+pub(crate) fn list_sites(cfg: &Config) -> Result<Vec<Site>> {
+    match cfg.site_layout {
+        SiteLayout::Nested => list_nested_sites(cfg),
+        SiteLayout::Flat => list_flat_sites(cfg),
+    }
+}
+
+fn collect_dangling_symlinks(dir: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::symlink_metadata(&path)?;
+
+        if meta.file_type().is_symlink() {
+            if !path.exists() {
+                found.push(path);
+            }
+        } else if meta.is_dir() {
+            collect_dangling_symlinks(&path, found)?;
+        }
     }
     Ok(())
 }
 
-fn insert_location_block(content: &mut String, location_block: &str) -> Result<()> {
-    if let Some(pos) = content.rfind('}') {
-        content.insert_str(pos, location_block);
+/// Symlinks under `sites_enabled_dir` whose target no longer exists.
+pub(crate) fn list_dangling_symlinks(cfg: &Config) -> Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    let enabled = Path::new(&cfg.sites_enabled_dir);
+    if !cfg.sites_enabled_dir.is_empty() && enabled.is_dir() {
+        collect_dangling_symlinks(enabled, &mut found)?;
+    }
+    Ok(found)
+}
+
+/// (Re)creates the enabled symlink of a site, replacing a stale one.
+pub(crate) fn enable_site(site: &Site) -> Result<()> {
+    if site.enabled_path == site.available_path {
+        return Ok(());
+    }
+
+    if fs::symlink_metadata(&site.enabled_path).is_ok() {
+        fs::remove_file(&site.enabled_path)?;
+    }
+    if let Some(dir) = site.enabled_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    symlink(&site.available_path, &site.enabled_path)
+}
+
+/// Finds the site whose `server_name` serves `hostname`.
+pub(crate) fn find_site(hostname: &str, cfg: &Config) -> Result<Option<Site>> {
+    Ok(list_sites(cfg)?
+        .into_iter()
+        .find(|site| site.server_names.iter().any(|name| name == hostname)))
+}
+
+/// Finds the sites of hostnames nested under `hostname`, like
+/// `blog.alice.<suffix>` under `alice.<suffix>`.
+pub(crate) fn find_nested_sites(hostname: &str, cfg: &Config) -> Result<Vec<Site>> {
+    let nested = format!(".{}", hostname);
+    Ok(list_sites(cfg)?
+        .into_iter()
+        .filter(|site| {
+            site.server_names
+                .iter()
+                .any(|name| name.ends_with(&nested) && !name.starts_with("*."))
+        })
+        .collect())
+}
+
+/// The document root of a business' site, which also records its owner.
+fn site_root(user_id: &str, business_id: &str, cfg: &Config) -> String {
+    format!("{}/{}/{}", cfg.prefix, user_id, business_id)
+}
+
+/// Whether the site at `available_path` belongs to another business. Flat
+/// site files are named `<user>-<business>.conf`, so user `a-b` with
+/// business `c` and user `a` with business `b-c` share a file; its `root`
+/// tells them apart.
+fn owned_by_other(available_path: &Path, root: &str, cfg: &Config) -> Result<bool> {
+    if cfg.site_layout != SiteLayout::Flat || !available_path.exists() {
+        return Ok(false);
+    }
+    Ok(NginxServer::load(available_path)?.root.as_deref() != Some(root))
+}
+
+/// Loads the site of a business.
+pub(crate) fn load_site(user_id: &str, business_id: &str, cfg: &Config) -> Result<NginxServer> {
+    let (available_path, _) = get_domain_paths(user_id, business_id, cfg);
+    let server = NginxServer::load(&available_path)?;
+    if cfg.site_layout == SiteLayout::Flat
+        && server.root.as_deref() != Some(site_root(user_id, business_id, cfg).as_str())
+    {
+        return Err(Error::new(ErrorKind::NotFound, "Site tidak ditemukan"));
+    }
+    Ok(server)
+}
+
+pub fn create_domain(
+    user_id: &str,
+    business_id: &str,
+    domain: &str,
+    wildcard: bool,
+    cfg: &Config,
+) -> Result<()> {
+    let (available_path, enabled_path) = get_host_paths(user_id, business_id, domain, cfg);
+    if available_path.exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, "Site sudah ada"));
+    }
+
+    for path in [&available_path, &enabled_path] {
+        match path.parent() {
+            Some(dir) => fs::create_dir_all(dir)?,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Path tidak valid")),
+        }
+    }
+
+    let hostname = format!("{}.{}", domain, cfg.dns_suffix);
+    let mut server_names = vec![hostname.clone()];
+    if wildcard {
+        server_names.push(format!("*.{}", hostname));
+    }
+
+    let root = site_root(user_id, business_id, cfg);
+    let mut server = NginxServer::new(server_names, &root);
+    if cfg.acme_enabled() {
+        server.acme_webroot = Some(cfg.acme_webroot.clone());
+    }
+
+    RELOADER.apply(&available_path, &enabled_path, cfg, |first| {
+        write_site(&server, &available_path, first)?;
+
+        if !enabled_path.exists() {
+            if let Err(e) = symlink(&available_path, &enabled_path) {
+                NginxServer::rollback(&available_path)?;
+                return Err(e);
+            }
+        }
         Ok(())
+    })
+}
+
+pub fn delete_domain(user_id: &str, business_id: &str, domain: &str, cfg: &Config) -> Result<()> {
+    let (available_path, enabled_path) = get_host_paths(user_id, business_id, domain, cfg);
+    if owned_by_other(&available_path, &site_root(user_id, business_id, cfg), cfg)? {
+        return Err(Error::new(ErrorKind::NotFound, "Site tidak ditemukan"));
+    }
+
+    RELOADER.apply(&available_path, &enabled_path, cfg, |first| {
+        if enabled_path != available_path && fs::symlink_metadata(&enabled_path).is_ok() {
+            fs::remove_file(&enabled_path)?;
+        }
+        NginxServer::delete(&available_path, first)
+    })
+}
+
+/// Only the first write of a burst replaces the backup, so a failed nginx
+/// test rolls the site back to where it was before the burst.
+fn write_site(server: &NginxServer, available_path: &Path, first: bool) -> Result<()> {
+    if first {
+        server.save(available_path)
     } else {
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            "Format file konfigurasi tidak valid",
-        ))
+        server.save_keeping_backup(available_path)
     }
 }
 
-pub fn add_slug_page(
+/// Saves the changed site of a business and has nginx tested and reloaded.
+fn save_site(server: &NginxServer, user_id: &str, business_id: &str, cfg: &Config) -> Result<()> {
+    let (available_path, enabled_path) = get_domain_paths(user_id, business_id, cfg);
+
+    RELOADER.apply(&available_path, &enabled_path, cfg, |first| {
+        write_site(server, &available_path, first)
+    })
+}
+
+/// Rewrites the `server_name` of a business' site with `edit` applied to
+/// its hostnames.
+fn edit_server_names(
     user_id: &str,
     business_id: &str,
-    slug: &str,
-    site_id: &str,
-    base_path: &str,
+    cfg: &Config,
+    edit: impl FnOnce(&mut Vec<String>),
 ) -> Result<()> {
-    let (available_path, _) = get_domain_paths(user_id, business_id);
+    let mut server = load_site(user_id, business_id, cfg)?;
 
-    let mut content = fs::read_to_string(&available_path)?;
+    if server.server_names.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "server_name tidak ditemukan",
+        ));
+    }
+    edit(&mut server.server_names);
 
-    let target = format!("/{}/index.html", site_id);
+    save_site(&server, user_id, business_id, cfg)
+}
 
-    let location_block = format!(
-        r#"
-location /{} {{
-    rewrite ^/{}$ {} break;
-}}
-"#,
-        slug,
-        slug,
-        target
-    );
+/// Has the business' site answer ACME HTTP-01 challenges. Returns false
+/// when it already did.
+pub fn enable_acme_challenges(user_id: &str, business_id: &str, cfg: &Config) -> Result<bool> {
+    let mut server = load_site(user_id, business_id, cfg)?;
 
-    content.push_str(&location_block);
+    if server.acme_webroot.as_deref() == Some(cfg.acme_webroot.as_str()) {
+        return Ok(false);
+    }
+    server.acme_webroot = Some(cfg.acme_webroot.clone());
 
-    fs::write(&available_path, &content)?;
+    save_site(&server, user_id, business_id, cfg)?;
+    Ok(true)
+}
 
-    validate_config(Path::new(&available_path))?;
+/// Serves the business' site over HTTPS with `tls`. Saving an unchanged
+/// site still reloads nginx, which picks up a renewed certificate.
+pub fn enable_https(user_id: &str, business_id: &str, tls: &Tls, cfg: &Config) -> Result<()> {
+    let mut server = load_site(user_id, business_id, cfg)?;
 
-    Ok(())
+    server.acme_webroot = Some(cfg.acme_webroot.clone());
+    if server.tls.is_none() {
+        server.enable_tls(tls.clone());
+    }
+    server.tls = Some(tls.clone());
+
+    save_site(&server, user_id, business_id, cfg)
 }
 
+/// Serves `hostname` (a verified custom domain) from the business' site.
+pub fn add_server_name(
+    user_id: &str,
+    business_id: &str,
+    hostname: &str,
+    cfg: &Config,
+) -> Result<()> {
+    edit_server_names(user_id, business_id, cfg, |names| {
+        if !names.iter().any(|name| name == hostname) {
+            names.push(hostname.to_string());
+        }
+    })
+}
+
+pub fn remove_server_name(
+    user_id: &str,
+    business_id: &str,
+    hostname: &str,
+    cfg: &Config,
+) -> Result<()> {
+    edit_server_names(user_id, business_id, cfg, |names| {
+        names.retain(|name| name != hostname)
+    })
+}
+
+/// Maps `/<slug>` to the index page of `site_id`.
+pub fn add_slug_page(
+    user_id: &str,
+    business_id: &str,
+    slug: &str,
+    site_id: &str,
+    cfg: &Config,
+) -> Result<()> {
+    let mut server = load_site(user_id, business_id, cfg)?;
+
+    let target = format!("/{}/index.html", site_id);
+    server.add_location(Location::slug(slug, &target))?;
+
+    save_site(&server, user_id, business_id, cfg)
+}
+
+/// Replaces the slug page `previous_slug` with `slug`, pointing at
+/// `rewrite_target` or else at the index page of `new_site`.
 pub fn update_slug_page(
     user_id: &str,
     business_id: &str,
@@ -122,41 +455,177 @@ pub fn update_slug_page(
     previous_slug: &str,
     new_site: &str,
     rewrite_target: Option<&str>,
+    cfg: &Config,
 ) -> Result<()> {
-    let (available_path, _) = get_domain_paths(user_id, business_id);
-    let mut content = fs::read_to_string(&available_path)?;
-    let start_marker = format!("location /{} {{", previous_slug);
-
-    if let Some(start) = content.find(&start_marker) {
-        if let Some(end) = content[start..].find('}') {
-            let end_index = start + end + 1;
-            let target = rewrite_target.unwrap_or(&format!("/{}/index.html", new_site));
-            let new_block = format!(
-                r#"location /{slug} {{
-        rewrite ^/{slug}$ {} break;
-    }}"#,
-                slug
-            );
-            content.replace_range(start..end_index, &new_block);
-            fs::write(&available_path, content)?;
-            return Ok(());
-        }
-    }
-    Err(Error::new(ErrorKind::NotFound, "Slug page tidak ditemukan"))
+    let mut server = load_site(user_id, business_id, cfg)?;
+
+    let target = match rewrite_target {
+        Some(target) => target.to_string(),
+        None => format!("/{}/index.html", new_site),
+    };
+    server
+        .replace_location(
+            &format!("/{}", previous_slug),
+            Location::slug(slug, &target),
+        )
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::new(ErrorKind::NotFound, "Slug page tidak ditemukan"),
+            _ => e,
+        })?;
+
+    save_site(&server, user_id, business_id, cfg)
 }
 
-pub fn delete_slug_page(user_id: &str, business_id: &str, slug: &str) -> Result<()> {
-    let (available_path, _) = get_domain_paths(user_id, business_id);
-    let mut content = fs::read_to_string(&available_path)?;
-    let start_marker = format!("location /{} {{", slug);
+pub fn delete_slug_page(user_id: &str, business_id: &str, slug: &str, cfg: &Config) -> Result<()> {
+    let mut server = load_site(user_id, business_id, cfg)?;
 
-    if let Some(start) = content.find(&start_marker) {
-        if let Some(end) = content[start..].find('}') {
-            let end_index = start + end + 1;
-            content.replace_range(start..end_index, "");
-            fs::write(&available_path, content)?;
-            return Ok(());
-        }
+    server
+        .remove_location(&format!("/{}", slug))
+        .map_err(|_| Error::new(ErrorKind::NotFound, "Slug page tidak ditemukan"))?;
+
+    save_site(&server, user_id, business_id, cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(name: &str, layout: SiteLayout, enabled: bool) -> (PathBuf, Config) {
+        let dir =
+            std::env::temp_dir().join(format!("floy-updater-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let cfg = Config {
+            dns_suffix: "floy.id".to_string(),
+            prefix: "/srv/sites".to_string(),
+            sites_available_dir: dir.join("available").display().to_string(),
+            sites_enabled_dir: if enabled {
+                dir.join("enabled").display().to_string()
+            } else {
+                String::new()
+            },
+            site_layout: layout,
+            ..Default::default()
+        };
+        (dir, cfg)
+    }
+
+    fn round_trip(cfg: &Config) -> (PathBuf, PathBuf) {
+        create_domain("u-1", "b-1", "alice", false, cfg).unwrap();
+        add_slug_page("u-1", "b-1", "about", "s1", cfg).unwrap();
+
+        let site = find_site("alice.floy.id", cfg).unwrap().unwrap();
+        assert_eq!("u-1", site.user_id);
+        assert_eq!("b-1", site.business_id);
+        assert_eq!(1, list_sites(cfg).unwrap().len());
+
+        let server = NginxServer::load(&site.available_path).unwrap();
+        assert!(server.location("/about").is_some());
+        assert!(site.enabled_path.exists());
+
+        delete_domain("u-1", "b-1", "alice", cfg).unwrap();
+        assert!(!site.available_path.exists());
+        assert!(fs::symlink_metadata(&site.enabled_path).is_err());
+        assert!(list_sites(cfg).unwrap().is_empty());
+
+        (site.available_path, site.enabled_path)
+    }
+
+    #[test]
+    fn test_nested_layout() {
+        let (dir, cfg) = setup("nested", SiteLayout::Nested, true);
+
+        let (available, enabled) = round_trip(&cfg);
+        assert_eq!(dir.join("available/u-1/b-1/nginx.conf"), available);
+        assert_eq!(dir.join("enabled/u-1/b-1/nginx.conf"), enabled);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flat_layout() {
+        let (dir, cfg) = setup("flat", SiteLayout::Flat, true);
+
+        let (available, enabled) = round_trip(&cfg);
+        assert_eq!(dir.join("available/u-1-b-1.conf"), available);
+        assert_eq!(dir.join("enabled/u-1-b-1.conf"), enabled);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flat_layout_without_enabled_dir() {
+        let (dir, cfg) = setup("conf-d", SiteLayout::Flat, false);
+        fs::create_dir_all(dir.join("available")).unwrap();
+        fs::write(dir.join("available/default.conf"), "server { listen 80; }").unwrap();
+        fs::write(dir.join("available/README"), "not a site").unwrap();
+
+        let (available, enabled) = round_trip(&cfg);
+        assert_eq!(dir.join("available/u-1-b-1.conf"), available);
+        assert_eq!(available, enabled);
+        assert!(list_dangling_symlinks(&cfg).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flat_file_name_collision() {
+        let (dir, cfg) = setup("collision", SiteLayout::Flat, true);
+
+        // Both pairs map to `a-b-c.conf`.
+        create_domain("a-b", "c", "alice", false, &cfg).unwrap();
+        let err = create_domain("a", "b-c", "bob", false, &cfg).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+
+        let err = add_slug_page("a", "b-c", "about", "s1", &cfg).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        let err = delete_domain("a", "b-c", "bob", &cfg).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+
+        let site = find_site("alice.floy.id", &cfg).unwrap().unwrap();
+        assert_eq!("a-b", site.user_id);
+        assert_eq!("c", site.business_id);
+        let server = NginxServer::load(&site.available_path).unwrap();
+        assert!(server.location("/about").is_none());
+        assert!(site.enabled_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_nested_host_has_its_own_site() {
+        let (dir, cfg) = setup("nested-host", SiteLayout::Nested, true);
+
+        create_domain("u1", "b1", "alice", false, &cfg).unwrap();
+        add_slug_page("u1", "b1", "about", "s1", &cfg).unwrap();
+        create_domain("u1", "b1", "blog.alice", false, &cfg).unwrap();
+
+        let alice = find_site("alice.floy.id", &cfg).unwrap().unwrap();
+        let blog = find_site("blog.alice.floy.id", &cfg).unwrap().unwrap();
+        assert_ne!(alice.available_path, blog.available_path);
+        assert_eq!(
+            dir.join("available/u1/b1/nginx-blog.alice.floy.id.conf"),
+            blog.available_path
+        );
+        assert!(blog.enabled_path.exists());
+        let server = NginxServer::load(&alice.available_path).unwrap();
+        assert_eq!(vec!["alice.floy.id".to_string()], server.server_names);
+        assert!(server.location("/about").is_some());
+
+        let nested = find_nested_sites("alice.floy.id", &cfg).unwrap();
+        assert_eq!(1, nested.len());
+        assert_eq!(blog.available_path, nested[0].available_path);
+
+        // An existing site is never replaced.
+        let err = create_domain("u1", "b1", "alice", true, &cfg).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        let err = create_domain("u1", "b1", "blog.alice", false, &cfg).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+
+        delete_domain("u1", "b1", "blog.alice", &cfg).unwrap();
+        assert!(find_site("blog.alice.floy.id", &cfg).unwrap().is_none());
+        assert!(find_site("alice.floy.id", &cfg).unwrap().is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-    Err(Error::new(ErrorKind::NotFound, "Slug page tidak ditemukan"))
 }
@@ -0,0 +1,619 @@
+use std::str::FromStr;
+
+use rocket::serde::json::{json, Value};
+
+use crate::common::errors::ErrorKind;
+use crate::common::utils::owns_subdomain;
+use crate::models::DnsRecord;
+
+/// What Cloudflare's automatic TTL amounts to, used as the file's `$TTL`.
+pub const AUTOMATIC_TTL: u32 = 300;
+
+/// Longest character-string a TXT record may hold (RFC 1035 3.3).
+const MAX_CHARACTER_STRING: usize = 255;
+
+/// Keeps the records named after a claimed subdomain or nested below one,
+/// sorted by name and type. Everything else in the zone belongs to whoever
+/// runs it, not to floy-dns.
+pub fn managed_records(records: Vec<DnsRecord>, suffix: &str, claims: &[String]) -> Vec<DnsRecord> {
+    let mut records: Vec<DnsRecord> = records
+        .into_iter()
+        .filter(|record| {
+            record
+                .name
+                .strip_suffix(suffix)
+                .and_then(|rest| rest.strip_suffix('.'))
+                .is_some_and(|subdomain| {
+                    claims.iter().any(|claim| owns_subdomain(claim, subdomain))
+                })
+        })
+        .collect();
+
+    records.sort_by(|a, b| {
+        (&a.name, &a.record_type, &a.content).cmp(&(&b.name, &b.record_type, &b.content))
+    });
+
+    records
+}
+
+/// Renders `records` as an RFC 1035 master file for the zone `origin`.
+///
+/// Owner names are written relative to `$ORIGIN` and records with automatic
+/// TTL inherit `$TTL`, which [`parse`] reads back as automatic. Proxied records keep their origin address and are
+/// marked with a comment.
+pub fn render(origin: &str, records: &[DnsRecord]) -> String {
+    let origin = origin.trim_end_matches('.');
+    let mut out = format!(
+        "; {} exported by floy-dns\n$ORIGIN {}.\n$TTL {}\n",
+        origin, origin, AUTOMATIC_TTL
+    );
+
+    for record in records {
+        let ttl = match record.ttl {
+            ttl if ttl == DnsRecord::automatic_ttl() => String::new(),
+            ttl => format!("{}\t", ttl),
+        };
+
+        out.push_str(&format!(
+            "{}\t{}IN\t{}\t{}",
+            owner(&record.name, origin),
+            ttl,
+            record.record_type,
+            rdata(record)
+        ));
+
+        if record.proxied {
+            out.push_str(" ; proxied");
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+fn owner(name: &str, origin: &str) -> String {
+    let name = name.trim_end_matches('.');
+
+    if name == origin {
+        "@".to_string()
+    } else if let Some(relative) = name.strip_suffix(&format!(".{}", origin)) {
+        relative.to_string()
+    } else {
+        absolute(name)
+    }
+}
+
+fn absolute(hostname: &str) -> String {
+    format!("{}.", hostname.trim_end_matches('.'))
+}
+
+/// Quotes `s` as one or more character-strings, splitting it every 255 bytes.
+fn quote(s: &str) -> String {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+
+    for c in s.chars() {
+        if chunk.len() + c.len_utf8() > MAX_CHARACTER_STRING {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.push(c);
+    }
+    chunks.push(chunk);
+
+    chunks
+        .iter()
+        .map(|chunk| format!("\"{}\"", chunk.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn data_field(data: Option<&Value>, key: &str) -> String {
+    match data.and_then(|data| data.get(key)) {
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+pub(crate) fn rdata(record: &DnsRecord) -> String {
+    let data = record.data.as_ref();
+
+    match record.record_type.as_str() {
+        "CNAME" | "NS" | "PTR" => absolute(&record.content),
+        "MX" => format!(
+            "{} {}",
+            record.priority.unwrap_or_default(),
+            absolute(&record.content)
+        ),
+        // Cloudflare may hand TXT content back already quoted.
+        "TXT" => match record
+            .content
+            .strip_prefix('"')
+            .and_then(|c| c.strip_suffix('"'))
+        {
+            Some(unquoted) if !unquoted.contains('"') => quote(unquoted),
+            _ => quote(&record.content),
+        },
+        "SRV" if data.is_some() => format!(
+            "{} {} {} {}",
+            data_field(data, "priority"),
+            data_field(data, "weight"),
+            data_field(data, "port"),
+            absolute(&data_field(data, "target"))
+        ),
+        "CAA" if data.is_some() => format!(
+            "{} {} {}",
+            data_field(data, "flags"),
+            data_field(data, "tag"),
+            quote(&data_field(data, "value"))
+        ),
+        _ => record.content.clone(),
+    }
+}
+
+/// A whitespace separated field of a master file line.
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One logical line, i.e. a physical line or several joined by parentheses.
+struct Entry {
+    line: usize,
+    blank_owner: bool,
+    tokens: Vec<Token>,
+    comments: Vec<String>,
+}
+
+fn syntax_error(line: usize, msg: &str) -> ErrorKind {
+    ErrorKind::ValidationError(format!("line {}: {}", line, msg))
+}
+
+fn flush(token: &mut String, tokens: &mut Vec<Token>) {
+    if !token.is_empty() {
+        tokens.push(Token {
+            text: std::mem::take(token),
+            quoted: false,
+        });
+    }
+}
+
+fn entries(text: &str) -> Result<Vec<Entry>, ErrorKind> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut depth = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        if depth == 0 {
+            entries.push(Entry {
+                line: i + 1,
+                blank_owner: line.starts_with([' ', '\t']),
+                tokens: vec![],
+                comments: vec![],
+            });
+        }
+        let entry = entries.last_mut().unwrap();
+        let mut token = String::new();
+        let mut chars = line.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    flush(&mut token, &mut entry.tokens);
+                    let mut s = String::new();
+                    let mut closed = false;
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => s.extend(chars.next()),
+                            '"' => {
+                                closed = true;
+                                break;
+                            }
+                            c => s.push(c),
+                        }
+                    }
+                    if !closed {
+                        return Err(syntax_error(i + 1, "unterminated string"));
+                    }
+                    entry.tokens.push(Token {
+                        text: s,
+                        quoted: true,
+                    });
+                }
+                ';' => {
+                    entry
+                        .comments
+                        .push(chars.by_ref().collect::<String>().trim().to_string());
+                }
+                '(' => {
+                    flush(&mut token, &mut entry.tokens);
+                    depth += 1;
+                }
+                ')' if depth == 0 => return Err(syntax_error(i + 1, "unbalanced ')'")),
+                ')' => {
+                    flush(&mut token, &mut entry.tokens);
+                    depth -= 1;
+                }
+                c if c.is_whitespace() => flush(&mut token, &mut entry.tokens),
+                c => token.push(c),
+            }
+        }
+        flush(&mut token, &mut entry.tokens);
+    }
+
+    if depth > 0 {
+        return Err(syntax_error(
+            entries.last().map_or(0, |e| e.line),
+            "unbalanced '('",
+        ));
+    }
+
+    entries.retain(|entry| !entry.tokens.is_empty());
+    Ok(entries)
+}
+
+/// Parses TTLs written in seconds (`3600`) or with BIND's units (`1h30m`).
+fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in s.to_ascii_lowercase().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        total = total.checked_add(
+            std::mem::take(&mut number)
+                .parse::<u32>()
+                .ok()?
+                .checked_mul(unit)?,
+        )?;
+    }
+
+    Some(total).filter(|_| number.is_empty())
+}
+
+fn is_class(s: &str) -> bool {
+    ["IN", "CH", "HS", "CS"].contains(&s.to_ascii_uppercase().as_str())
+}
+
+/// Makes `name` absolute against `origin`, without the trailing dot.
+fn qualify(name: &str, origin: &str) -> String {
+    let name = name.to_lowercase();
+
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(absolute) = name.strip_suffix('.') {
+        absolute.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// Parses a numeric field straight into its wire size, so out of range
+/// values are rejected rather than truncated.
+fn number<T: FromStr>(token: Option<&Token>, line: usize, field: &str) -> Result<T, ErrorKind> {
+    token
+        .and_then(|t| t.text.parse().ok())
+        .ok_or(syntax_error(line, &format!("invalid {}", field)))
+}
+
+fn field<'a>(token: Option<&'a Token>, line: usize, field: &str) -> Result<&'a str, ErrorKind> {
+    token
+        .map(|t| t.text.as_str())
+        .ok_or(syntax_error(line, &format!("missing {}", field)))
+}
+
+fn record_from(
+    record_type: String,
+    name: String,
+    ttl: u32,
+    rdata: &[&Token],
+    origin: &str,
+    line: usize,
+) -> Result<DnsRecord, ErrorKind> {
+    let mut record = DnsRecord::new(record_type, name, ttl, String::new(), false);
+
+    match record.record_type.as_str() {
+        "CNAME" | "NS" | "PTR" => {
+            record.content = qualify(field(rdata.first().copied(), line, "target")?, origin);
+        }
+        "MX" => {
+            record.priority = Some(number(rdata.first().copied(), line, "preference")?);
+            record.content = qualify(field(rdata.get(1).copied(), line, "exchange")?, origin);
+        }
+        "TXT" => {
+            record.content = rdata.iter().map(|t| t.text.as_str()).collect();
+        }
+        "SRV" => {
+            record.data = Some(json!({
+                "priority": number::<u16>(rdata.first().copied(), line, "priority")?,
+                "weight": number::<u16>(rdata.get(1).copied(), line, "weight")?,
+                "port": number::<u16>(rdata.get(2).copied(), line, "port")?,
+                "target": qualify(field(rdata.get(3).copied(), line, "target")?, origin),
+            }));
+        }
+        "CAA" => {
+            record.data = Some(json!({
+                "flags": number::<u8>(rdata.first().copied(), line, "flags")?,
+                "tag": field(rdata.get(1).copied(), line, "tag")?,
+                "value": field(rdata.get(2).copied(), line, "value")?,
+            }));
+        }
+        _ => {
+            record.content = rdata
+                .iter()
+                .map(|t| t.text.as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+    }
+
+    if record.content.is_empty() && record.data.is_none() {
+        return Err(syntax_error(line, "missing record data"));
+    }
+
+    Ok(record)
+}
+
+/// Parses an RFC 1035 master file into records with absolute names.
+///
+/// `$ORIGIN` and `$TTL` are honoured, `origin` being the starting origin.
+/// Records without a TTL take `$TTL`, or automatic TTL when there is none
+/// or it is [`AUTOMATIC_TTL`], the way [`render`] writes automatic TTL.
+/// A `; proxied` comment, as written by [`render`], turns proxying on.
+/// `$INCLUDE` is not supported.
+pub fn parse(text: &str, origin: &str) -> Result<Vec<DnsRecord>, ErrorKind> {
+    let mut origin = origin.trim_end_matches('.').to_lowercase();
+    let mut default_ttl = DnsRecord::automatic_ttl();
+    let mut owner: Option<String> = None;
+    let mut records = vec![];
+
+    for entry in entries(text)? {
+        let line = entry.line;
+        let tokens = &entry.tokens;
+
+        match tokens[0].text.to_ascii_uppercase().as_str() {
+            "$ORIGIN" if !tokens[0].quoted => {
+                origin = qualify(field(tokens.get(1), line, "origin")?, &origin);
+                continue;
+            }
+            "$TTL" if !tokens[0].quoted => {
+                default_ttl = field(tokens.get(1), line, "TTL")
+                    .ok()
+                    .and_then(parse_ttl)
+                    .ok_or(syntax_error(line, "invalid $TTL"))?;
+                if default_ttl == AUTOMATIC_TTL {
+                    default_ttl = DnsRecord::automatic_ttl();
+                }
+                continue;
+            }
+            directive if directive.starts_with('$') => {
+                return Err(syntax_error(
+                    line,
+                    &format!("{} is not supported", directive),
+                ));
+            }
+            _ => {}
+        }
+
+        let mut rest = tokens.iter();
+        if !entry.blank_owner {
+            owner = rest.next().map(|t| qualify(&t.text, &origin));
+        }
+        let name = owner
+            .clone()
+            .ok_or(syntax_error(line, "record without owner name"))?;
+
+        let mut ttl = None;
+        let mut rest = rest.peekable();
+        for _ in 0..2 {
+            match rest.peek() {
+                Some(t) if ttl.is_none() && parse_ttl(&t.text).is_some() => {
+                    ttl = parse_ttl(&t.text);
+                }
+                Some(t) if is_class(&t.text) => {}
+                _ => break,
+            }
+            rest.next();
+        }
+
+        let record_type = field(rest.next(), line, "record type")?.to_ascii_uppercase();
+        let rdata: Vec<&Token> = rest.collect();
+
+        let mut record = record_from(
+            record_type,
+            name,
+            ttl.unwrap_or(default_ttl),
+            &rdata,
+            &origin,
+            line,
+        )?;
+
+        if entry.comments.iter().any(|c| c == "proxied") {
+            record.proxied = true;
+            record.ttl = DnsRecord::automatic_ttl();
+        }
+
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(record_type: &str, name: &str, ttl: u32, content: &str) -> DnsRecord {
+        DnsRecord::new(
+            record_type.to_string(),
+            name.to_string(),
+            ttl,
+            content.to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_managed_records() {
+        let records = vec![
+            record("A", "www.alice.floy.id", 1, "10.0.0.1"),
+            record("A", "alice.floy.id", 1, "10.0.0.1"),
+            record("A", "malice.floy.id", 1, "10.0.0.1"),
+            record("MX", "floy.id", 1, "mail.floy.id"),
+            record("A", "bob.floy.id", 1, "10.0.0.1"),
+        ];
+
+        let names: Vec<String> = managed_records(records, "floy.id", &["alice".to_string()])
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+
+        assert_eq!(vec!["alice.floy.id", "www.alice.floy.id"], names);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut proxied = record("A", "alice.floy.id", 1, "10.0.0.1");
+        proxied.proxied = true;
+        let mut mx = record("MX", "alice.floy.id", 3600, "mail.example.com");
+        mx.priority = Some(10);
+        let mut srv = record("SRV", "_sip._tcp.alice.floy.id", 1, "");
+        srv.data =
+            Some(json!({"priority": 1, "weight": 5, "port": 5060, "target": "sip.alice.floy.id"}));
+        let mut caa = record("CAA", "alice.floy.id", 1, "");
+        caa.data = Some(json!({"flags": 0, "tag": "issue", "value": "letsencrypt.org"}));
+
+        let records = vec![
+            proxied,
+            mx,
+            record("CNAME", "www.alice.floy.id", 1, "alice.floy.id"),
+            record("TXT", "alice.floy.id", 1, "say \"hi\""),
+            srv,
+            caa,
+        ];
+
+        assert_eq!(
+            "; floy.id exported by floy-dns\n\
+             $ORIGIN floy.id.\n\
+             $TTL 300\n\
+             alice\tIN\tA\t10.0.0.1 ; proxied\n\
+             alice\t3600\tIN\tMX\t10 mail.example.com.\n\
+             www.alice\tIN\tCNAME\talice.floy.id.\n\
+             alice\tIN\tTXT\t\"say \\\"hi\\\"\"\n\
+             _sip._tcp.alice\tIN\tSRV\t1 5 5060 sip.alice.floy.id.\n\
+             alice\tIN\tCAA\t0 issue \"letsencrypt.org\"\n",
+            render("floy.id", &records)
+        );
+    }
+
+    #[test]
+    fn test_quote_splits_long_strings() {
+        let long = "a".repeat(300);
+
+        assert_eq!(
+            format!("\"{}\" \"{}\"", "a".repeat(255), "a".repeat(45)),
+            quote(&long)
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let zone = "$ORIGIN floy.id.\n\
+                    $TTL 1h\n\
+                    @ IN SOA ns1.floy.id. admin.floy.id. (\n\
+                    \t2024010101 ; serial\n\
+                    \t7200 3600 1209600 300 )\n\
+                    alice 300 IN A 10.0.0.1 ; proxied\n\
+                    \tIN AAAA 2001:db8::1\n\
+                    www.alice CNAME alice\n\
+                    alice IN 600 MX 10 mail.example.com.\n\
+                    alice TXT \"v=spf1 \" \"-all\"\n\
+                    _sip._tcp.alice SRV 1 5 5060 sip.alice\n\
+                    alice CAA 0 issue \"letsencrypt.org\"\n";
+
+        let records = parse(zone, "example.com").unwrap();
+        let summary: Vec<(String, String, u32, String)> = records
+            .iter()
+            .map(|r| (r.name.clone(), r.record_type.clone(), r.ttl, rdata(r)))
+            .collect();
+
+        let expected = [
+            (
+                "floy.id",
+                "SOA",
+                3600,
+                "ns1.floy.id. admin.floy.id. 2024010101 7200 3600 1209600 300",
+            ),
+            ("alice.floy.id", "A", 1, "10.0.0.1"),
+            ("alice.floy.id", "AAAA", 3600, "2001:db8::1"),
+            ("www.alice.floy.id", "CNAME", 3600, "alice.floy.id."),
+            ("alice.floy.id", "MX", 600, "10 mail.example.com."),
+            ("alice.floy.id", "TXT", 3600, "\"v=spf1 -all\""),
+            (
+                "_sip._tcp.alice.floy.id",
+                "SRV",
+                3600,
+                "1 5 5060 sip.alice.floy.id.",
+            ),
+            ("alice.floy.id", "CAA", 3600, "0 issue \"letsencrypt.org\""),
+        ];
+        assert_eq!(
+            expected
+                .iter()
+                .map(|(n, t, ttl, d)| (n.to_string(), t.to_string(), *ttl, d.to_string()))
+                .collect::<Vec<_>>(),
+            summary
+        );
+        assert!(records[1].proxied);
+        assert!(!records[2].proxied);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("alice A (10.0.0.1\n", "floy.id").is_err());
+        assert!(parse("alice TXT \"open\n", "floy.id").is_err());
+        assert!(parse("\tA 10.0.0.1\n", "floy.id").is_err());
+        assert!(parse("$INCLUDE other.zone\n", "floy.id").is_err());
+        assert!(parse("alice MX 10\n", "floy.id").is_err());
+
+        let err = parse("\nalice MX 70000 mail.example.com.\n", "floy.id").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        assert!(parse("_sip._tcp.alice SRV 1 5 65536 sip.alice\n", "floy.id").is_err());
+        assert!(parse("alice CAA 256 issue \"letsencrypt.org\"\n", "floy.id").is_err());
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        let mut proxied = record("A", "alice.floy.id", 1, "10.0.0.1");
+        proxied.proxied = true;
+        let records = vec![
+            proxied,
+            record("TXT", "alice.floy.id", 3600, "say \"hi\""),
+            record("CNAME", "www.alice.floy.id", 600, "alice.floy.id"),
+            record("A", "www.alice.floy.id", 1, "10.0.0.2"),
+            record("A", "api.alice.floy.id", AUTOMATIC_TTL, "10.0.0.3"),
+        ];
+
+        let parsed = parse(&render("floy.id", &records), "floy.id").unwrap();
+
+        assert_eq!(records.len(), parsed.len());
+        for (a, b) in records.iter().zip(&parsed) {
+            assert_eq!(
+                (&a.name, &a.record_type, a.ttl, &a.content, a.proxied),
+                (&b.name, &b.record_type, b.ttl, &b.content, b.proxied)
+            );
+        }
+    }
+}
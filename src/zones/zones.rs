@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use crate::common::errors::ErrorKind;
+use crate::config::Config;
+use crate::provider::provider::{build_provider, DnsProvider};
+
+/// A configured zone together with the provider serving it.
+#[derive(Clone)]
+pub struct Zone {
+    /// Configuration scoped to this zone with [`Config::for_zone`].
+    pub config: Config,
+    pub provider: Arc<dyn DnsProvider>,
+}
+
+/// Every zone of the deployment, the first one being the default.
+#[derive(Clone)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub async fn new(config: &Config) -> Self {
+        let mut zones = vec![];
+        for zone in &config.zones {
+            let config = config.for_zone(zone);
+            let provider = build_provider(&config).await;
+            zones.push(Zone { config, provider });
+        }
+
+        Zones { zones }
+    }
+
+    pub fn from_zones(zones: Vec<Zone>) -> Self {
+        Zones { zones }
+    }
+
+    pub fn default_zone(&self) -> &Zone {
+        &self.zones[0]
+    }
+
+    /// Picks the zone whose suffix is `name`, or the default zone when the
+    /// request did not name one.
+    pub fn select(&self, name: Option<&str>) -> Result<&Zone, ErrorKind> {
+        let name = match name.map(|n| n.trim().trim_end_matches('.')) {
+            Some(name) if !name.is_empty() => name,
+            _ => return Ok(self.default_zone()),
+        };
+
+        self.zones
+            .iter()
+            .find(|zone| zone.config.dns_suffix.eq_ignore_ascii_case(name))
+            .ok_or(ErrorKind::ZoneNotFound(name.to_string()))
+    }
+
+    /// Finds the zone a fully qualified hostname belongs to, preferring the
+    /// longest matching suffix.
+    pub fn for_hostname(&self, hostname: &str) -> Option<&Zone> {
+        let hostname = hostname.trim_end_matches('.').to_lowercase();
+
+        self.zones
+            .iter()
+            .filter(|zone| hostname.ends_with(&format!(".{}", zone.config.dns_suffix)))
+            .max_by_key(|zone| zone.config.dns_suffix.len())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Zone> {
+        self.zones.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::config::{SiteLayout, ZoneConfig};
+
+    async fn zones() -> Zones {
+        let config = Config {
+            zones: ["floy.id", "floy.site", "eu.floy.site"]
+                .iter()
+                .map(|suffix| ZoneConfig {
+                    dns_suffix: suffix.to_string(),
+                    ip: "10.0.0.1".to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        Zones::new(&config).await
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let zones = zones().await;
+
+        assert_eq!("floy.id", zones.select(None).unwrap().config.dns_suffix);
+        assert_eq!("floy.id", zones.select(Some("")).unwrap().config.dns_suffix);
+        assert_eq!(
+            "floy.site",
+            zones.select(Some("Floy.Site.")).unwrap().config.dns_suffix
+        );
+        assert!(matches!(
+            zones.select(Some("example.com")),
+            Err(ErrorKind::ZoneNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_for_hostname() {
+        let zones = zones().await;
+
+        let suffix = |h: &str| zones.for_hostname(h).map(|z| z.config.dns_suffix.clone());
+
+        assert_eq!(Some("floy.id".to_string()), suffix("alice.floy.id"));
+        assert_eq!(
+            Some("floy.site".to_string()),
+            suffix("blog.alice.floy.site")
+        );
+        assert_eq!(
+            Some("eu.floy.site".to_string()),
+            suffix("alice.eu.floy.site")
+        );
+        assert_eq!(None, suffix("floy.id"));
+        assert_eq!(None, suffix("alice.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_zone_scoped_config() {
+        let zones = zones().await;
+
+        let default = &zones.default_zone().config;
+        assert!(default.is_default_zone());
+        assert_eq!(
+            Path::new("u1/b1/nginx.conf"),
+            default.site_file_name("u1", "b1")
+        );
+        assert_eq!("floy.id.", default.pdns_zone);
+
+        let site = &zones.select(Some("floy.site")).unwrap().config;
+        assert!(!site.is_default_zone());
+        assert_eq!(
+            Path::new("u1/b1/nginx.floy.site.conf"),
+            site.site_file_name("u1", "b1")
+        );
+
+        let flat = Config {
+            site_layout: SiteLayout::Flat,
+            ..site.clone()
+        };
+        assert_eq!(
+            Path::new("u1-b1.floy.site.conf"),
+            flat.site_file_name("u1", "b1")
+        );
+
+        assert_eq!(
+            Path::new("u1/b1/nginx-blog.alice.floy.site.conf"),
+            site.host_site_file_name("u1", "b1", "blog.alice.floy.site")
+        );
+        assert_eq!(
+            Path::new("u1-b1-blog.alice.floy.site.conf"),
+            flat.host_site_file_name("u1", "b1", "blog.alice.floy.site")
+        );
+    }
+}